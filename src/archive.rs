@@ -0,0 +1,306 @@
+use chrono::{DateTime, Days, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use serde::Serialize;
+
+use crate::catalog::CatalogEntry;
+use crate::segment_meta;
+
+/// Segments are opened by wall clock, so consecutive ones rarely line up to the sample.
+/// Anything shorter than this between them isn't worth calling a gap.
+pub const GAP_TOLERANCE_SECS: f64 = 1.;
+
+const DATE_TIME_FORMATS: [&str; 4] = [
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M",
+    "%Y-%m-%dT%H:%M",
+];
+const TIME_FORMATS: [&str; 2] = ["%H:%M:%S%.f", "%H:%M"];
+
+fn local(naive: NaiveDateTime) -> Result<DateTime<FixedOffset>, String> {
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.fixed_offset())
+        .ok_or_else(|| format!("{} doesn't exist in the local time zone", naive))
+}
+
+/// Parses a wall-clock time given on the command line. Accepts RFC 3339, `YYYY-MM-DD[ HH:MM[:SS]]`,
+/// or a bare `HH:MM[:SS]`, optionally preceded by `today` or `yesterday`. Times without an offset are local.
+pub fn parse_timestamp(s: &str) -> Result<DateTime<FixedOffset>, String> {
+    let s = s.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time);
+    }
+    for format in DATE_TIME_FORMATS {
+        if let Ok(naive) = NaiveDateTime::parse_from_str(s, format) {
+            return local(naive);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return local(date.and_time(NaiveTime::MIN));
+    }
+    let today = Local::now().date_naive();
+    let (date, time) = match s.split_once(' ') {
+        Some(("today", time)) => (today, time),
+        Some(("yesterday", time)) => (today - Days::new(1), time),
+        _ => (today, s),
+    };
+    for format in TIME_FORMATS {
+        if let Ok(time) = NaiveTime::parse_from_str(time.trim(), format) {
+            return local(date.and_time(time));
+        }
+    }
+    Err(format!(
+        "Could not make sense of `{}` as a time; try e.g. `2024-05-01 14:37`, `yesterday 14:37` or RFC 3339",
+        s
+    ))
+}
+
+/// A stretch of time between two segments where nothing was recorded. Times are UTC, as in the catalog.
+#[derive(Debug, Clone, Serialize)]
+pub struct Gap {
+    pub start: String,
+    pub end: String,
+    pub duration_secs: f64,
+    /// Index of the entry the gap ends at
+    #[serde(skip)]
+    pub before: usize,
+}
+
+/// Gaps between `entries`, which must be sorted by start time. Segments written in several formats
+/// at once overlap each other, so a gap only starts once every segment before it has ended.
+pub fn find_gaps(entries: &[CatalogEntry]) -> Vec<Gap> {
+    let mut gaps = Vec::new();
+    let mut covered_until: Option<DateTime<FixedOffset>> = None;
+    for (i, entry) in entries.iter().enumerate() {
+        let (Some(start), Some(end)) = (entry.start_time(), entry.end_time()) else {
+            continue;
+        };
+        if let Some(covered_until) = covered_until {
+            let duration_secs = (start - covered_until).num_milliseconds() as f64 / 1000.;
+            if duration_secs > GAP_TOLERANCE_SECS {
+                gaps.push(Gap {
+                    start: segment_meta::format_time(&covered_until.with_timezone(&Utc)),
+                    end: segment_meta::format_time(&start.with_timezone(&Utc)),
+                    duration_secs,
+                    before: i,
+                });
+            }
+        }
+        covered_until = Some(covered_until.map_or(end, |until| until.max(end)));
+    }
+    gaps
+}
+
+/// How much time `entries` cover between them, counting overlapping segments once.
+pub fn recorded_secs(entries: &[CatalogEntry]) -> f64 {
+    let mut total = 0.;
+    let mut covered_until: Option<DateTime<FixedOffset>> = None;
+    for entry in entries {
+        let (Some(start), Some(end)) = (entry.start_time(), entry.end_time()) else {
+            continue;
+        };
+        let from = covered_until.map_or(start, |until| until.max(start));
+        if end > from {
+            total += (end - from).num_microseconds().unwrap_or(0) as f64 / 1e6;
+            covered_until = Some(end);
+        }
+    }
+    total
+}
+
+/// Every segment covering `at`, with how many seconds into it `at` is. Segments run from their start up to,
+/// but not including, their end.
+pub fn segments_at(entries: &[CatalogEntry], at: DateTime<FixedOffset>) -> Vec<(&CatalogEntry, f64)> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let (start, end) = (entry.start_time()?, entry.end_time()?);
+            let offset = (at - start).num_microseconds().unwrap_or(0) as f64 / 1e6;
+            (start <= at && at < end).then_some((entry, offset))
+        })
+        .collect()
+}
+
+/// For a moment nothing covers: when the last segment before it ended, and when the next one after it starts.
+pub fn gap_around(
+    entries: &[CatalogEntry],
+    at: DateTime<FixedOffset>,
+) -> (Option<DateTime<FixedOffset>>, Option<DateTime<FixedOffset>>) {
+    let before = entries
+        .iter()
+        .filter_map(|entry| entry.end_time())
+        .filter(|end| *end <= at)
+        .max();
+    let after = entries
+        .iter()
+        .filter_map(|entry| entry.start_time())
+        .filter(|start| *start > at)
+        .min();
+    (before, after)
+}
+
+/// For showing a time to the user, in their own time zone. What's stored is UTC.
+pub fn format_time(time: &DateTime<FixedOffset>) -> String {
+    time.with_timezone(&Local).to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+}
+
+/// `format_time` for a time as stored, e.g. a catalog entry's start; left alone if it doesn't parse.
+pub fn format_stored_time(time: &str) -> String {
+    DateTime::parse_from_rfc3339(time).map_or_else(|_| time.to_owned(), |time| format_time(&time))
+}
+
+/// `1h 02m 03s`, leaving off leading zero units.
+pub fn format_duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h {:02}m {:02}s", hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}m {:02}s", mins, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// `12.3 MiB` and the like.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naive(s: &str) -> NaiveDateTime {
+        parse_timestamp(s).unwrap().naive_local()
+    }
+
+    fn entry(start: &str, duration_secs: f64) -> CatalogEntry {
+        CatalogEntry {
+            path: format!("{}.ogg", start),
+            start: start.to_owned(),
+            duration_secs,
+            device: None,
+            format: "ogg".to_owned(),
+            size: 0,
+            sha256: None,
+            sample_rate: 44_100,
+            channels: 1,
+        }
+    }
+
+    fn at(s: &str) -> DateTime<FixedOffset> {
+        parse_timestamp(s).unwrap()
+    }
+
+    #[test]
+    fn finds_the_offset_into_every_segment_covering_a_moment() {
+        // Two formats from the same capture, then the next segment
+        let entries = [
+            entry("2024-05-01T12:00:00Z", 60.),
+            entry("2024-05-01T12:00:00.500Z", 60.),
+            entry("2024-05-01T12:01:00Z", 60.),
+        ];
+        let offsets = |s| -> Vec<(&str, f64)> {
+            segments_at(&entries, at(s)).into_iter().map(|(entry, offset)| (entry.path.as_str(), offset)).collect()
+        };
+        assert_eq!(
+            offsets("2024-05-01T12:00:30.250Z"),
+            [("2024-05-01T12:00:00Z.ogg", 30.25), ("2024-05-01T12:00:00.500Z.ogg", 29.75)]
+        );
+        assert_eq!(offsets("2024-05-01T12:00:00Z"), [("2024-05-01T12:00:00Z.ogg", 0.)]);
+        // A segment's end belongs to whatever comes next
+        assert_eq!(
+            offsets("2024-05-01T12:01:00Z"),
+            [("2024-05-01T12:00:00.500Z.ogg", 59.5), ("2024-05-01T12:01:00Z.ogg", 0.)]
+        );
+        assert!(offsets("2024-05-01T11:59:59Z").is_empty());
+        assert!(offsets("2024-05-01T12:02:00Z").is_empty());
+    }
+
+    #[test]
+    fn finds_what_surrounds_a_moment_nothing_covers() {
+        let entries = [entry("2024-05-01T12:00:00Z", 60.), entry("2024-05-01T12:05:00Z", 60.)];
+        assert_eq!(
+            gap_around(&entries, at("2024-05-01T12:03:00Z")),
+            (Some(at("2024-05-01T12:01:00Z")), Some(at("2024-05-01T12:05:00Z")))
+        );
+        assert_eq!(gap_around(&entries, at("2024-05-01T11:00:00Z")), (None, Some(at("2024-05-01T12:00:00Z"))));
+        assert_eq!(gap_around(&entries, at("2024-05-01T13:00:00Z")), (Some(at("2024-05-01T12:06:00Z")), None));
+        assert_eq!(gap_around(&[], at("2024-05-01T13:00:00Z")), (None, None));
+    }
+
+    #[test]
+    fn parses_rfc3339_with_its_offset() {
+        assert_eq!(
+            parse_timestamp("2024-05-01T14:37:05+02:00").unwrap().to_rfc3339(),
+            "2024-05-01T14:37:05+02:00"
+        );
+    }
+
+    #[test]
+    fn parses_local_dates_and_times() {
+        let at = |s| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S%.f").unwrap();
+        assert_eq!(naive("2024-05-01 14:37:05.250"), at("2024-05-01 14:37:05.250"));
+        assert_eq!(naive("2024-05-01T14:37"), at("2024-05-01 14:37:00"));
+        assert_eq!(naive("2024-05-01"), at("2024-05-01 00:00:00"));
+    }
+
+    #[test]
+    fn parses_times_of_today_and_yesterday() {
+        let today = Local::now().date_naive();
+        let time = NaiveTime::from_hms_opt(14, 37, 0).unwrap();
+        assert_eq!(naive("14:37"), today.and_time(time));
+        assert_eq!(naive("today 14:37"), today.and_time(time));
+        assert_eq!(naive("yesterday 14:37"), (today - Days::new(1)).and_time(time));
+    }
+
+    #[test]
+    fn rejects_nonsense() {
+        assert!(parse_timestamp("last tuesday").is_err());
+        assert!(parse_timestamp("25:00").is_err());
+    }
+
+    #[test]
+    fn finds_gaps_between_segments() {
+        let entries = [
+            entry("2024-05-01T12:00:00.000Z", 60.),
+            // Within the tolerance
+            entry("2024-05-01T12:01:00.500Z", 60.),
+            entry("2024-05-01T12:05:00.000Z", 60.),
+        ];
+        let gaps = find_gaps(&entries);
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].start, "2024-05-01T12:02:00.500Z");
+        assert_eq!(gaps[0].end, "2024-05-01T12:05:00.000Z");
+        assert_eq!(gaps[0].duration_secs, 179.5);
+        assert_eq!(gaps[0].before, 2);
+    }
+
+    #[test]
+    fn overlapping_formats_cover_each_other() {
+        // A long FLAC segment alongside shorter Opus ones: no gap while the FLAC is still going
+        let entries = [
+            entry("2024-05-01T12:00:00.000Z", 600.),
+            entry("2024-05-01T12:00:00.000Z", 60.),
+            entry("2024-05-01T12:05:00.000Z", 60.),
+            entry("2024-05-01T12:20:00.000Z", 60.),
+        ];
+        let gaps = find_gaps(&entries);
+        assert_eq!(gaps.len(), 1);
+        assert_eq!(gaps[0].start, "2024-05-01T12:10:00.000Z");
+        assert_eq!(recorded_secs(&entries), 660.);
+    }
+}
@@ -0,0 +1,435 @@
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::catalog::{self, CatalogEntry};
+
+/// Lives next to the catalog, one signed link per finished segment file.
+pub const CHAIN_FILE_NAME: &str = "akasha-chain.jsonl";
+// Bumped if what goes into a link's hash ever changes
+const CHAIN_DOMAIN: &str = "akasha-chain-v1";
+/// What the first link's `prev` points at
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One segment's place in the chain. `hash` covers every field above it, including the previous link's
+/// hash, and `signature` is over `hash`; so no link can be edited, dropped or moved without it showing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChainLink {
+    pub seq: u64,
+    /// Relative to the chain's directory
+    pub path: String,
+    pub sha256: String,
+    pub start: String,
+    pub prev: String,
+    pub hash: String,
+    pub public_key: String,
+    pub signature: String,
+}
+
+impl ChainLink {
+    fn compute_hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for field in [
+            CHAIN_DOMAIN,
+            &self.seq.to_string(),
+            &self.path,
+            &self.sha256,
+            &self.start,
+            &self.prev,
+        ] {
+            // Length-prefixed, so no two different links hash the same input
+            hasher.update((field.len() as u64).to_le_bytes());
+            hasher.update(field.as_bytes());
+        }
+        hasher.finalize().into()
+    }
+}
+
+pub fn chain_path(dir: &Path) -> PathBuf {
+    dir.join(CHAIN_FILE_NAME)
+}
+
+/// `~/.config/akasha/attest.key` or the platform's equivalent.
+pub fn default_key_path() -> PathBuf {
+    let mut path = dirs::config_dir().expect("Failed to determine config directory D:");
+    path.push("akasha");
+    path.push("attest.key");
+    path
+}
+
+fn public_key_path(key_path: &Path) -> PathBuf {
+    key_path.with_extension("pub")
+}
+
+/// Loads the signing key at `path`, generating one (and its `.pub` alongside) if there's none yet.
+pub fn load_or_create_key(path: &Path) -> Result<SigningKey, Box<dyn Error>> {
+    match fs::read_to_string(path) {
+        Ok(hex_key) => {
+            let bytes: [u8; 32] = hex::decode(hex_key.trim())?
+                .try_into()
+                .map_err(|_| format!("{} is not an Ed25519 key", path.display()))?;
+            return Ok(SigningKey::from_bytes(&bytes));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let mut secret = [0; 32];
+    getrandom::getrandom(&mut secret).map_err(|e| format!("Could not generate a signing key: {}", e))?;
+    let key = SigningKey::from_bytes(&secret);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(target_family = "unix")]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut f = options.open(path)?;
+    writeln!(f, "{}", hex::encode(key.to_bytes()))?;
+    f.sync_all()?;
+    fs::write(public_key_path(path), format!("{}\n", public_key_hex(&key.verifying_key())))?;
+    Ok(key)
+}
+
+pub fn public_key_hex(key: &VerifyingKey) -> String {
+    hex::encode(key.to_bytes())
+}
+
+pub fn parse_public_key(s: &str) -> Result<VerifyingKey, Box<dyn Error>> {
+    let bytes: [u8; 32] = hex::decode(s.trim())?
+        .try_into()
+        .map_err(|_| "An Ed25519 public key is 32 bytes")?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+/// A line of the chain file: the link, or why it couldn't be parsed.
+pub type ChainLine = Result<ChainLink, String>;
+
+/// Every line of the chain in `dir`. `None` if there's no chain.
+pub fn read(dir: &Path) -> Result<Option<Vec<ChainLine>>, Box<dyn Error>> {
+    let f = match File::open(chain_path(dir)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut links = Vec::new();
+    for line in BufReader::new(f).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            links.push(serde_json::from_str(&line).map_err(|e| e.to_string()));
+        }
+    }
+    Ok(Some(links))
+}
+
+/// Adds a freshly finalized segment to the end of the chain in `dir`, signed with `key`.
+pub fn append(dir: &Path, key: &SigningKey, entry: &CatalogEntry) -> Result<ChainLink, Box<dyn Error>> {
+    let last = match read(dir)? {
+        Some(links) => match links.into_iter().last() {
+            Some(Ok(link)) => Some(link),
+            // Carrying on from a link we can't read would only bury the damage; make someone look at it
+            Some(Err(e)) => return Err(format!("The last link of the chain is unreadable: {}", e).into()),
+            None => None,
+        },
+        None => None,
+    };
+    let mut link = ChainLink {
+        seq: last.as_ref().map_or(0, |last| last.seq + 1),
+        path: entry.path.clone(),
+        sha256: entry.sha256.clone().ok_or("Segment has not been hashed")?,
+        start: entry.start.clone(),
+        prev: last.map_or(GENESIS.to_owned(), |last| last.hash),
+        hash: String::new(),
+        public_key: public_key_hex(&key.verifying_key()),
+        signature: String::new(),
+    };
+    let hash = link.compute_hash();
+    link.hash = hex::encode(hash);
+    link.signature = hex::encode(key.sign(&hash).to_bytes());
+
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(chain_path(dir))?;
+    let mut line = serde_json::to_string(&link)?;
+    line.push('\n');
+    f.write_all(line.as_bytes())?;
+    f.sync_data()?;
+    Ok(link)
+}
+
+/// Where and how the chain stops adding up. `link` counts from 1, in file order.
+#[derive(Debug, Clone)]
+pub struct Break {
+    pub link: usize,
+    pub path: Option<String>,
+    pub reason: String,
+}
+
+pub struct ChainReport {
+    pub links: usize,
+    /// The key the chain is signed with, as found in its first link
+    pub public_key: Option<String>,
+    pub breaks: Vec<Break>,
+    /// Segment files on disk the chain doesn't cover
+    pub unlisted: Vec<String>,
+    /// Hash of the last link, to keep somewhere safe: nothing inside the archive can show links cut off the end
+    pub head: Option<String>,
+}
+
+/// Walks the chain in `dir`, checking each link follows on from the last, is signed by the same key
+/// (`trusted_key` if given), and still matches its segment file.
+pub fn verify(dir: &Path, trusted_key: Option<&VerifyingKey>) -> Result<ChainReport, Box<dyn Error>> {
+    let links = read(dir)?.ok_or_else(|| format!("No {} in {}", CHAIN_FILE_NAME, dir.display()))?;
+    let mut breaks = Vec::new();
+    let mut signer: Option<VerifyingKey> = trusted_key.copied();
+    let mut public_key = None;
+    let mut prev = GENESIS.to_owned();
+    let mut expected_seq = 0;
+    let mut head = None;
+    for (i, link) in links.iter().enumerate() {
+        let number = i + 1;
+        let mut report = |path: Option<&str>, reason: String| {
+            breaks.push(Break {
+                link: number,
+                path: path.map(str::to_owned),
+                reason,
+            })
+        };
+        let link = match link {
+            Ok(link) => link,
+            Err(e) => {
+                report(None, format!("unreadable: {}", e));
+                continue;
+            }
+        };
+        let path = Some(link.path.as_str());
+        if link.seq != expected_seq {
+            report(
+                path,
+                format!(
+                    "numbered {}, expected {}: links were removed, inserted or reordered",
+                    link.seq, expected_seq
+                ),
+            );
+        }
+        if link.prev != prev {
+            let reason = if i == 0 {
+                "doesn't start the chain: earlier links were removed".to_owned()
+            } else {
+                format!("doesn't follow on from link {}: links were removed, inserted or reordered", i)
+            };
+            report(path, reason);
+        }
+        let hash = link.compute_hash();
+        if hex::encode(hash) != link.hash {
+            report(path, "has been edited: its hash doesn't match its contents".to_owned());
+        }
+        match parse_public_key(&link.public_key) {
+            Ok(key) => {
+                public_key.get_or_insert_with(|| link.public_key.clone());
+                let signer = *signer.get_or_insert(key);
+                if key != signer {
+                    report(path, format!("is signed by a different key, {}", link.public_key));
+                } else {
+                    let signature = hex::decode(&link.signature)
+                        .ok()
+                        .and_then(|bytes| Signature::from_slice(&bytes).ok());
+                    if signature.is_none_or(|signature| key.verify(&hash, &signature).is_err()) {
+                        report(path, "has a bad signature".to_owned());
+                    }
+                }
+            }
+            Err(e) => report(path, format!("has an unusable public key: {}", e)),
+        }
+        let file = dir.join(&link.path);
+        if !file.exists() {
+            report(path, "segment file has been deleted".to_owned());
+        } else if catalog::sha256_file(&file)? != link.sha256 {
+            report(path, "segment file has been altered".to_owned());
+        }
+        // Carry on from this link as it stands, so one break isn't reported again for every link after it
+        prev = link.hash.clone();
+        expected_seq = link.seq + 1;
+        head = Some(link.hash.clone());
+    }
+
+    let chained: Vec<&str> = links
+        .iter()
+        .filter_map(|link| link.as_ref().ok())
+        .map(|link| link.path.as_str())
+        .collect();
+    let unlisted = catalog::find_segment_files(dir)?
+        .iter()
+        .map(|path| path.strip_prefix(dir).unwrap_or(path).to_string_lossy().into_owned())
+        .filter(|path| !chained.contains(&path.as_str()))
+        .collect();
+    Ok(ChainReport {
+        links: links.len(),
+        public_key,
+        breaks,
+        unlisted,
+        head,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    /// Writes the `i`th segment file, and catalogs it.
+    fn segment(dir: &TestDir, name: &str, i: usize) -> CatalogEntry {
+        let path = dir.join(name);
+        fs::write(&path, format!("segment {}", i)).unwrap();
+        CatalogEntry {
+            path: name.to_owned(),
+            start: format!("2024-05-01T12:0{}:00.000Z", i),
+            duration_secs: 60.,
+            device: None,
+            format: "ogg".to_owned(),
+            size: 9,
+            sha256: Some(catalog::sha256_file(&path).unwrap()),
+            sample_rate: 44_100,
+            channels: 1,
+        }
+    }
+
+    /// Three segments, chained in order.
+    fn chained_dir() -> TestDir {
+        let dir = TestDir::new("attest");
+        for (i, name) in ["a.ogg", "b.ogg", "c.ogg"].iter().enumerate() {
+            append(dir.path(), &key(), &segment(&dir, name, i)).unwrap();
+        }
+        dir
+    }
+
+    fn edit_chain(dir: &TestDir, edit: impl FnOnce(&mut Vec<String>)) {
+        let mut lines: Vec<String> = fs::read_to_string(chain_path(dir.path()))
+            .unwrap()
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        edit(&mut lines);
+        fs::write(chain_path(dir.path()), lines.join("\n") + "\n").unwrap();
+    }
+
+    /// `(link, path, reason)` of every break, with only the start of each reason
+    fn breaks(dir: &TestDir, trusted_key: Option<&VerifyingKey>) -> Vec<(usize, Option<String>, String)> {
+        verify(dir.path(), trusted_key)
+            .unwrap()
+            .breaks
+            .into_iter()
+            .map(|b| (b.link, b.path, b.reason.split(':').next().unwrap().to_owned()))
+            .collect()
+    }
+
+    fn at(link: usize, path: &str, reason: &str) -> (usize, Option<String>, String) {
+        (link, Some(path.to_owned()), reason.to_owned())
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        let dir = chained_dir();
+        let report = verify(dir.path(), Some(&key().verifying_key())).unwrap();
+        assert!(report.breaks.is_empty(), "{:?}", report.breaks);
+        assert_eq!(report.links, 3);
+        assert_eq!(report.public_key, Some(public_key_hex(&key().verifying_key())));
+        let last = read(dir.path()).unwrap().unwrap().pop().unwrap().unwrap();
+        assert_eq!(report.head, Some(last.hash));
+        assert!(report.unlisted.is_empty());
+    }
+
+    #[test]
+    fn finds_altered_and_deleted_segments() {
+        let dir = chained_dir();
+        fs::write(dir.join("a.ogg"), "segment X").unwrap();
+        fs::remove_file(dir.join("c.ogg")).unwrap();
+        fs::write(dir.join("d.ogg"), "not chained").unwrap();
+        assert_eq!(
+            breaks(&dir, None),
+            [
+                at(1, "a.ogg", "segment file has been altered"),
+                at(3, "c.ogg", "segment file has been deleted")
+            ]
+        );
+        assert_eq!(verify(dir.path(), None).unwrap().unlisted, ["d.ogg"]);
+    }
+
+    #[test]
+    fn finds_a_tampered_link() {
+        let dir = chained_dir();
+        edit_chain(&dir, |lines| lines[1] = lines[1].replace("12:01:00", "12:01:30"));
+        assert_eq!(
+            breaks(&dir, None),
+            [at(2, "b.ogg", "has been edited"), at(2, "b.ogg", "has a bad signature")]
+        );
+    }
+
+    #[test]
+    fn finds_a_deleted_link() {
+        let dir = chained_dir();
+        edit_chain(&dir, |lines| {
+            lines.remove(1);
+        });
+        assert_eq!(
+            breaks(&dir, None),
+            [at(2, "c.ogg", "numbered 2, expected 1"), at(2, "c.ogg", "doesn't follow on from link 1")]
+        );
+    }
+
+    #[test]
+    fn finds_a_deleted_first_link() {
+        let dir = chained_dir();
+        edit_chain(&dir, |lines| {
+            lines.remove(0);
+        });
+        assert_eq!(
+            breaks(&dir, None),
+            [at(1, "b.ogg", "numbered 1, expected 0"), at(1, "b.ogg", "doesn't start the chain")]
+        );
+    }
+
+    #[test]
+    fn finds_reordered_links() {
+        let dir = chained_dir();
+        edit_chain(&dir, |lines| lines.swap(1, 2));
+        assert_eq!(
+            breaks(&dir, None),
+            [
+                at(2, "c.ogg", "numbered 2, expected 1"),
+                at(2, "c.ogg", "doesn't follow on from link 1"),
+                at(3, "b.ogg", "numbered 1, expected 3"),
+                at(3, "b.ogg", "doesn't follow on from link 2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_a_chain_signed_by_someone_else() {
+        let dir = chained_dir();
+        let other = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert_eq!(breaks(&dir, Some(&other)).len(), 3);
+    }
+
+    #[test]
+    fn unreadable_line_is_a_break() {
+        let dir = chained_dir();
+        edit_chain(&dir, |lines| lines.push("{\"seq\": 3".to_owned()));
+        assert_eq!(breaks(&dir, None), [(4, None, "unreadable".to_owned())]);
+        // ...and the next append refuses to carry on from it
+        let entry = segment(&dir, "e.ogg", 4);
+        assert!(append(dir.path(), &key(), &entry).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::encrypt::{self, Identities, ReadSeek};
+use crate::ogg_demux::OggPacketReader;
+use crate::wav::WavReader;
+
+/// What we can learn about a segment from its headers alone, without decoding it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Sample frames, at `sample_rate`
+    pub frames: u64,
+}
+
+impl AudioInfo {
+    pub fn duration_secs(&self) -> f64 {
+        self.frames as f64 / self.sample_rate as f64
+    }
+}
+
+// How far back from the end of an Ogg file to look for the last page. Pages max out just under 64 KiB.
+const OGG_TAIL_LEN: u64 = 1 << 17;
+const OPUS_GRANULE_RATE: u32 = 48_000;
+
+/// Reads sample rate, channel count and length from a WAV/RF64, FLAC, Ogg Vorbis or Ogg Opus file,
+/// decrypting it with `identities` if it's encrypted.
+pub fn read(path: &Path, identities: &Identities) -> Result<AudioInfo, Box<dyn Error>> {
+    let mut f = encrypt::open(path, identities)?;
+    let mut magic = [0; 4];
+    f.read_exact(&mut magic)?;
+    f.seek(SeekFrom::Start(0))?;
+    match &magic {
+        b"RIFF" | b"RF64" => read_wav(f.as_mut()),
+        b"fLaC" => read_flac(f.as_mut()),
+        b"OggS" => read_ogg(f.as_mut()),
+        _ => Err(format!("{} is not a format akasha writes", path.display()).into()),
+    }
+}
+
+fn u16_le(b: &[u8]) -> u16 {
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+fn u32_le(b: &[u8]) -> u32 {
+    u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+}
+
+fn u64_le(b: &[u8]) -> u64 {
+    u64::from_le_bytes(b[..8].try_into().unwrap())
+}
+
+fn read_wav(f: &mut dyn ReadSeek) -> Result<AudioInfo, Box<dyn Error>> {
+    let reader = WavReader::new(f)?;
+    Ok(AudioInfo {
+        sample_rate: reader.spec().sample_rate,
+        channels: reader.spec().channels,
+        frames: reader.frames(),
+    })
+}
+
+fn read_flac(f: &mut dyn ReadSeek) -> Result<AudioInfo, Box<dyn Error>> {
+    // "fLaC", then the STREAMINFO block header, then STREAMINFO itself
+    let mut header = [0; 8 + 18];
+    f.read_exact(&mut header)?;
+    if header[4] & 0x7f != 0 {
+        return Err("FLAC file doesn't start with STREAMINFO".into());
+    }
+    // 20 bits sample rate, 3 bits channels - 1, 5 bits bits per sample - 1, 36 bits total samples
+    let packed = u64::from_be_bytes(header[18..26].try_into().unwrap());
+    let mut frames = packed & 0xf_ffff_ffff;
+    // Zero means unknown, as in an encrypted segment, which can't go back and fill it in;
+    // count the blocks instead
+    if frames == 0 {
+        f.seek(SeekFrom::Start(0))?;
+        let mut reader = claxon::FlacReader::new(&mut *f)?;
+        let mut blocks = reader.blocks();
+        let mut buffer = Vec::new();
+        // A file cut short by a crash ends in half a block; count up to there
+        while let Ok(Some(block)) = blocks.read_next_or_eof(buffer) {
+            frames += block.duration() as u64;
+            buffer = block.into_buffer();
+        }
+    }
+    Ok(AudioInfo {
+        sample_rate: (packed >> 44) as u32,
+        channels: ((packed >> 41) & 0x7) as u16 + 1,
+        frames,
+    })
+}
+
+fn read_ogg(f: &mut dyn ReadSeek) -> Result<AudioInfo, Box<dyn Error>> {
+    let first_packet = OggPacketReader::new(&mut *f)
+        .next_packet()?
+        .ok_or("Empty Ogg stream")?
+        .data;
+    let (sample_rate, channels, pre_skip) = if first_packet.len() < 19 {
+        return Err("Ogg stream is too short to identify".into());
+    } else if first_packet.starts_with(b"\x01vorbis") {
+        (u32_le(&first_packet[12..]), first_packet[11] as u16, 0)
+    } else if first_packet.starts_with(b"OpusHead") {
+        // Opus always decodes at 48 kHz, whatever the input rate was
+        (OPUS_GRANULE_RATE, first_packet[9] as u16, u16_le(&first_packet[10..]) as u64)
+    } else {
+        return Err("Ogg stream is neither Vorbis nor Opus".into());
+    };
+    Ok(AudioInfo {
+        sample_rate,
+        channels,
+        frames: last_ogg_granule(f)?.saturating_sub(pre_skip),
+    })
+}
+
+/// The granule position of the last page that has one, i.e. the stream length in samples.
+fn last_ogg_granule(f: &mut dyn ReadSeek) -> Result<u64, Box<dyn Error>> {
+    let file_len = f.seek(SeekFrom::End(0))?;
+    let tail_start = file_len.saturating_sub(OGG_TAIL_LEN);
+    f.seek(SeekFrom::Start(tail_start))?;
+    let mut tail = Vec::new();
+    f.read_to_end(&mut tail)?;
+    let mut end = tail.len();
+    while let Some(pos) = tail[..end].windows(4).rposition(|w| w == b"OggS") {
+        // Version 0 and a sane header type make it less likely we've just hit "OggS" in packet data
+        if let Some(header) = tail.get(pos..pos + 14).filter(|h| h[4] == 0 && h[5] < 8) {
+            let granule = u64_le(&header[6..14]);
+            // -1 marks a page on which no packet ends
+            if granule != u64::MAX {
+                return Ok(granule);
+            }
+        }
+        end = pos;
+    }
+    Ok(0)
+}
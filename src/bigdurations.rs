@@ -1,3 +1,5 @@
+#![allow(dead_code)]
+
 use std::time::Duration;
 
 static MILLIS_PER_MIN: f64 = 60_000f64;
@@ -0,0 +1,425 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::audio_info;
+use crate::encrypt::{self, Identities};
+use crate::name_template::NameTemplate;
+use crate::segment_file;
+use crate::segment_meta::{self, SegmentSidecar};
+use crate::time_zone::NameTimeZone;
+
+/// Lives at the top of the recording directory, one JSON object per line.
+pub const CATALOG_FILE_NAME: &str = "akasha-catalog.jsonl";
+pub const AUDIO_EXTENSIONS: [&str; 4] = ["wav", "ogg", "flac", "opus"];
+
+/// One finished segment, as recorded in the catalog.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    /// Relative to the catalog's directory, so the archive can be moved around
+    pub path: String,
+    pub start: String,
+    pub duration_secs: f64,
+    pub device: Option<String>,
+    pub format: String,
+    pub size: u64,
+    /// Left out when listing an uncatalogued directory, where hashing every file would be too slow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl CatalogEntry {
+    pub fn start_time(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.start).ok()
+    }
+
+    pub fn end_time(&self) -> Option<DateTime<FixedOffset>> {
+        let duration = chrono::Duration::microseconds((self.duration_secs * 1e6) as i64);
+        Some(self.start_time()? + duration)
+    }
+}
+
+pub fn catalog_path(dir: &Path) -> PathBuf {
+    dir.join(CATALOG_FILE_NAME)
+}
+
+pub fn sha256_file(path: &Path) -> io::Result<String> {
+    let mut f = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 16];
+    loop {
+        let len = f.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        hasher.update(&buf[..len]);
+    }
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn relative_path(dir: &Path, path: &Path) -> String {
+    path.strip_prefix(dir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Catalogs a segment the recorder just finished, using what its sidecar already says.
+pub fn entry_from_sidecar(
+    dir: &Path,
+    path: &Path,
+    sidecar: &SegmentSidecar,
+) -> Result<CatalogEntry, Box<dyn Error>> {
+    Ok(CatalogEntry {
+        path: relative_path(dir, path),
+        start: sidecar.start.clone(),
+        duration_secs: sidecar.duration_secs,
+        device: Some(sidecar.device.clone()),
+        format: sidecar.format.clone(),
+        size: fs::metadata(path)?.len(),
+        sha256: Some(sha256_file(path)?),
+        sample_rate: sidecar.sample_rate,
+        channels: sidecar.channels,
+    })
+}
+
+/// Adds one entry to the end of the catalog in `dir`.
+pub fn append(dir: &Path, entry: &CatalogEntry) -> Result<(), Box<dyn Error>> {
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(catalog_path(dir))?;
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    // One write, so concurrent appends can't interleave within a line
+    f.write_all(line.as_bytes())?;
+    f.sync_data()?;
+    Ok(())
+}
+
+/// Recovers a segment's start time from its path relative to `dir`, as `name_template` made it:
+/// maybe under a format subdirectory, maybe with a `__<n>` suffix. Times without an offset are taken
+/// to be in `time_zone`.
+pub fn parse_segment_start(
+    relative_path: &str,
+    name_prefix: &str,
+    name_template: &NameTemplate,
+    time_zone: NameTimeZone,
+) -> Option<DateTime<FixedOffset>> {
+    let plain_path = encrypt::plain_path(Path::new(relative_path));
+    let name = plain_path.with_extension("");
+    let name = name.to_str()?;
+    // Maybe a `__<n>` suffix added to dodge a name collision
+    let unsuffixed = name
+        .rsplit_once("__")
+        .filter(|(_, n)| n.parse::<u32>().is_ok())
+        .map(|(name, _)| name);
+    // The name template starts wherever the format's subdirectory, if any, ends
+    for i in std::iter::once(0).chain(name.match_indices('/').map(|(i, _)| i + 1)) {
+        for name in [Some(name), unsuffixed].into_iter().flatten() {
+            if let Some(start) = name_template.parse_start(&name[i..], name_prefix, time_zone) {
+                return Some(start);
+            }
+        }
+    }
+    None
+}
+
+fn is_segment_file(path: &Path) -> bool {
+    encrypt::plain_path(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext))
+}
+
+/// Every finished segment file under `dir`, including format subdirectories and encrypted ones.
+pub fn find_segment_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    find_files(dir, is_segment_file)
+}
+
+/// Every `.partial` segment file under `dir`: still being recorded, or left behind by a recording that
+/// crashed or couldn't finish it.
+pub fn find_partial_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    find_files(dir, |path| {
+        path.extension() == Some(OsStr::new(segment_file::PARTIAL_EXTENSION))
+            && is_segment_file(&path.with_extension(""))
+    })
+}
+
+fn find_files(dir: &Path, keep: impl Fn(&Path) -> bool) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if keep(&path) {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Works out a catalog entry for a segment file from scratch: from its sidecar if it has one,
+/// otherwise from its name and headers, decrypted with `identities` if need be.
+/// The file is only hashed if `hash` is set. `None` if it has no sidecar and its name doesn't fit `name_template`.
+pub fn scan_segment(
+    dir: &Path,
+    path: &Path,
+    name_prefix: &str,
+    name_template: &NameTemplate,
+    time_zone: NameTimeZone,
+    hash: bool,
+    identities: &Identities,
+) -> Result<Option<CatalogEntry>, Box<dyn Error>> {
+    let sha256 = |path| if hash { sha256_file(path).map(Some) } else { Ok(None) };
+    let sidecar_path = segment_meta::sidecar_path(path);
+    if let Ok(sidecar) = fs::read(&sidecar_path) {
+        match serde_json::from_slice::<SegmentSidecar>(&sidecar) {
+            Ok(sidecar) => {
+                return Ok(Some(CatalogEntry {
+                    path: relative_path(dir, path),
+                    start: sidecar.start,
+                    duration_secs: sidecar.duration_secs,
+                    device: Some(sidecar.device),
+                    format: sidecar.format,
+                    size: fs::metadata(path)?.len(),
+                    sha256: sha256(path)?,
+                    sample_rate: sidecar.sample_rate,
+                    channels: sidecar.channels,
+                }))
+            }
+            Err(e) => warn!("Ignoring bad sidecar {}: {}", sidecar_path.display(), e),
+        }
+    }
+    let Some(start) = parse_segment_start(&relative_path(dir, path), name_prefix, name_template, time_zone) else {
+        return Ok(None);
+    };
+    let plain_path = encrypt::plain_path(path);
+    let info = audio_info::read(path, identities)?;
+    Ok(Some(CatalogEntry {
+        path: relative_path(dir, path),
+        start: segment_meta::format_time(&start.with_timezone(&Utc)),
+        duration_secs: info.duration_secs(),
+        device: None,
+        format: plain_path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        size: fs::metadata(path)?.len(),
+        sha256: sha256(path)?,
+        sample_rate: info.sample_rate,
+        channels: info.channels,
+    }))
+}
+
+/// Scans every segment file under `dir`, skipping the ones that can't be made sense of with a warning.
+/// Sorted by start time.
+/// Files without a sidecar whose names don't fit `name_template` are counted up in a single warning,
+/// since they're most likely all from recording with a different `--name-template` or `--time-format`.
+pub fn scan(
+    dir: &Path,
+    name_prefix: &str,
+    name_template: &NameTemplate,
+    time_zone: NameTimeZone,
+    hash: bool,
+    identities: &Identities,
+) -> io::Result<Vec<CatalogEntry>> {
+    let mut entries = Vec::new();
+    let mut unnamed = Vec::new();
+    for path in find_segment_files(dir)? {
+        match scan_segment(dir, &path, name_prefix, name_template, time_zone, hash, identities) {
+            Ok(Some(entry)) => entries.push(entry),
+            Ok(None) => unnamed.push(relative_path(dir, &path)),
+            Err(e) => warn!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    if !unnamed.is_empty() {
+        warn!(
+            "Skipping {} segment files with no sidecar whose names don't fit the name template, e.g. {}; \
+             pass the --name-template or --time-format and --name-prefix they were recorded with",
+            unnamed.len(),
+            unnamed[0]
+        );
+    }
+    entries.sort_by_key(|entry| entry.start_time());
+    Ok(entries)
+}
+
+/// Reads the catalog in `dir`, if there is one.
+pub fn read(dir: &Path) -> Result<Option<Vec<CatalogEntry>>, Box<dyn Error>> {
+    let f = match File::open(catalog_path(dir)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            // Most likely a line cut short by a crash; the rest is still good
+            Err(e) => warn!("Skipping bad catalog line {}: {}", i + 1, e),
+        }
+    }
+    Ok(Some(entries))
+}
+
+/// The segments in `dir`, sorted by start time: from the catalog if there is one, otherwise scanned.
+pub fn load(
+    dir: &Path,
+    name_prefix: &str,
+    name_template: &NameTemplate,
+    time_zone: NameTimeZone,
+    identities: &Identities,
+) -> Result<Vec<CatalogEntry>, Box<dyn Error>> {
+    match read(dir)? {
+        Some(mut entries) => {
+            entries.sort_by_key(|entry| entry.start_time());
+            Ok(entries)
+        }
+        None => {
+            info!("No catalog in {}, scanning segment files", dir.display());
+            Ok(scan(dir, name_prefix, name_template, time_zone, false, identities)?)
+        }
+    }
+}
+
+/// Rebuilds the catalog in `dir` from the segment files there, replacing whatever was there before.
+/// Files that can't be catalogued are skipped with a warning. Returns the new entries.
+pub fn rebuild(
+    dir: &Path,
+    name_prefix: &str,
+    name_template: &NameTemplate,
+    time_zone: NameTimeZone,
+    identities: &Identities,
+) -> Result<Vec<CatalogEntry>, Box<dyn Error>> {
+    let entries = scan(dir, name_prefix, name_template, time_zone, true, identities)?;
+
+    let target = catalog_path(dir);
+    let partial = segment_file::partial_path(&target);
+    let _ = fs::remove_file(&partial);
+    let mut f = segment_file::create_new(&partial)?;
+    for entry in &entries {
+        serde_json::to_writer(&mut f, entry)?;
+        f.write_all(b"\n")?;
+    }
+    f.sync_all()?;
+    // Unlike segments, replacing the old catalog is the whole point
+    fs::rename(&partial, &target)?;
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use crate::time_zone::ISO8601_TIME_FORMAT;
+    use crate::DEFAULT_TIME_FORMAT;
+
+    fn start(relative_path: &str, time_format: &str, time_zone: NameTimeZone) -> Option<String> {
+        let template = NameTemplate::from_time_format(time_format).unwrap();
+        parse_segment_start(relative_path, "akasha", &template, time_zone).map(|start| start.to_rfc3339())
+    }
+
+    #[test]
+    fn parses_the_default_time_format() {
+        assert_eq!(
+            start("akasha__2024-05-01__14_37_00__Wed_May__+0200.ogg", DEFAULT_TIME_FORMAT, NameTimeZone::Utc),
+            Some("2024-05-01T14:37:00+02:00".to_owned())
+        );
+    }
+
+    #[test]
+    fn parses_iso8601_names_with_a_collision_suffix() {
+        assert_eq!(
+            start("akasha__20240501T143700+0000__2.flac", ISO8601_TIME_FORMAT, NameTimeZone::Utc),
+            Some("2024-05-01T14:37:00+00:00".to_owned())
+        );
+    }
+
+    #[test]
+    fn reads_times_without_an_offset_in_the_time_zone() {
+        let berlin = NameTimeZone::Named(chrono_tz::Europe::Berlin);
+        assert_eq!(
+            start("akasha__2024-05-01_14-37.wav", "%Y-%m-%d_%H-%M", berlin),
+            Some("2024-05-01T14:37:00+02:00".to_owned())
+        );
+        assert_eq!(
+            start("akasha__2024-05-01_14-37.wav", "%Y-%m-%d_%H-%M", NameTimeZone::Utc),
+            Some("2024-05-01T14:37:00+00:00".to_owned())
+        );
+    }
+
+    #[test]
+    fn rejects_other_names() {
+        assert_eq!(start("other__20240501T143700+0000.ogg", ISO8601_TIME_FORMAT, NameTimeZone::Utc), None);
+        assert_eq!(start("akasha__not-a-time.ogg", ISO8601_TIME_FORMAT, NameTimeZone::Utc), None);
+        assert_eq!(start("akasha__20240501T143700+0000__x.ogg", ISO8601_TIME_FORMAT, NameTimeZone::Utc), None);
+    }
+
+    #[test]
+    fn reads_names_under_format_subdirectories_and_encrypted() {
+        assert_eq!(
+            start("opus/akasha__20240501T143700+0000__3.opus.age", ISO8601_TIME_FORMAT, NameTimeZone::Utc),
+            Some("2024-05-01T14:37:00+00:00".to_owned())
+        );
+    }
+
+    #[test]
+    fn reads_names_made_by_a_name_template() {
+        let template = crate::name_template::parse("{device}/%Y/%m/%d/{prefix}_{index:05}_%H%M%S").unwrap();
+        let start = |relative_path| {
+            parse_segment_start(relative_path, "akasha", &template, NameTimeZone::Utc).map(|start| start.to_rfc3339())
+        };
+        let expected = Some("2024-05-01T14:37:05+00:00".to_owned());
+        assert_eq!(start("mic/2024/05/01/akasha_00042_143705.ogg"), expected);
+        assert_eq!(start("flac/mic/2024/05/01/akasha_00042_143705__2.flac"), expected);
+        assert_eq!(start("mic/2024/05/01/akasha__2024-05-01__14_37_05__Wed_May__+0000.ogg"), None);
+    }
+
+    #[test]
+    fn scanning_skips_names_that_dont_fit_the_template() {
+        let dir = TestDir::new("scan-names");
+        let template = NameTemplate::from_time_format(ISO8601_TIME_FORMAT).unwrap();
+        fs::write(dir.join("mic_00001_143705.ogg"), "").unwrap();
+        let identities = Identities::default();
+        assert!(scan_segment(dir.path(), &dir.join("mic_00001_143705.ogg"), "akasha", &template, NameTimeZone::Utc, false, &identities)
+            .unwrap()
+            .is_none());
+        assert!(scan(dir.path(), "akasha", &template, NameTimeZone::Utc, false, &identities).unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_segments_in_subdirectories_but_not_partials() {
+        let dir = TestDir::new("find-segments");
+        fs::create_dir_all(dir.join("opus")).unwrap();
+        for name in ["a.ogg", "opus/b.opus", "c.flac.age", "d.wav.partial", "e.txt", "a.ogg.json"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let relative = |paths: Vec<PathBuf>| -> Vec<String> {
+            paths.iter().map(|path| relative_path(dir.path(), path)).collect()
+        };
+        assert_eq!(
+            relative(find_segment_files(dir.path()).unwrap()),
+            ["a.ogg", "c.flac.age", "opus/b.opus"]
+        );
+        assert_eq!(relative(find_partial_files(dir.path()).unwrap()), ["d.wav.partial"]);
+    }
+}
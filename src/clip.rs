@@ -0,0 +1,312 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_fn_stream::fn_stream;
+use chrono::Utc;
+use futures_core::Stream;
+use futures_util::StreamExt;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::gain::db_to_amplitude;
+use crate::{hook, segment_meta, Chunk, ClipOpts};
+
+/// Clipping this close together counts as one event.
+const EVENT_GAP_SECS: u32 = 1;
+/// A segment that clips all the way through doesn't need every event listed.
+const MAX_EVENTS: usize = 1000;
+/// At most one warning about clipping this often, summing up everything since the last one.
+const WARN_INTERVAL_SECS: u32 = 10;
+/// How long the display keeps showing the clip indicator after the last clipped sample.
+pub const INDICATOR_HOLD: Duration = Duration::from_secs(1);
+
+/// One burst of clipping.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClipEvent {
+    /// When the first clipped sample came in, UTC
+    pub start: String,
+    /// Clipped samples in the burst, all channels together
+    pub samples: u64,
+}
+
+/// How much the input clipped during one segment, before any processing.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClipReport {
+    /// Per channel
+    pub samples: Vec<u64>,
+    /// The first `MAX_EVENTS` bursts
+    pub events: Vec<ClipEvent>,
+}
+
+/// Clipping seen by the detector, until the recorder takes it for a segment.
+#[derive(Debug)]
+pub struct ClipTrack {
+    report: ClipReport,
+    last_clip: Option<Instant>,
+}
+
+impl ClipTrack {
+    /// What's been seen since the last `reset`.
+    pub fn report(&self) -> ClipReport {
+        self.report.clone()
+    }
+
+    /// Starts over, for the next segment.
+    pub fn reset(&mut self) {
+        self.report.samples.iter_mut().for_each(|samples| *samples = 0);
+        self.report.events.clear();
+    }
+
+    /// Whether the input clipped within the last `INDICATOR_HOLD`, for the display.
+    pub fn recently_clipped(&self) -> bool {
+        self.last_clip.is_some_and(|at| at.elapsed() < INDICATOR_HOLD)
+    }
+}
+
+/// Counts samples at or near full scale on each channel as they come in from the device, warns about them
+/// at most every `WARN_INTERVAL_SECS`, and runs `--clip-hook` once clipping has gone on for `--clip-alert-after`.
+pub struct ClipDetector {
+    threshold: f32,
+    sample_rate: u32,
+    alert_after_secs: u64,
+    hook: Option<String>,
+    track: Arc<Mutex<ClipTrack>>,
+    /// Frames since the last clipped sample, for telling bursts apart
+    frames_since_clip: u64,
+    /// Whether the current burst made it into the list
+    listing_event: bool,
+    /// Per channel, since the last warning
+    unwarned: Vec<u64>,
+    frames_since_warning: u64,
+    frames_this_second: u32,
+    clipped_this_second: bool,
+    /// Consecutive seconds with clipping in them
+    streak_secs: u64,
+    alerted: bool,
+}
+
+impl ClipDetector {
+    pub fn from_opts(opts: &ClipOpts, channels: usize, sample_rate: u32) -> Self {
+        Self {
+            threshold: db_to_amplitude(opts.clip_threshold),
+            sample_rate,
+            alert_after_secs: opts.clip_alert_after.as_secs().max(1),
+            hook: opts.clip_hook.clone(),
+            track: Arc::new(Mutex::new(ClipTrack {
+                report: ClipReport {
+                    samples: vec![0; channels],
+                    events: Vec::new(),
+                },
+                last_clip: None,
+            })),
+            frames_since_clip: u64::MAX,
+            listing_event: false,
+            unwarned: vec![0; channels],
+            frames_since_warning: u64::MAX,
+            frames_this_second: 0,
+            clipped_this_second: false,
+            streak_secs: 0,
+            alerted: false,
+        }
+    }
+
+    /// Where clipping is written down, for the recorder to take at the end of each segment.
+    pub fn track(&self) -> Arc<Mutex<ClipTrack>> {
+        self.track.clone()
+    }
+
+    pub fn process(&mut self, chunk: &[f32]) {
+        let channels = self.unwarned.len();
+        let track = self.track.clone();
+        let mut track = track.lock().unwrap_or_else(|e| e.into_inner());
+        for frame in chunk.chunks_exact(channels) {
+            let mut clipped_in_frame = 0;
+            for (channel, sample) in frame.iter().enumerate() {
+                if sample.abs() >= self.threshold {
+                    track.report.samples[channel] += 1;
+                    self.unwarned[channel] += 1;
+                    clipped_in_frame += 1;
+                }
+            }
+            if clipped_in_frame > 0 {
+                // A burst still going when the last segment ended starts this segment's list too
+                let new_event = self.frames_since_clip > (EVENT_GAP_SECS * self.sample_rate) as u64
+                    || track.report.events.is_empty();
+                if new_event {
+                    self.listing_event = track.report.events.len() < MAX_EVENTS;
+                    if self.listing_event {
+                        track.report.events.push(ClipEvent {
+                            start: segment_meta::format_time(&Utc::now()),
+                            samples: 0,
+                        });
+                    }
+                }
+                if let Some(event) = track.report.events.last_mut().filter(|_| self.listing_event) {
+                    event.samples += clipped_in_frame;
+                }
+                track.last_clip = Some(Instant::now());
+                self.frames_since_clip = 0;
+                self.clipped_this_second = true;
+            } else {
+                self.frames_since_clip = self.frames_since_clip.saturating_add(1);
+            }
+            self.frames_since_warning = self.frames_since_warning.saturating_add(1);
+            self.frames_this_second += 1;
+            if self.frames_this_second >= self.sample_rate {
+                self.end_second();
+            }
+        }
+    }
+
+    fn end_second(&mut self) {
+        self.frames_this_second = 0;
+        if self.clipped_this_second {
+            self.streak_secs += 1;
+        } else {
+            self.streak_secs = 0;
+            self.alerted = false;
+        }
+        self.clipped_this_second = false;
+
+        if self.unwarned.iter().any(|samples| *samples > 0)
+            && self.frames_since_warning >= (WARN_INTERVAL_SECS * self.sample_rate) as u64
+        {
+            warn!("Input is clipping: {}", describe(&self.unwarned));
+            self.unwarned.iter_mut().for_each(|samples| *samples = 0);
+            self.frames_since_warning = 0;
+        }
+        if self.streak_secs >= self.alert_after_secs && !self.alerted {
+            self.alerted = true;
+            warn!("Input has been clipping for {}s; turn the input gain down", self.streak_secs);
+            if let Some(command) = &self.hook {
+                hook::run(
+                    command,
+                    "clipping",
+                    &[("AKASHA_CLIPPING_SECS", self.streak_secs.to_string())],
+                );
+            }
+        }
+    }
+}
+
+/// `120 sample(s) on channel 1, 4 sample(s) on channel 2`
+fn describe(per_channel: &[u64]) -> String {
+    per_channel
+        .iter()
+        .enumerate()
+        .filter(|(_, samples)| **samples > 0)
+        .map(|(channel, samples)| format!("{} sample(s) on channel {}", samples, channel + 1))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Runs every chunk past `detector`, leaving it as it is.
+pub fn getstream_clip_detect<S: Stream<Item = Chunk> + Unpin>(
+    mut audio_stream: S,
+    mut detector: ClipDetector,
+) -> impl Stream<Item = Chunk> {
+    fn_stream(|emitter| async move {
+        while let Some(chunk) = audio_stream.next().await {
+            detector.process(&chunk);
+            emitter.emit(chunk).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_opts::rec_opts;
+
+    const SAMPLE_RATE: u32 = 1_000;
+
+    fn detector(args: &[&str], channels: usize) -> ClipDetector {
+        ClipDetector::from_opts(&rec_opts(args).clip, channels, SAMPLE_RATE)
+    }
+
+    /// `secs` of silence, on one channel.
+    fn quiet(secs: f32) -> Vec<f32> {
+        vec![0.; (secs * SAMPLE_RATE as f32) as usize]
+    }
+
+    #[test]
+    fn counts_samples_at_full_scale_per_channel() {
+        let mut detector = detector(&[], 2);
+        detector.process(&[1., 0.5, -1., 0.99, 0.999, -1., 0., 0.]);
+        let report = detector.track().lock().unwrap().report();
+        // -0.1dBFS is about 0.9886
+        assert_eq!(report.samples, vec![3, 2]);
+        assert_eq!(report.events.len(), 1);
+        assert_eq!(report.events[0].samples, 5);
+        assert!(detector.track().lock().unwrap().recently_clipped());
+    }
+
+    #[test]
+    fn threshold_is_adjustable() {
+        let mut detector = detector(&["--clip-threshold", "-6dBFS"], 1);
+        detector.process(&[0.4, 0.6, -0.55]);
+        assert_eq!(detector.track().lock().unwrap().report().samples, vec![2]);
+    }
+
+    #[test]
+    fn bursts_further_apart_than_the_gap_are_separate_events() {
+        let mut detector = detector(&[], 1);
+        detector.process(&[1., 1.]);
+        detector.process(&quiet(0.5));
+        detector.process(&[1.]);
+        detector.process(&quiet(1.5));
+        detector.process(&[-1., -1., -1.]);
+        let report = detector.track().lock().unwrap().report();
+        let samples: Vec<u64> = report.events.iter().map(|event| event.samples).collect();
+        assert_eq!(samples, vec![3, 3]);
+        assert_eq!(report.samples, vec![6]);
+        assert!(report.events[0].start <= report.events[1].start);
+    }
+
+    #[test]
+    fn reset_starts_a_new_list_even_mid_burst() {
+        let mut detector = detector(&[], 1);
+        detector.process(&[1., 1.]);
+        detector.track().lock().unwrap().reset();
+        assert_eq!(detector.track().lock().unwrap().report(), ClipReport { samples: vec![0], events: vec![] });
+        detector.process(&[1.]);
+        let report = detector.track().lock().unwrap().report();
+        assert_eq!(report.events.len(), 1);
+        assert_eq!(report.events[0].samples, 1);
+    }
+
+    #[test]
+    fn events_are_capped() {
+        let mut detector = detector(&[], 1);
+        let mut chunk = Vec::new();
+        for _ in 0..MAX_EVENTS + 5 {
+            chunk.push(1.);
+            chunk.extend(quiet(1.1));
+        }
+        detector.process(&chunk);
+        let report = detector.track().lock().unwrap().report();
+        assert_eq!(report.events.len(), MAX_EVENTS);
+        assert_eq!(report.samples, vec![MAX_EVENTS as u64 + 5]);
+    }
+
+    #[test]
+    fn alerts_once_clipping_has_gone_on_long_enough() {
+        let mut detector = detector(&["--clip-alert-after", "3s"], 1);
+        let clipping_second = [vec![1.], quiet(0.999)].concat();
+        for _ in 0..2 {
+            detector.process(&clipping_second);
+        }
+        assert!(!detector.alerted);
+        detector.process(&clipping_second);
+        assert!(detector.alerted);
+        // A clean second ends the streak, and the next one can alert again
+        detector.process(&quiet(1.));
+        assert!(!detector.alerted);
+        assert_eq!(detector.streak_secs, 0);
+    }
+
+    #[test]
+    fn describes_only_the_channels_that_clipped() {
+        assert_eq!(describe(&[120, 0, 4]), "120 sample(s) on channel 1, 4 sample(s) on channel 3");
+    }
+}
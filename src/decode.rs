@@ -0,0 +1,169 @@
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::audio_info;
+use crate::encrypt::{self, Identities};
+use crate::ogg_demux::OggPacketReader;
+use crate::opus_decoder::{self, OpusDecoder};
+use crate::vorbis_decoder::VorbisDecoder;
+use crate::wav::WavReader;
+use crate::Chunk;
+
+const WAV_CHUNK_FRAMES: usize = 4096;
+
+/// Reads a finished segment back as interleaved f32 chunks, whatever format it was written in.
+pub trait SegmentReader {
+    fn sample_rate(&self) -> u32;
+    fn channels(&self) -> u16;
+    /// The next chunk of audio, or `None` once the segment is used up
+    fn read_chunk(&mut self) -> Result<Option<Chunk>, Box<dyn Error>>;
+}
+
+/// Opens a WAV/RF64, FLAC, Ogg Vorbis or Ogg Opus segment for decoding, decrypting it with
+/// `identities` if it's encrypted.
+pub fn open(path: &Path, identities: &Identities) -> Result<Box<dyn SegmentReader>, Box<dyn Error>> {
+    let mut f = encrypt::open(path, identities)?;
+    let mut magic = [0; 4];
+    f.read_exact(&mut magic)?;
+    f.seek(SeekFrom::Start(0))?;
+    match &magic {
+        b"RIFF" | b"RF64" => Ok(Box::new(WavReader::new(f)?)),
+        b"fLaC" => Ok(Box::new(FlacSegmentReader::new(f)?)),
+        b"OggS" => {
+            let first_packet = OggPacketReader::new(&mut f).next_packet()?;
+            f.seek(SeekFrom::Start(0))?;
+            let is_opus = first_packet.is_some_and(|packet| packet.data.starts_with(b"OpusHead"));
+            let reader: Box<dyn SegmentReader> = if is_opus {
+                Box::new(OpusDecoder::new(f)?)
+            } else {
+                Box::new(VorbisDecoder::new(f)?)
+            };
+            // Codecs work in whole blocks, so the last one is padded out; the final granule position
+            // says where the real audio ends
+            Ok(Box::new(Trimmed {
+                inner: reader,
+                remaining: audio_info::read(path, identities)?.frames,
+            }))
+        }
+        _ => Err(format!("{} is not a format akasha writes", path.display()).into()),
+    }
+}
+
+impl<R: Read + Seek> SegmentReader for WavReader<R> {
+    fn sample_rate(&self) -> u32 {
+        self.spec().sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.spec().channels
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<Chunk>, Box<dyn Error>> {
+        let chunk = self.read_frames(WAV_CHUNK_FRAMES)?;
+        Ok((!chunk.is_empty()).then_some(chunk))
+    }
+}
+
+impl<R: Read> SegmentReader for VorbisDecoder<R> {
+    fn sample_rate(&self) -> u32 {
+        VorbisDecoder::sample_rate(self)
+    }
+
+    fn channels(&self) -> u16 {
+        VorbisDecoder::channels(self)
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<Chunk>, Box<dyn Error>> {
+        self.decode_chunk()
+    }
+}
+
+impl<R: Read> SegmentReader for OpusDecoder<R> {
+    fn sample_rate(&self) -> u32 {
+        opus_decoder::DECODE_RATE
+    }
+
+    fn channels(&self) -> u16 {
+        OpusDecoder::channels(self)
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<Chunk>, Box<dyn Error>> {
+        self.decode_chunk()
+    }
+}
+
+struct FlacSegmentReader<R: Read> {
+    reader: claxon::FlacReader<R>,
+    buffer: Vec<i32>,
+    scale: f32,
+}
+
+impl<R: Read> FlacSegmentReader<R> {
+    fn new(source: R) -> Result<Self, Box<dyn Error>> {
+        let reader = claxon::FlacReader::new(source)?;
+        let scale = 1. / (1u64 << (reader.streaminfo().bits_per_sample - 1)) as f32;
+        Ok(Self {
+            reader,
+            buffer: Vec::new(),
+            scale,
+        })
+    }
+}
+
+impl<R: Read> SegmentReader for FlacSegmentReader<R> {
+    fn sample_rate(&self) -> u32 {
+        self.reader.streaminfo().sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.reader.streaminfo().channels as u16
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<Chunk>, Box<dyn Error>> {
+        let buffer = std::mem::take(&mut self.buffer);
+        let Some(block) = self.reader.blocks().read_next_or_eof(buffer)? else {
+            return Ok(None);
+        };
+        let mut chunk = Vec::with_capacity((block.duration() * block.channels()) as usize);
+        for i in 0..block.duration() {
+            for channel in 0..block.channels() {
+                chunk.push(block.sample(channel, i) as f32 * self.scale);
+            }
+        }
+        self.buffer = block.into_buffer();
+        Ok(Some(chunk))
+    }
+}
+
+/// Cuts a reader off after `remaining` frames.
+struct Trimmed {
+    inner: Box<dyn SegmentReader>,
+    remaining: u64,
+}
+
+impl SegmentReader for Trimmed {
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn channels(&self) -> u16 {
+        self.inner.channels()
+    }
+
+    fn read_chunk(&mut self) -> Result<Option<Chunk>, Box<dyn Error>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let Some(mut chunk) = self.inner.read_chunk()? else {
+            return Ok(None);
+        };
+        let channels = self.channels() as usize;
+        let frames = (chunk.len() / channels) as u64;
+        if frames > self.remaining {
+            chunk.truncate(self.remaining as usize * channels);
+        }
+        self.remaining -= frames.min(self.remaining);
+        Ok(Some(chunk))
+    }
+}
@@ -1,16 +1,32 @@
 use std::fmt::{Display, Formatter};
-use std::iter::Sum;
-use std::sync::Arc;
+use std::io::IsTerminal;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration};
 use async_fn_stream::fn_stream;
+use crossterm::style::{Color, Stylize};
 use futures_core::stream::Stream;
 use futures_util::StreamExt;
 use log::info;
+use crate::clip::ClipTrack;
+use crate::segment_stats::amplitude_to_dbfs;
 use crate::{Chunk, ProgramState};
 use wide::*;
 use tokio::time::Instant;
 use printrn::printrn;
 
+/// The left end of the bar; anything quieter reads as empty.
+const METER_FLOOR_DBFS: f32 = -60.;
+/// The bar turns yellow from here...
+const YELLOW_FROM_DBFS: f32 = -18.;
+/// ...and red from here.
+const RED_FROM_DBFS: f32 = -6.;
+/// The RMS level is averaged over about this long, so the bar moves like a VU meter rather than flickering.
+const RMS_WINDOW_SECS: f32 = 0.3;
+/// How long the peak marker stays put before it starts to fall...
+const PEAK_HOLD_SECS: f32 = 1.5;
+/// ...and how fast it falls then.
+const PEAK_DECAY_DB_PER_SEC: f32 = 20.;
+
 // When I try to make these generic I get:
 // "type parameter `F` must be covered by another type when it appears before the first local type (`Db<F>`)"
 // No idea why :(
@@ -22,7 +38,7 @@ pub struct Db(f32);
 
 impl Display for Db {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "dB: {:06.2}", self.0)?;
+        write!(f, "{:6.1}", self.0)?;
         Ok(())
     }
 }
@@ -35,7 +51,7 @@ pub struct NormRatio(f32);
 impl NormRatio {
     pub fn new<F>(value: F) -> Option<NormRatio>  where F: Into<f32> + Copy {
         let value: f32 = value.into();
-        if value >= 0.0 && value <= 1.0 {
+        if (0.0..=1.0).contains(&value) {
             Some(NormRatio(value))
         } else {
             None
@@ -46,68 +62,202 @@ impl NormRatio {
         NormRatio(value.into().clamp(0., 1.))
     }
 
+    /// Where `db` sits on the meter, which is linear in dB from `METER_FLOOR_DBFS` to full scale.
+    fn on_meter(db: &Db) -> NormRatio {
+        NormRatio::new_clamped((db.0 - METER_FLOOR_DBFS) / -METER_FLOOR_DBFS)
+    }
+
     fn get(&self) -> f32 {
         self.0
     }
+}
 
-    fn replace<F>(&mut self, value: F) where F: Into<f32> + Copy {
-        let value: f32 = value.into();
-        self.0 = value.clamp(0., 1.);
+/// dBFS to amplitude, as a fraction of full scale.
+impl From<Db> for NormRatio {
+    fn from(db: Db) -> NormRatio {
+        NormRatio::new_clamped(10f32.powf(db.0 / 20.))
+    }
+}
+
+fn sum_of_squares(samples: &[f32]) -> f32 {
+    // SIMD over whole chunks of 8, then whatever's left over one at a time
+    let chunks = samples.chunks_exact(8);
+    let remainder = chunks.remainder();
+    let simd_sum = chunks.fold(f32x8::ZERO, |sum, chunk| {
+        let simd_chunk = f32x8::from(chunk);
+        sum + simd_chunk * simd_chunk
+    });
+    simd_sum.reduce_add() + remainder.iter().map(|sample| sample * sample).sum::<f32>()
+}
+
+#[derive(Clone)]
+struct ChannelMeter {
+    /// Smoothed over `RMS_WINDOW_SECS`
+    mean_square: f32,
+    peak_hold_dbfs: f32,
+    held_for_secs: f32,
+}
+
+impl ChannelMeter {
+    fn new() -> Self {
+        Self {
+            mean_square: 0.,
+            peak_hold_dbfs: f32::NEG_INFINITY,
+            held_for_secs: 0.,
+        }
+    }
+
+    fn rms(&self) -> Db {
+        Db(amplitude_to_dbfs(self.mean_square.sqrt()).unwrap_or(f32::NEG_INFINITY))
+    }
+
+    fn peak(&self) -> Db {
+        Db(self.peak_hold_dbfs)
+    }
+
+    fn update(&mut self, samples: &[f32], secs: f32) {
+        let smoothing = (-secs / RMS_WINDOW_SECS).exp();
+        let mean_square = sum_of_squares(samples) / samples.len() as f32;
+        self.mean_square = smoothing * self.mean_square + (1. - smoothing) * mean_square;
+
+        let peak = samples.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+        let peak_dbfs = amplitude_to_dbfs(peak).unwrap_or(f32::NEG_INFINITY);
+        if peak_dbfs >= self.peak_hold_dbfs {
+            self.peak_hold_dbfs = peak_dbfs;
+            self.held_for_secs = 0.;
+        } else {
+            self.held_for_secs += secs;
+            if self.held_for_secs > PEAK_HOLD_SECS {
+                self.peak_hold_dbfs = (self.peak_hold_dbfs - PEAK_DECAY_DB_PER_SEC * secs).max(peak_dbfs);
+            }
+        }
+    }
+}
+
+/// RMS and held peak for each channel, kept up to date with every chunk whether or not it's drawn.
+pub struct Meter {
+    channels: Vec<ChannelMeter>,
+    sample_rate: u32,
+    samples: Vec<f32>,
+}
+
+impl Meter {
+    pub fn new(channels: usize, sample_rate: u32) -> Self {
+        Self {
+            channels: vec![ChannelMeter::new(); channels.max(1)],
+            sample_rate,
+            samples: Vec::new(),
+        }
     }
 
-    fn into_opt_norm_ratio(self) -> Option<NormRatio> {
-        self.into()
+    /// Takes an interleaved chunk of any length.
+    pub fn update(&mut self, chunk: &[f32]) {
+        let num_channels = self.channels.len();
+        for (channel, meter) in self.channels.iter_mut().enumerate() {
+            self.samples.clear();
+            self.samples.extend(chunk.iter().skip(channel).step_by(num_channels));
+            if self.samples.is_empty() {
+                continue;
+            }
+            meter.update(&self.samples, self.samples.len() as f32 / self.sample_rate as f32);
+        }
+    }
+
+    /// One line, `width` characters wide: a bar per channel, each followed by its RMS and held peak in dBFS.
+    pub fn render(&self, width: u16, colour: bool, clipping: bool) -> String {
+        let clip = if clipping { " CLIP" } else { "     " };
+        let num_channels = self.channels.len();
+        let per_channel = (width as usize).saturating_sub(clip.len()) / num_channels;
+        let mut line = String::new();
+        for (channel, meter) in self.channels.iter().enumerate() {
+            let label = channel_label(channel, num_channels);
+            let (rms, peak) = (meter.rms(), meter.peak());
+            // label, space, space, rms, space, peak, space
+            let fixed = label.len() + 1 + 1 + 6 + 1 + 6 + 1;
+            let bar_length = per_channel.saturating_sub(fixed).max(4) as u16;
+            line.push_str(&format!(
+                "{} {} {} {} ",
+                label,
+                sound_bar(&NormRatio::on_meter(&rms), &NormRatio::on_meter(&peak), bar_length, colour),
+                rms,
+                peak
+            ));
+        }
+        line.push_str(&paint(clip, Color::Red, colour));
+        line
     }
 }
 
-impl Into<NormRatio> for Db {
-    fn into(self) -> NormRatio {
-        NormRatio::new_clamped(10f32.powf(self.0 / 10. ) )
+/// `L`/`R` for stereo, numbers otherwise.
+fn channel_label(channel: usize, num_channels: usize) -> String {
+    match (num_channels, channel) {
+        (2, 0) => "L".to_owned(),
+        (2, _) => "R".to_owned(),
+        _ => (channel + 1).to_string(),
     }
 }
 
-fn avg8x(input: Vec<f32>) -> Vec<f32> {
-    input.chunks_exact(8).map(|chunk| {
-        let simd_chunk = f32x8::from(chunk);
-        simd_chunk.reduce_add() / (chunk.len() as f32)
-    }).collect()
+fn zone_colour(position: f32) -> Color {
+    let db = METER_FLOOR_DBFS * (1. - position);
+    if db >= RED_FROM_DBFS {
+        Color::Red
+    } else if db >= YELLOW_FROM_DBFS {
+        Color::Yellow
+    } else {
+        Color::Green
+    }
 }
 
-fn get_average_volume(samples: &Vec<f32>) -> Db {
-    // FIRST STEP: CALCULATE RMS
-    // We need chunks of 8 so that the SIMD magic will work...
-    let chunks = samples.chunks(8);
-    let avg_energies: Vec<f32> = chunks.map(|chunk| {
-        // convert into a SIMD type
-        let simd_chunk = f32x8::from(chunk);
-        // Each sample gets squared
-        let square_chunk = simd_chunk.powf(2.);
-        // Average all values
-        let avg_energy = square_chunk.reduce_add() / (chunk.len() as f32);
-        avg_energy
-    }).collect();
-    let avg_energy_avged = avg8x(avg_energies.into());
-    // Average all the averages into the avergeist average
-    let avg_energy  = f32::sum(avg_energy_avged.iter()) / (avg_energy_avged.len() as f32);
-    // Sqrt for RMS
-    let rms = f32::sqrt(avg_energy);
-
-    Db(20.0 * f32::log10(rms ))
+fn paint(s: &str, colour: Color, use_colour: bool) -> String {
+    if use_colour && !s.trim().is_empty() {
+        s.with(colour).to_string()
+    } else {
+        s.to_owned()
+    }
 }
 
-pub fn sound_bar(p: &NormRatio, bar_length: u16) -> String {
-    let num_char: f32 = bar_length as f32 - 2.;
-    let num_stars: usize = ((1000. * p.get() * num_char) % num_char) as usize;
-    let num_char_usize = num_char as usize;
-    format!("[{}{}]", "*".repeat(num_stars).to_string(),
-            " ".repeat(num_char_usize.saturating_sub(num_stars).saturating_sub(1)).to_string())
+/// `bar_length` characters: the bar filled up to `level`, a `|` at `peak`, coloured by zone if `colour`.
+pub fn sound_bar(level: &NormRatio, peak: &NormRatio, bar_length: u16, colour: bool) -> String {
+    let num_char = bar_length.saturating_sub(2) as usize;
+    let num_stars = (level.get() * num_char as f32).round() as usize;
+    // Nothing to mark if the peak is off the bottom of the scale, or there's no room for it
+    let peak_at = (peak.get() > 0. && num_char > 0).then(|| ((peak.get() * num_char as f32).ceil() as usize).clamp(1, num_char) - 1);
+    let mut bar = String::from("[");
+    let mut run = String::new();
+    let mut run_colour = None;
+    for i in 0..num_char {
+        let cell_colour = zone_colour((i as f32 + 0.5) / num_char as f32);
+        if run_colour != Some(cell_colour) {
+            if let Some(run_colour) = run_colour {
+                bar.push_str(&paint(&run, run_colour, colour));
+            }
+            run.clear();
+            run_colour = Some(cell_colour);
+        }
+        run.push(if peak_at == Some(i) {
+            '|'
+        } else if i < num_stars {
+            '*'
+        } else {
+            ' '
+        });
+    }
+    if let Some(run_colour) = run_colour {
+        bar.push_str(&paint(&run, run_colour, colour));
+    }
+    bar.push(']');
+    bar
 }
 
 #[derive(Clone)]
 pub struct VolumeStreamBuilder {
     pub(crate) time_of_start: Instant,
     pub(crate) dur_of_display: Option<Duration>,
-    pub(crate) every_n: u128
+    pub(crate) every_n: u128,
+    pub(crate) channels: usize,
+    pub(crate) sample_rate: u32,
+    /// Where to look for input clipping, to show `CLIP` next to the bars
+    pub(crate) clip_track: Option<Arc<Mutex<ClipTrack>>>,
 }
 
 
@@ -116,14 +266,19 @@ impl VolumeStreamBuilder {
         Self {
             time_of_start: Instant::now(),
             dur_of_display: None,
-            every_n: 0
+            every_n: 0,
+            channels: 1,
+            sample_rate: 44_100,
+            clip_track: None,
         }
     }
 
     pub async fn getstream_display_volume<S: Stream<Item = Chunk> + Unpin>(&self, mut mic_audio_stream: S, state: Arc<ProgramState>) -> impl Stream<Item = Chunk> {
         let builder = self.clone();
+        let colour = std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none();
         fn_stream(|emitter| async move {
             let mut chunk_num: u128 = u128::default();
+            let mut meter = Meter::new(builder.channels, builder.sample_rate);
            // for await chunk in mic_audio_stream {
             while let Some(chunk) = mic_audio_stream.next().await {
                 if *state.display.read().await {
@@ -133,12 +288,13 @@ impl VolumeStreamBuilder {
                         info!("Display of microphone stream is disabled.");
                     }
 
-                    if builder.every_n == 0 || (chunk_num % builder.every_n == 0)  {
-                        let db: Db = get_average_volume(&chunk);
-                        let db_string = db.to_string();
-                        let p: NormRatio = db.into();
-                        printrn!("{} {}", sound_bar(&p,
-            state.term_size.read().await.x - db_string.len() as u16 - 1), db_string);
+                    // Keep the peak hold and RMS window going between the lines that get drawn
+                    meter.update(&chunk);
+                    if builder.every_n == 0 || chunk_num.is_multiple_of(builder.every_n)  {
+                        let clipping = builder.clip_track.as_ref().is_some_and(|track| {
+                            track.lock().unwrap_or_else(|e| e.into_inner()).recently_clipped()
+                        });
+                        printrn!("{}", meter.render(state.term_size.read().await.x, colour, clipping));
                     }
                 }
 
@@ -147,4 +303,73 @@ impl VolumeStreamBuilder {
             }
         })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratio(value: f32) -> NormRatio {
+        NormRatio::new(value).unwrap()
+    }
+
+    #[test]
+    fn bar_fills_up_to_the_level_with_the_peak_marked() {
+        assert_eq!(sound_bar(&ratio(0.5), &ratio(0.75), 12, false), "[*****  |  ]");
+        assert_eq!(sound_bar(&ratio(1.), &ratio(1.), 12, false), "[*********|]");
+        assert_eq!(sound_bar(&ratio(0.25), &ratio(0.25), 6, false), "[|   ]");
+    }
+
+    #[test]
+    fn no_peak_marker_off_the_bottom_of_the_scale() {
+        assert_eq!(sound_bar(&ratio(0.), &ratio(0.), 12, false), "[          ]");
+        // Anything above it still shows, in the first cell
+        assert_eq!(sound_bar(&ratio(0.), &ratio(0.01), 12, false), "[|         ]");
+    }
+
+    #[test]
+    fn bar_is_always_as_long_as_asked() {
+        for length in 2..40 {
+            for level in [0., 0.3, 0.5, 0.99, 1.] {
+                let bar = sound_bar(&ratio(level), &ratio(level), length, false);
+                assert_eq!(bar.chars().count(), length as usize, "{:?}", bar);
+            }
+        }
+        assert_eq!(sound_bar(&ratio(1.), &ratio(1.), 0, false), "[]");
+    }
+
+    #[test]
+    fn colour_only_adds_escapes() {
+        let plain = sound_bar(&ratio(0.9), &ratio(1.), 30, false);
+        let coloured = sound_bar(&ratio(0.9), &ratio(1.), 30, true);
+        assert_ne!(plain, coloured);
+        let stripped: String = coloured
+            .split('\x1b')
+            .enumerate()
+            .map(|(i, part)| if i == 0 { part } else { &part[part.find('m').unwrap() + 1..] })
+            .collect();
+        assert_eq!(stripped, plain);
+    }
+
+    #[test]
+    fn meter_scale_runs_from_the_floor_to_full_scale() {
+        assert_eq!(NormRatio::on_meter(&Db(0.)).get(), 1.);
+        assert_eq!(NormRatio::on_meter(&Db(METER_FLOOR_DBFS / 2.)).get(), 0.5);
+        assert_eq!(NormRatio::on_meter(&Db(-100.)).get(), 0.);
+        assert_eq!(NormRatio::on_meter(&Db(f32::NEG_INFINITY)).get(), 0.);
+        assert!(NormRatio::new(1.5).is_none());
+        assert!(NormRatio::new(-0.1).is_none());
+    }
+
+    #[test]
+    fn stereo_line_fits_the_terminal() {
+        let mut meter = Meter::new(2, 1_000);
+        meter.update(&[[0.5, 0.]; 2_000].concat());
+        let line = meter.render(80, false, true);
+        // Whatever's left over from sharing the width out between the channels goes unused
+        assert!((79..=80).contains(&line.chars().count()), "{}", line);
+        assert!(line.starts_with("L ["), "{}", line);
+        assert!(line.contains("  -6.0 R ["), "{}", line);
+        assert!(line.ends_with(" CLIP"), "{}", line);
+    }
+}
@@ -0,0 +1,67 @@
+/// Converts a float sample into a signed integer of `bits` bits, clipping anything out of range.
+pub fn quantize(sample: f32, bits: u8) -> i32 {
+    let scale = (1i64 << (bits - 1)) as f32;
+    (sample * scale).round().clamp(-scale, scale - 1.) as i32
+}
+
+/// Triangular (TPDF) dither for float -> integer conversion, so that quantization error
+/// becomes a flat noise floor instead of distortion that follows the signal.
+pub struct TpdfDither {
+    rng: fastrand::Rng,
+}
+
+impl TpdfDither {
+    pub fn new() -> Self {
+        Self {
+            rng: fastrand::Rng::new(),
+        }
+    }
+
+    /// Like `quantize`, with +/-1 LSB of triangular noise added first.
+    pub fn quantize(&mut self, sample: f32, bits: u8) -> i32 {
+        let lsb = 1. / (1i64 << (bits - 1)) as f32;
+        // The difference of two uniform variables has a triangular distribution
+        let noise = (self.rng.f32() - self.rng.f32()) * lsb;
+        quantize(sample + noise, bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quantize_scales_rounds_and_clamps() {
+        assert_eq!(quantize(0.5, 16), 16_384);
+        assert_eq!(quantize(-0.5, 24), -4_194_304);
+        assert_eq!(quantize(1.5 / 32_768., 16), 2);
+        // Full scale positive is one step past the largest value, and gets clamped to it
+        assert_eq!(quantize(1., 16), i16::MAX as i32);
+        assert_eq!(quantize(-1., 16), i16::MIN as i32);
+        assert_eq!(quantize(7., 8), i8::MAX as i32);
+        assert_eq!(quantize(-7., 24), -(1 << 23));
+        assert_eq!(quantize(1., 32), i32::MAX);
+        assert_eq!(quantize(-1., 32), i32::MIN);
+    }
+
+    #[test]
+    fn dither_stays_within_a_step_and_averages_out() {
+        let mut dither = TpdfDither::new();
+        // A quarter of the way between two 16-bit steps
+        let sample = 100.25 / 32_768.;
+        let quantized: Vec<i32> = (0..100_000).map(|_| dither.quantize(sample, 16)).collect();
+        assert!(quantized.iter().all(|q| (99..=101).contains(q)));
+        // Undithered this would always be 100; dithered, the average finds the quarter step
+        let mean = quantized.iter().map(|&q| q as f64).sum::<f64>() / quantized.len() as f64;
+        assert!((mean - 100.25).abs() < 0.01, "mean {}", mean);
+    }
+
+    #[test]
+    fn dither_never_wraps_at_full_scale() {
+        let mut dither = TpdfDither::new();
+        for _ in 0..10_000 {
+            assert!(dither.quantize(1., 16) > 0);
+            assert!(dither.quantize(-1., 16) < 0);
+        }
+    }
+}
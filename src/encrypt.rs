@@ -0,0 +1,428 @@
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use age::stream::StreamWriter;
+use age::x25519;
+
+use crate::segment_file;
+
+/// Added after a segment's own extension, e.g. `….ogg.age`.
+pub const ENCRYPTED_EXTENSION: &str = "age";
+// First line of every (unarmored) age file
+const AGE_MAGIC: &[u8] = b"age-encryption.org/v1";
+// Plaintext is encrypted in chunks of this size; a crash loses whatever hadn't filled one yet
+const AGE_CHUNK_LEN: usize = 64 * 1024;
+
+/// How long a stream of `bits_per_sec` takes to fill one age chunk, which is how much of it can be lost
+/// in a crash however often the encoder flushes.
+pub fn chunk_secs(bits_per_sec: u32) -> f64 {
+    (AGE_CHUNK_LEN * 8) as f64 / bits_per_sec.max(1) as f64
+}
+
+/// Parses an age recipient (public key) for `--encrypt-to`.
+pub fn parse_recipient(s: &str) -> Result<x25519::Recipient, String> {
+    s.trim()
+        .parse()
+        .map_err(|e| format!("`{}` is not an age recipient: {}", s, e))
+}
+
+/// `recipients`, plus every recipient listed in each of `files`, one per line as age's `-R` reads them.
+pub fn load_recipients(
+    recipients: &[x25519::Recipient],
+    files: &[PathBuf],
+) -> Result<Vec<x25519::Recipient>, Box<dyn Error>> {
+    let mut all = recipients.to_vec();
+    for path in files {
+        let f = BufReader::new(File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?);
+        for line in f.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            all.push(parse_recipient(line).map_err(|e| format!("{}: {}", path.display(), e))?);
+        }
+    }
+    Ok(all)
+}
+
+/// Keys to decrypt segments with, as read from age identity files. Empty if none were given.
+#[derive(Default)]
+pub struct Identities(Vec<Box<dyn age::Identity>>);
+
+impl Identities {
+    /// Reads every identity in each of `files`, e.g. as written by `age-keygen`.
+    pub fn load(files: &[PathBuf]) -> Result<Self, Box<dyn Error>> {
+        let mut identities = Vec::new();
+        for path in files {
+            let file = age::IdentityFile::from_file(path.to_string_lossy().into_owned())
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            identities.extend(file.into_identities()?);
+        }
+        Ok(Self(identities))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Whether the file at `path` is age-encrypted, going by its contents rather than its name.
+pub fn is_encrypted(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; AGE_MAGIC.len()];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == AGE_MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// `x.ogg.age` -> `x.ogg`; anything else is left alone.
+pub fn plain_path(path: &Path) -> PathBuf {
+    if path.extension() == Some(OsStr::new(ENCRYPTED_EXTENSION)) {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// `x.ogg` -> `x.ogg.age`
+pub fn encrypted_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(ENCRYPTED_EXTENSION);
+    PathBuf::from(name)
+}
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+fn decryptor(
+    path: &Path,
+    identities: &Identities,
+) -> Result<age::stream::StreamReader<BufReader<File>>, Box<dyn Error>> {
+    if identities.is_empty() {
+        return Err(format!("{} is encrypted; give an --identity to read it", path.display()).into());
+    }
+    let decryptor = age::Decryptor::new_buffered(BufReader::new(File::open(path)?))?;
+    decryptor
+        .decrypt(identities.0.iter().map(|identity| identity.as_ref()))
+        .map_err(|e| format!("Can't decrypt {}: {}", path.display(), e).into())
+}
+
+/// Opens a segment for reading, decrypting it on the fly if it's encrypted.
+pub fn open(path: &Path, identities: &Identities) -> Result<Box<dyn ReadSeek>, Box<dyn Error>> {
+    if is_encrypted(path)? {
+        Ok(Box::new(decryptor(path, identities)?))
+    } else {
+        Ok(Box::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+/// How much of an encrypted file `decrypt_file` got back.
+pub struct Decrypted {
+    pub bytes: u64,
+    /// Why the rest couldn't be decrypted, if it stopped short
+    pub cut_off: Option<String>,
+}
+
+/// Decrypts `path` into `output`. Everything that authenticates is written out even if the end
+/// of the file doesn't, as when a crash cut a `.partial` file short; `cut_off` says so.
+pub fn decrypt_file(
+    path: &Path,
+    identities: &Identities,
+    output: &mut impl Write,
+) -> Result<Decrypted, Box<dyn Error>> {
+    let mut reader = decryptor(path, identities)?;
+    let mut buf = vec![0; AGE_CHUNK_LEN];
+    let mut bytes = 0;
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => return Ok(Decrypted { bytes, cut_off: None }),
+            Ok(len) => {
+                output.write_all(&buf[..len])?;
+                bytes += len as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Ok(Decrypted { bytes, cut_off: Some(e.to_string()) }),
+        }
+    }
+}
+
+/// Where a `SegmentWriter` writes a segment to: the file itself, or an age stream encrypting into it.
+pub enum SegmentSink {
+    Plain(File),
+    Encrypted {
+        stream: StreamWriter<File>,
+        // The same file, for syncing; the age stream doesn't hand its writer back until it's finished
+        file: File,
+        position: u64,
+    },
+}
+
+impl SegmentSink {
+    /// Creates the segment file at `path`, which must not exist yet. Encrypted if there are any `recipients`.
+    pub fn create(path: &Path, recipients: &[x25519::Recipient]) -> Result<Self, Box<dyn Error>> {
+        let file = segment_file::create_new(path)?;
+        if recipients.is_empty() {
+            return Ok(SegmentSink::Plain(file));
+        }
+        let encryptor = age::Encryptor::with_recipients(
+            recipients.iter().map(|recipient| recipient as &dyn age::Recipient),
+        )?;
+        Ok(SegmentSink::Encrypted {
+            stream: encryptor.wrap_output(file.try_clone()?)?,
+            file,
+            position: 0,
+        })
+    }
+
+    /// Encrypted segments are a stream; their headers can't be patched up after the fact.
+    pub fn is_seekable(&self) -> bool {
+        matches!(self, SegmentSink::Plain(_))
+    }
+
+    /// Syncs what's reached the file to disk. An encrypted segment holds back its last partial chunk.
+    pub fn sync_data(&self) -> io::Result<()> {
+        match self {
+            SegmentSink::Plain(file) => file.sync_data(),
+            SegmentSink::Encrypted { file, .. } => file.sync_data(),
+        }
+    }
+
+    /// Writes out anything still held back, and hands back the file.
+    pub fn finish(self) -> io::Result<File> {
+        match self {
+            SegmentSink::Plain(file) => Ok(file),
+            SegmentSink::Encrypted { stream, .. } => stream.finish(),
+        }
+    }
+}
+
+impl Write for SegmentSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SegmentSink::Plain(file) => file.write(buf),
+            SegmentSink::Encrypted { stream, position, .. } => {
+                let written = stream.write(buf)?;
+                *position += written as u64;
+                Ok(written)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SegmentSink::Plain(file) => file.flush(),
+            SegmentSink::Encrypted { stream, .. } => stream.flush(),
+        }
+    }
+}
+
+impl Seek for SegmentSink {
+    /// Encrypted segments only "seek" to where they already are.
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            SegmentSink::Plain(file) => file.seek(pos),
+            SegmentSink::Encrypted { position, .. } => match pos {
+                SeekFrom::Start(to) if to == *position => Ok(to),
+                SeekFrom::End(0) | SeekFrom::Current(0) => Ok(*position),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "Can't seek within an encrypted segment",
+                )),
+            },
+        }
+    }
+}
+
+/// Removes the encryption from `path` (`x.ogg.age`, or a crashed `x.ogg.age.partial`) into `target`,
+/// going through a `.partial` file like a segment. Returns where it ended up, and how much was recovered.
+pub fn decrypt_to(
+    path: &Path,
+    target: &Path,
+    identities: &Identities,
+) -> Result<(PathBuf, Decrypted), Box<dyn Error>> {
+    if target.exists() {
+        return Err(format!("{} already exists", target.display()).into());
+    }
+    let partial = segment_file::partial_path(target);
+    let mut f = segment_file::create_new(&partial)?;
+    let result = decrypt_file(path, identities, &mut f).and_then(|decrypted| {
+        f.sync_all()?;
+        Ok(decrypted)
+    });
+    let decrypted = match result {
+        // Not even one chunk's worth made it to disk
+        Ok(decrypted) if decrypted.bytes == 0 && decrypted.cut_off.is_some() => {
+            let _ = fs::remove_file(&partial);
+            return Err(format!(
+                "Nothing could be recovered: {}",
+                decrypted.cut_off.unwrap_or_default()
+            )
+            .into());
+        }
+        Ok(decrypted) => decrypted,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    Ok((segment_file::publish(&partial, target)?, decrypted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use crate::wav::{SampleFormat, WavReader, WavSpec, WavWriter};
+
+    fn keys() -> (x25519::Recipient, Identities) {
+        let identity = x25519::Identity::generate();
+        (identity.to_public(), Identities(vec![Box::new(identity)]))
+    }
+
+    /// Bytes that don't repeat within a chunk, so a chunk in the wrong place would show.
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    /// Writes `plain` to an encrypted segment at `path`, leaving it as a crash would: the last,
+    /// partial chunk never gets out.
+    fn write_and_crash(path: &Path, recipient: &x25519::Recipient, plain: &[u8]) {
+        let mut sink = SegmentSink::create(path, std::slice::from_ref(recipient)).unwrap();
+        sink.write_all(plain).unwrap();
+        sink.flush().unwrap();
+        sink.sync_data().unwrap();
+        // No `finish`, as if the process had died here
+        std::mem::forget(sink);
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = TestDir::new("encrypt");
+        let (recipient, identities) = keys();
+        let path = dir.join("x.ogg.age");
+        let plain = data(3 * AGE_CHUNK_LEN + 1234);
+        let mut sink = SegmentSink::create(&path, &[recipient]).unwrap();
+        assert!(!sink.is_seekable());
+        sink.write_all(&plain).unwrap();
+        sink.finish().unwrap();
+        assert!(is_encrypted(&path).unwrap());
+        assert!(!fs::read(&path).unwrap().windows(64).any(|window| window == &plain[..64]));
+
+        let (out, decrypted) = decrypt_to(&path, &plain_path(&path), &identities).unwrap();
+        assert_eq!(out, dir.join("x.ogg"));
+        assert_eq!(decrypted.bytes, plain.len() as u64);
+        assert!(decrypted.cut_off.is_none());
+        assert_eq!(fs::read(&out).unwrap(), plain);
+        assert!(!segment_file::partial_path(&out).exists());
+    }
+
+    #[test]
+    fn needs_the_right_identity() {
+        let dir = TestDir::new("encrypt");
+        let (recipient, _) = keys();
+        let (_, other) = keys();
+        let path = dir.join("x.ogg.age");
+        let mut sink = SegmentSink::create(&path, &[recipient]).unwrap();
+        sink.write_all(b"secret").unwrap();
+        sink.finish().unwrap();
+        assert!(decrypt_to(&path, &dir.join("x.ogg"), &Identities::default()).is_err());
+        assert!(decrypt_to(&path, &dir.join("x.ogg"), &other).is_err());
+        assert!(!dir.join("x.ogg").exists());
+        assert!(!dir.join("x.ogg.partial").exists());
+    }
+
+    #[test]
+    fn recovers_the_whole_chunks_of_a_crashed_segment() {
+        let dir = TestDir::new("encrypt");
+        let (recipient, identities) = keys();
+        let path = dir.join("x.ogg.age.partial");
+        let plain = data(3 * AGE_CHUNK_LEN + AGE_CHUNK_LEN / 2);
+        write_and_crash(&path, &recipient, &plain);
+
+        let (out, decrypted) = decrypt_to(&path, &dir.join("x.ogg"), &identities).unwrap();
+        assert!(decrypted.cut_off.is_some());
+        // Whole chunks only, and every one of them that authenticates
+        assert_eq!(decrypted.bytes % AGE_CHUNK_LEN as u64, 0);
+        assert!(decrypted.bytes >= 2 * AGE_CHUNK_LEN as u64, "{}", decrypted.bytes);
+        assert_eq!(fs::read(&out).unwrap(), plain[..decrypted.bytes as usize]);
+    }
+
+    #[test]
+    fn a_crash_before_the_first_chunk_recovers_nothing() {
+        let dir = TestDir::new("encrypt");
+        let (recipient, identities) = keys();
+        let path = dir.join("x.ogg.age.partial");
+        write_and_crash(&path, &recipient, &data(1000));
+        let err = decrypt_to(&path, &dir.join("x.ogg"), &identities).err().unwrap();
+        assert!(err.to_string().starts_with("Nothing could be recovered"), "{}", err);
+        assert!(!dir.join("x.ogg.partial").exists());
+    }
+
+    #[test]
+    fn encrypted_wav_reads_back_without_its_sizes() {
+        let dir = TestDir::new("encrypt");
+        let (recipient, identities) = keys();
+        let spec = WavSpec {
+            channels: 2,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let path = dir.join("x.wav.age");
+        let sink = SegmentSink::create(&path, &[recipient]).unwrap();
+        let mut writer = WavWriter::new_streaming(sink, spec, &[]).unwrap();
+        for i in 0..1000 {
+            writer.write_sample_int(i).unwrap();
+            writer.write_sample_int(-i).unwrap();
+        }
+        writer.finalize().unwrap().finish().unwrap();
+
+        let mut plain = Vec::new();
+        open(&path, &identities).unwrap().read_to_end(&mut plain).unwrap();
+        assert_eq!(&plain[..4], b"RIFF");
+        assert_eq!(&plain[4..8], &u32::MAX.to_le_bytes());
+
+        let mut reader = WavReader::new(open(&path, &identities).unwrap()).unwrap();
+        assert_eq!(reader.frames(), 1000);
+        let samples = reader.read_frames(1000).unwrap();
+        assert_eq!(samples[1998], 999. / 32768.);
+        assert_eq!(samples[1999], -999. / 32768.);
+    }
+
+    #[test]
+    fn crashed_encrypted_wav_is_playable_up_to_the_last_chunk() {
+        let dir = TestDir::new("encrypt");
+        let (recipient, identities) = keys();
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: 8_000,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        let path = dir.join("x.wav.age.partial");
+        let sink = SegmentSink::create(&path, &[recipient]).unwrap();
+        let mut writer = WavWriter::new_streaming(sink, spec, &[]).unwrap();
+        for i in 0..2 * AGE_CHUNK_LEN as i32 {
+            writer.write_sample_int(i % 1000).unwrap();
+        }
+        writer.flush().unwrap();
+        std::mem::forget(writer);
+
+        let (out, decrypted) = decrypt_to(&path, &dir.join("x.wav"), &identities).unwrap();
+        assert!(decrypted.cut_off.is_some());
+        let mut reader = WavReader::new(File::open(&out).unwrap()).unwrap();
+        let header_len = decrypted.bytes - reader.frames() * 2;
+        assert!(header_len < 100, "{}", header_len);
+        assert!(reader.frames() > 0);
+        let samples = reader.read_frames(reader.frames() as usize).unwrap();
+        assert_eq!(samples[999], 999. / 32768.);
+        assert_eq!(samples[1000], 0.);
+    }
+}
@@ -0,0 +1,402 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::ValueEnum;
+use log::info;
+
+use crate::archive::{self, GAP_TOLERANCE_SECS};
+use crate::catalog::CatalogEntry;
+use crate::decode;
+use crate::encrypt::{Identities, SegmentSink};
+use crate::segment_file;
+use crate::segment_meta::{self, SegmentMeta};
+use crate::write_audio::SegmentWriter;
+use crate::FormatSelect;
+
+const SILENCE_CHUNK_FRAMES: u64 = 4096;
+
+/// What to put in the export where nothing was recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GapFill {
+    /// Silence of the same length, so times in the export line up with the wall clock
+    Silence,
+    /// Nothing; the audio either side is butted together, and the gaps are only listed in the tags
+    Skip,
+}
+
+/// Where an export ended up, and what went into it.
+pub struct ExportSummary {
+    pub path: PathBuf,
+    pub start: DateTime<FixedOffset>,
+    pub end: DateTime<FixedOffset>,
+    pub sources: usize,
+    pub gaps: Vec<archive::Gap>,
+    pub frames: u64,
+    pub sample_rate: u32,
+}
+
+/// Preferred source formats, when the same stretch was recorded in several: lossless first.
+const SOURCE_PREFERENCE: [FormatSelect; 4] =
+    [FormatSelect::Wav, FormatSelect::Flac, FormatSelect::Ogg, FormatSelect::Opus];
+
+fn source_rank(entry: &CatalogEntry) -> usize {
+    SOURCE_PREFERENCE
+        .iter()
+        .position(|format| format.extension() == entry.format)
+        .unwrap_or(SOURCE_PREFERENCE.len())
+}
+
+/// The segments to stitch together for `from..to`: one per recorded stretch, in the chosen format if any,
+/// otherwise in the best one available.
+pub fn select_sources(
+    entries: &[CatalogEntry],
+    from: DateTime<FixedOffset>,
+    to: DateTime<FixedOffset>,
+    source_format: Option<FormatSelect>,
+) -> Vec<CatalogEntry> {
+    let mut candidates: Vec<_> = entries
+        .iter()
+        .filter(|entry| source_format.is_none_or(|format| format.extension() == entry.format))
+        .filter(|entry| match (entry.start_time(), entry.end_time()) {
+            (Some(start), Some(end)) => start < to && end > from,
+            _ => false,
+        })
+        .cloned()
+        .collect();
+    candidates.sort_by_key(|entry| (entry.start_time(), source_rank(entry)));
+    // Copies of one segment in other formats share its start time
+    candidates.dedup_by_key(|entry| entry.start_time());
+    candidates
+}
+
+fn frames_between(from: DateTime<FixedOffset>, to: DateTime<FixedOffset>, sample_rate: u32) -> i64 {
+    let micros = (to - from).num_microseconds().unwrap_or(i64::MAX);
+    (micros as f64 * sample_rate as f64 / 1e6).round() as i64
+}
+
+fn write_silence(
+    writer: &mut dyn SegmentWriter,
+    frames: u64,
+    channels: u16,
+) -> Result<(), Box<dyn Error>> {
+    let mut left = frames;
+    while left > 0 {
+        let len = left.min(SILENCE_CHUNK_FRAMES);
+        writer.write_chunk(&vec![0.; (len * channels as u64) as usize])?;
+        left -= len;
+    }
+    Ok(())
+}
+
+/// Decodes `sources` (from `select_sources`), decrypting them with `identities` where need be,
+/// and writes `from..to` of them into one file at `output`, trimmed to the sample.
+/// The range is narrowed to what was actually recorded.
+#[allow(clippy::too_many_arguments)]
+pub fn export(
+    dir: &Path,
+    sources: &[CatalogEntry],
+    from: DateTime<FixedOffset>,
+    to: DateTime<FixedOffset>,
+    gap_fill: GapFill,
+    mut writer: Box<dyn SegmentWriter>,
+    output: &Path,
+    user_tags: &[(String, String)],
+    identities: &Identities,
+) -> Result<ExportSummary, Box<dyn Error>> {
+    let (Some(first), Some(last_end)) = (
+        sources.first(),
+        sources.iter().filter_map(|entry| entry.end_time()).max(),
+    ) else {
+        return Err(format!(
+            "Nothing was recorded between {} and {}",
+            archive::format_time(&from),
+            archive::format_time(&to)
+        )
+        .into());
+    };
+    let start = first.start_time().map_or(from, |first_start| from.max(first_start));
+    let end = to.min(last_end);
+    let gaps = archive::find_gaps(sources);
+
+    let first_reader = decode::open(&dir.join(&first.path), identities)?;
+    let (sample_rate, channels) = (first_reader.sample_rate(), first_reader.channels());
+    drop(first_reader);
+    let total_frames = frames_between(start, end, sample_rate).max(0) as u64;
+
+    let mut devices: Vec<&str> = sources.iter().filter_map(|entry| entry.device.as_deref()).collect();
+    devices.sort();
+    devices.dedup();
+    let mut tags = vec![
+        ("AKASHA_EXPORT_FROM".to_owned(), segment_meta::format_time(&start.with_timezone(&Utc))),
+        ("AKASHA_EXPORT_TO".to_owned(), segment_meta::format_time(&end.with_timezone(&Utc))),
+    ];
+    tags.extend(sources.iter().map(|entry| ("AKASHA_SOURCE".to_owned(), entry.path.clone())));
+    tags.extend(gaps.iter().map(|gap| ("AKASHA_GAP".to_owned(), format!("{}/{}", gap.start, gap.end))));
+    tags.extend(user_tags.iter().cloned());
+    let meta = SegmentMeta {
+        start: start.with_timezone(&Utc),
+        device: if devices.is_empty() { "unknown".to_owned() } else { devices.join(", ") },
+        hostname: segment_meta::hostname(),
+        session_id: segment_meta::new_session_id(),
+        index: 0,
+        sample_rate,
+        channels,
+        user_tags: tags,
+    };
+    let config = cpal::StreamConfig {
+        channels,
+        sample_rate: cpal::SampleRate(sample_rate),
+        buffer_size: cpal::BufferSize::Default,
+    };
+
+    if output.exists() {
+        return Err(format!("{} already exists", output.display()).into());
+    }
+    let partial = segment_file::partial_path(output);
+    writer.open(SegmentSink::create(&partial, &[])?, &config, &meta)?;
+    let result = write_sources(
+        dir,
+        sources,
+        start,
+        gap_fill,
+        writer.as_mut(),
+        sample_rate,
+        channels,
+        total_frames,
+        identities,
+    )
+        .and_then(|frames| {
+            writer.finalize()?;
+            Ok(frames)
+        });
+    let frames = match result {
+        Ok(frames) => frames,
+        Err(e) => {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    let path = segment_file::publish(&partial, output)?;
+    Ok(ExportSummary {
+        path,
+        start,
+        end,
+        sources: sources.len(),
+        gaps,
+        frames,
+        sample_rate,
+    })
+}
+
+/// Writes the sources out along a timeline starting at `start`, in frames. Returns the frames written.
+#[allow(clippy::too_many_arguments)]
+fn write_sources(
+    dir: &Path,
+    sources: &[CatalogEntry],
+    start: DateTime<FixedOffset>,
+    gap_fill: GapFill,
+    writer: &mut dyn SegmentWriter,
+    sample_rate: u32,
+    channels: u16,
+    total_frames: u64,
+    identities: &Identities,
+) -> Result<u64, Box<dyn Error>> {
+    let frame_len = channels as usize;
+    let gap_tolerance_frames = (GAP_TOLERANCE_SECS * sample_rate as f64) as i64;
+    // Position on the timeline, and how much of it actually went into the file
+    let mut position: i64 = 0;
+    let mut written: u64 = 0;
+    for entry in sources {
+        if position as u64 >= total_frames {
+            break;
+        }
+        let Some(entry_start) = entry.start_time() else {
+            continue;
+        };
+        let path = dir.join(&entry.path);
+        let mut reader = decode::open(&path, identities)?;
+        if reader.sample_rate() != sample_rate || reader.channels() != channels {
+            return Err(format!(
+                "{} is {} Hz, {} channels, but the export started out at {} Hz, {} channels; \
+                 pick sources that match with --source-format",
+                path.display(),
+                reader.sample_rate(),
+                reader.channels(),
+                sample_rate,
+                channels
+            )
+            .into());
+        }
+        info!("Exporting from {}", path.display());
+
+        let entry_position = frames_between(start, entry_start, sample_rate);
+        let gap = entry_position - position;
+        if gap > 0 {
+            // Clock jitter between segments is always filled, to keep the timeline honest
+            if gap_fill == GapFill::Silence || gap <= gap_tolerance_frames {
+                let gap = (gap as u64).min(total_frames - position as u64);
+                write_silence(writer, gap, channels)?;
+                written += gap;
+            }
+            position = entry_position;
+        }
+        // Frames of this segment that come before the timeline's current position
+        let mut skip = (position - entry_position).max(0) as u64;
+        while (position as u64) < total_frames {
+            let Some(chunk) = reader.read_chunk()? else {
+                break;
+            };
+            let frames = (chunk.len() / frame_len) as u64;
+            if skip >= frames {
+                skip -= frames;
+                continue;
+            }
+            let take = (frames - skip).min(total_frames - position as u64);
+            let begin = skip as usize * frame_len;
+            writer.write_chunk(&chunk[begin..begin + take as usize * frame_len])?;
+            skip = 0;
+            position += take as i64;
+            written += take;
+        }
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+    use crate::test_opts::rec_opts;
+    use crate::wav::{SampleFormat, WavContainer, WavReader, WavSpec, WavWriter};
+    use crate::write_audio::WavSegmentWriter;
+
+    const SAMPLE_RATE: u32 = 1_000;
+
+    fn time(s: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(s).unwrap()
+    }
+
+    fn entry(path: &str, start: &str, duration_secs: f64, format: &str) -> CatalogEntry {
+        CatalogEntry {
+            path: path.to_owned(),
+            start: start.to_owned(),
+            duration_secs,
+            device: Some("mic".to_owned()),
+            format: format.to_owned(),
+            size: 0,
+            sha256: None,
+            sample_rate: SAMPLE_RATE,
+            channels: 1,
+        }
+    }
+
+    /// A two-second mono float WAV segment whose sample `i` is `base + i / 10000`, so every sample says where it came from.
+    fn segment(dir: &TestDir, name: &str, start: &str, base: f32) -> CatalogEntry {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        let mut writer = WavWriter::new(File::create(dir.join(name)).unwrap(), spec, WavContainer::Auto, &[]).unwrap();
+        for i in 0..2 * SAMPLE_RATE {
+            writer.write_sample_f32(base + i as f32 / 10_000.).unwrap();
+        }
+        writer.finalize().unwrap();
+        entry(name, start, 2., "wav")
+    }
+
+    /// Exports 12:00:01.25 to 12:00:06.5 from a segment at 12:00:00 and one at 12:00:05, with a 3 s gap between.
+    fn export_across_a_gap(gap_fill: GapFill) -> (Vec<f32>, Vec<u8>) {
+        let dir = TestDir::new("export");
+        let sources = vec![
+            segment(&dir, "a.wav", "2024-05-01T12:00:00.000Z", 0.),
+            segment(&dir, "b.wav", "2024-05-01T12:00:05.000Z", 0.5),
+        ];
+        let output = dir.join("out.wav");
+        let writer = Box::new(WavSegmentWriter::new(rec_opts(&[]).wav, Duration::from_secs(10)));
+        let summary = export(
+            dir.path(),
+            &sources,
+            time("2024-05-01T12:00:01.250Z"),
+            time("2024-05-01T12:00:06.500Z"),
+            gap_fill,
+            writer,
+            &output,
+            &[("NOTE".to_owned(), "test".to_owned())],
+            &Identities::default(),
+        )
+        .unwrap();
+        assert_eq!(summary.path, output);
+        assert_eq!(summary.sources, 2);
+        assert_eq!(summary.gaps.len(), 1);
+
+        let mut reader = WavReader::new(File::open(&output).unwrap()).unwrap();
+        assert_eq!(reader.frames(), summary.frames);
+        let samples = reader.read_frames(usize::MAX).unwrap();
+        (samples, std::fs::read(&output).unwrap())
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn trims_to_the_sample_and_fills_gaps_with_silence() {
+        let (samples, _) = export_across_a_gap(GapFill::Silence);
+        // 0.75 s of the first segment, the 3 s gap, then 1.5 s of the second
+        assert_eq!(samples.len(), 750 + 3000 + 1500);
+        assert!(close(samples[0], 0.125), "{}", samples[0]);
+        assert!(close(samples[749], 0.1999), "{}", samples[749]);
+        assert!(samples[750..3750].iter().all(|sample| *sample == 0.));
+        assert!(close(samples[3750], 0.5), "{}", samples[3750]);
+        assert!(close(samples[5249], 0.6499), "{}", samples[5249]);
+    }
+
+    #[test]
+    fn skipping_gaps_butts_the_audio_together() {
+        let (samples, _) = export_across_a_gap(GapFill::Skip);
+        assert_eq!(samples.len(), 750 + 1500);
+        assert!(close(samples[749], 0.1999), "{}", samples[749]);
+        assert!(close(samples[750], 0.5), "{}", samples[750]);
+        assert!(close(samples[2249], 0.6499), "{}", samples[2249]);
+    }
+
+    #[test]
+    fn tags_record_the_range_sources_and_gaps() {
+        let (_, bytes) = export_across_a_gap(GapFill::Silence);
+        let text = String::from_utf8_lossy(&bytes);
+        for tag in [
+            "AKASHA_EXPORT_FROM=2024-05-01T12:00:01.250Z",
+            "AKASHA_EXPORT_TO=2024-05-01T12:00:06.500Z",
+            "AKASHA_SOURCE=a.wav",
+            "AKASHA_SOURCE=b.wav",
+            "AKASHA_GAP=",
+            "AKASHA_DEVICE=mic",
+            "NOTE=test",
+        ] {
+            assert!(text.contains(tag), "{} missing", tag);
+        }
+    }
+
+    #[test]
+    fn picks_one_source_per_stretch_lossless_first() {
+        let entries = vec![
+            entry("a.opus", "2024-05-01T12:00:00.000Z", 60., "opus"),
+            entry("a.wav", "2024-05-01T12:00:00.000Z", 60., "wav"),
+            entry("a.flac", "2024-05-01T12:00:00.000Z", 60., "flac"),
+            entry("b.ogg", "2024-05-01T12:01:00.000Z", 60., "ogg"),
+            entry("c.wav", "2024-05-01T12:05:00.000Z", 60., "wav"),
+        ];
+        let (from, to) = (time("2024-05-01T12:00:30Z"), time("2024-05-01T12:01:30Z"));
+        let paths = |sources: Vec<CatalogEntry>| sources.into_iter().map(|entry| entry.path).collect::<Vec<_>>();
+        assert_eq!(paths(select_sources(&entries, from, to, None)), ["a.wav", "b.ogg"]);
+        assert_eq!(paths(select_sources(&entries, from, to, Some(FormatSelect::Opus))), ["a.opus"]);
+        // Touching the range's ends isn't overlapping it
+        assert!(select_sources(&entries, time("2024-05-01T12:02:00Z"), time("2024-05-01T12:05:00Z"), None).is_empty());
+    }
+}
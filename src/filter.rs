@@ -0,0 +1,247 @@
+use std::f64::consts::PI;
+
+use async_fn_stream::fn_stream;
+use futures_core::Stream;
+use futures_util::StreamExt;
+
+use crate::{Chunk, FilterOpts};
+
+/// Corner of the DC blocker: low enough to leave everything audible alone, high enough to settle in well under a second.
+const DC_BLOCKER_HZ: f64 = 5.;
+/// Butterworth: as flat a passband as a second-order filter gets.
+const HIGHPASS_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+const MAX_HIGHPASS_HZ: f32 = 1000.;
+
+/// Parses a frequency such as `80Hz`, `0.1kHz` or a bare `80`, in Hz.
+pub fn parse_frequency(s: &str) -> Result<f32, String> {
+    let lower = s.trim().to_ascii_lowercase();
+    let (number, scale) = match lower.strip_suffix("khz") {
+        Some(number) => (number, 1000.),
+        None => (lower.strip_suffix("hz").unwrap_or(&lower), 1.),
+    };
+    let hz = number
+        .trim()
+        .parse::<f32>()
+        .map(|number| number * scale)
+        .map_err(|_| format!("`{}` is not a frequency, e.g. 80Hz", s))?;
+    if !(1. ..=MAX_HIGHPASS_HZ).contains(&hz) {
+        return Err(format!("must be between 1Hz and {}Hz", MAX_HIGHPASS_HZ));
+    }
+    Ok(hz)
+}
+
+/// One channel's worth of a second-order IIR filter, in transposed direct form II.
+/// Run in f64, since at low corner frequencies the poles sit close enough to 1 for f32 to get noisy.
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    /// High-pass from the Audio EQ Cookbook.
+    fn highpass(corner_hz: f64, q: f64, sample_rate: u32) -> Self {
+        let w0 = 2. * PI * corner_hz / sample_rate as f64;
+        let alpha = w0.sin() / (2. * q);
+        let cos_w0 = w0.cos();
+        let a0 = 1. + alpha;
+        Self {
+            b0: (1. + cos_w0) / 2. / a0,
+            b1: -(1. + cos_w0) / a0,
+            b2: (1. + cos_w0) / 2. / a0,
+            a1: -2. * cos_w0 / a0,
+            a2: (1. - alpha) / a0,
+            z1: 0.,
+            z2: 0.,
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// `y[n] = x[n] - x[n-1] + r * y[n-1]`: a zero at DC and a pole just inside it.
+#[derive(Debug, Clone, Copy)]
+struct DcBlocker {
+    r: f64,
+    x1: f64,
+    y1: f64,
+}
+
+impl DcBlocker {
+    fn new(sample_rate: u32) -> Self {
+        Self {
+            r: 1. - 2. * PI * DC_BLOCKER_HZ / sample_rate as f64,
+            x1: 0.,
+            y1: 0.,
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = x - self.x1 + self.r * self.y1;
+        self.x1 = x;
+        self.y1 = y;
+        y
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ChannelFilters {
+    dc_blocker: Option<DcBlocker>,
+    highpass: Option<Biquad>,
+}
+
+/// DC offset removal and a high-pass for rumble, each channel filtered on its own.
+/// Lives as long as the input stream, so there's no click or settling at segment boundaries.
+pub struct Filters {
+    channels: Vec<ChannelFilters>,
+}
+
+impl Filters {
+    /// `None` if `opts` don't ask for any filtering.
+    pub fn from_opts(opts: &FilterOpts, channels: usize, sample_rate: u32) -> Option<Self> {
+        if opts.highpass.is_none() && !opts.dc_block {
+            return None;
+        }
+        // A corner at or past Nyquist doesn't make a filter
+        let highpass = opts
+            .highpass
+            .map(|hz| Biquad::highpass((hz as f64).min(sample_rate as f64 * 0.45), HIGHPASS_Q, sample_rate));
+        let filters = ChannelFilters {
+            dc_blocker: opts.dc_block.then(|| DcBlocker::new(sample_rate)),
+            highpass,
+        };
+        Some(Self {
+            channels: vec![filters; channels],
+        })
+    }
+
+    /// Filters an interleaved chunk in place.
+    pub fn process(&mut self, chunk: &mut [f32]) {
+        for frame in chunk.chunks_exact_mut(self.channels.len()) {
+            for (sample, filters) in frame.iter_mut().zip(self.channels.iter_mut()) {
+                let mut x = *sample as f64;
+                if let Some(dc_blocker) = &mut filters.dc_blocker {
+                    x = dc_blocker.process(x);
+                }
+                if let Some(highpass) = &mut filters.highpass {
+                    x = highpass.process(x);
+                }
+                *sample = x as f32;
+            }
+        }
+    }
+}
+
+/// Runs every chunk through `filters`.
+pub fn getstream_filter<S: Stream<Item = Chunk> + Unpin>(
+    mut audio_stream: S,
+    mut filters: Filters,
+) -> impl Stream<Item = Chunk> {
+    fn_stream(|emitter| async move {
+        while let Some(mut chunk) = audio_stream.next().await {
+            filters.process(&mut chunk);
+            emitter.emit(chunk).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_opts::rec_opts;
+
+    const SAMPLE_RATE: u32 = 16_000;
+
+    fn opts(args: &[&str]) -> FilterOpts {
+        rec_opts(args).filter
+    }
+
+    /// Two seconds of a mono sine at `hz` plus `offset`.
+    fn sine(hz: f32, offset: f32) -> Vec<f32> {
+        (0..2 * SAMPLE_RATE)
+            .map(|i| offset + 0.5 * (2. * std::f32::consts::PI * hz * i as f32 / SAMPLE_RATE as f32).sin())
+            .collect()
+    }
+
+    /// Level of the last half second relative to the input sine's, in dB, once the filter has settled.
+    fn gain_db(filtered: &[f32]) -> f32 {
+        let settled = &filtered[filtered.len() - SAMPLE_RATE as usize / 2..];
+        let rms = (settled.iter().map(|sample| sample * sample).sum::<f32>() / settled.len() as f32).sqrt();
+        20. * (rms / (0.5 / 2f32.sqrt())).log10()
+    }
+
+    fn filtered(args: &[&str], mut chunk: Vec<f32>) -> Vec<f32> {
+        let mut filters = Filters::from_opts(&opts(args), 1, SAMPLE_RATE).unwrap();
+        // In a few chunks, as the stream would hand them over
+        for part in chunk.chunks_mut(1000) {
+            filters.process(part);
+        }
+        chunk
+    }
+
+    #[test]
+    fn parses_frequencies() {
+        assert_eq!(parse_frequency("80Hz"), Ok(80.));
+        assert_eq!(parse_frequency("0.1kHz"), Ok(100.));
+        assert_eq!(parse_frequency(" 120 HZ "), Ok(120.));
+        assert_eq!(parse_frequency("80"), Ok(80.));
+        assert!(parse_frequency("0Hz").is_err());
+        assert!(parse_frequency("2kHz").is_err());
+        assert!(parse_frequency("low").is_err());
+    }
+
+    #[test]
+    fn nothing_to_do_without_options() {
+        assert!(Filters::from_opts(&opts(&[]), 1, SAMPLE_RATE).is_none());
+        assert!(Filters::from_opts(&opts(&["--dc-block"]), 1, SAMPLE_RATE).is_some());
+        assert!(Filters::from_opts(&opts(&["--highpass", "80Hz"]), 1, SAMPLE_RATE).is_some());
+    }
+
+    #[test]
+    fn dc_blocker_removes_the_offset_and_keeps_the_signal() {
+        let out = filtered(&["--dc-block"], sine(200., 0.3));
+        let settled = &out[out.len() - SAMPLE_RATE as usize / 2..];
+        let mean = settled.iter().sum::<f32>() / settled.len() as f32;
+        assert!(mean.abs() < 1e-3, "{}", mean);
+        assert!(gain_db(&out).abs() < 0.1, "{}", gain_db(&out));
+    }
+
+    #[test]
+    fn highpass_cuts_below_the_corner_and_passes_above_it() {
+        let args = ["--highpass", "100Hz"];
+        let rumble = gain_db(&filtered(&args, sine(20., 0.)));
+        let corner = gain_db(&filtered(&args, sine(100., 0.)));
+        let voice = gain_db(&filtered(&args, sine(1000., 0.)));
+        // Second order: 12 dB per octave, a bit over two octaves down
+        assert!(rumble < -25., "{}", rumble);
+        assert!((corner + 3.).abs() < 0.2, "{}", corner);
+        assert!(voice.abs() < 0.1, "{}", voice);
+    }
+
+    #[test]
+    fn channels_are_filtered_on_their_own() {
+        let left = sine(200., 0.3);
+        let right = sine(200., 0.);
+        let mut chunk: Vec<f32> = left.iter().zip(&right).flat_map(|(l, r)| [*l, *r]).collect();
+        let mut filters = Filters::from_opts(&opts(&["--dc-block"]), 2, SAMPLE_RATE).unwrap();
+        filters.process(&mut chunk);
+        let left_out: Vec<f32> = chunk.iter().step_by(2).copied().collect();
+        let right_out: Vec<f32> = chunk.iter().skip(1).step_by(2).copied().collect();
+        let mut right_alone = right.clone();
+        Filters::from_opts(&opts(&["--dc-block"]), 1, SAMPLE_RATE).unwrap().process(&mut right_alone);
+        assert_eq!(right_out, right_alone);
+        // Half a second is a whole number of cycles, so only what's left of the offset remains
+        let settled = &left_out[left_out.len() - SAMPLE_RATE as usize / 2..];
+        assert!((settled.iter().sum::<f32>() / settled.len() as f32).abs() < 1e-3);
+    }
+}
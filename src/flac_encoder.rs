@@ -0,0 +1,274 @@
+use std::error::Error;
+use std::io::{Seek, SeekFrom, Write};
+
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, StreamInfo};
+use flacenc::config;
+use flacenc::error::{Verified, Verify};
+use flacenc::source::{Context, Fill, FrameBuf};
+
+use crate::dither::quantize;
+
+// "fLaC" marker + the STREAMINFO metadata block header
+const STREAMINFO_OFFSET: u64 = 8;
+const METADATA_STREAMINFO: u8 = 0;
+const METADATA_VORBIS_COMMENT: u8 = 4;
+
+/// Roughly mirrors what the reference encoder does at each of its -0 .. -8 levels.
+fn config_for_level(level: u8) -> Result<Verified<config::Encoder>, Box<dyn Error>> {
+    let mut config = config::Encoder::default();
+    config.multithread = false;
+    config.block_size = if level < 3 { 1152 } else { 4096 };
+    let stereo = level != 0 && level != 3;
+    config.stereo_coding.use_leftside = stereo;
+    config.stereo_coding.use_rightside = stereo;
+    config.stereo_coding.use_midside = stereo;
+    config.subframe_coding.use_lpc = level >= 3;
+    config.subframe_coding.qlpc.lpc_order = match level {
+        0..=3 => 6,
+        4..=6 => 8,
+        _ => 12,
+    };
+    config
+        .into_verified()
+        .map_err(|(_, e)| format!("Invalid FLAC encoder config: {}", e).into())
+}
+
+fn to_bytes<T: BitRepr>(component: &T) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut sink = ByteSink::new();
+    component
+        .write(&mut sink)
+        .map_err(|e| format!("Could not serialize FLAC data: {}", e))?;
+    Ok(sink.into_inner())
+}
+
+fn write_metadata_header<W: Write>(
+    sink: &mut W,
+    is_last: bool,
+    block_type: u8,
+    len: usize,
+) -> Result<(), Box<dyn Error>> {
+    if len >= 1 << 24 {
+        return Err("FLAC metadata block too large".into());
+    }
+    let flag = if is_last { 0x80 } else { 0 };
+    sink.write_all(&[flag | block_type])?;
+    sink.write_all(&(len as u32).to_be_bytes()[1..])?;
+    Ok(())
+}
+
+/// Serializes a VORBIS_COMMENT metadata block body. Unlike the rest of FLAC, this is little-endian.
+fn vorbis_comment_block<'a>(tags: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let vendor = format!("akasha {}", env!("CARGO_PKG_VERSION"));
+    let comments: Vec<String> = tags
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    let mut out = Vec::new();
+    out.extend((vendor.len() as u32).to_le_bytes());
+    out.extend(vendor.as_bytes());
+    out.extend((comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        out.extend((comment.len() as u32).to_le_bytes());
+        out.extend(comment.as_bytes());
+    }
+    out
+}
+
+/// Streaming FLAC encoder taking interleaved f32 samples.
+///
+/// Frames are written out as soon as a block fills up. STREAMINFO is written
+/// with placeholder totals at the start, then patched in `finish`.
+pub struct FlacEncoder<W: Write + Seek> {
+    config: Verified<config::Encoder>,
+    stream_info: StreamInfo,
+    framebuf: FrameBuf,
+    context: Context,
+    pending: Vec<i32>,
+    channels: usize,
+    bits: u8,
+    sink: W,
+    // Whether `finish` can go back and fill in STREAMINFO
+    seekable: bool,
+}
+
+impl<W: Write + Seek> FlacEncoder<W> {
+    pub fn new<'a>(
+        tags: impl IntoIterator<Item = (&'a str, &'a str)>,
+        sample_rate: u32,
+        channels: u16,
+        bits: u8,
+        compression_level: u8,
+        mut sink: W,
+    ) -> Result<Self, Box<dyn Error>> {
+        if bits != 16 && bits != 24 {
+            return Err(format!("Unsupported FLAC bit depth: {}", bits).into());
+        }
+        let config = config_for_level(compression_level)?;
+        let channels = channels as usize;
+        let mut stream_info = StreamInfo::new(sample_rate as usize, channels, bits as usize)?;
+        stream_info.set_block_sizes(config.block_size, config.block_size)?;
+
+        let stream_info_bytes = to_bytes(&stream_info)?;
+        let comments = vorbis_comment_block(tags);
+        sink.write_all(b"fLaC")?;
+        write_metadata_header(&mut sink, false, METADATA_STREAMINFO, stream_info_bytes.len())?;
+        sink.write_all(&stream_info_bytes)?;
+        write_metadata_header(&mut sink, true, METADATA_VORBIS_COMMENT, comments.len())?;
+        sink.write_all(&comments)?;
+
+        Ok(Self {
+            framebuf: FrameBuf::with_size(channels, config.block_size)?,
+            context: Context::new(bits as usize, channels),
+            pending: Vec::with_capacity(config.block_size * channels),
+            config,
+            stream_info,
+            channels,
+            bits,
+            sink,
+            seekable: true,
+        })
+    }
+
+    /// For sinks that can only be written front to back. STREAMINFO then keeps its placeholder
+    /// totals, which FLAC takes to mean the length and MD5 are unknown.
+    pub fn without_seeking(mut self) -> Self {
+        self.seekable = false;
+        self
+    }
+
+    /// Encode interleaved samples, writing out every block that fills up.
+    pub fn encode_interleaved(&mut self, samples: &[f32]) -> Result<(), Box<dyn Error>> {
+        let block_len = self.config.block_size * self.channels;
+        for sample in samples {
+            self.pending.push(quantize(*sample, self.bits));
+            if self.pending.len() == block_len {
+                self.write_block()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    /// Writes the final partial block, and fills in STREAMINFO now that we know the totals.
+    pub fn finish(mut self) -> Result<W, Box<dyn Error>> {
+        // Drop any trailing partial sample frame, in case the stream was cut off mid-way
+        let whole = self.pending.len() - self.pending.len() % self.channels;
+        self.pending.truncate(whole);
+        if !self.pending.is_empty() {
+            self.write_block()?;
+        }
+        if self.seekable {
+            self.stream_info
+                .set_total_samples(self.context.total_samples());
+            self.stream_info.set_md5_digest(&self.context.md5_digest());
+            self.sink.seek(SeekFrom::Start(STREAMINFO_OFFSET))?;
+            self.sink.write_all(&to_bytes(&self.stream_info)?)?;
+            self.sink.seek(SeekFrom::End(0))?;
+        }
+        self.sink.flush()?;
+        Ok(self.sink)
+    }
+
+    fn write_block(&mut self) -> Result<(), Box<dyn Error>> {
+        self.framebuf.fill_interleaved(&self.pending)?;
+        self.context.fill_interleaved(&self.pending)?;
+        self.pending.clear();
+        let frame_number = self
+            .context
+            .current_frame_number()
+            .ok_or("FLAC frame counter not started")?;
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &self.framebuf,
+            frame_number,
+            &self.stream_info,
+        )?;
+        self.stream_info.update_frame_info(&frame);
+        self.sink.write_all(&to_bytes(&frame)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn tone(frames: usize, channels: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| (0..channels).map(move |c| 0.5 * ((i + 100 * c) as f32 * 0.03).sin()))
+            .collect()
+    }
+
+    fn encode(samples: &[f32], bits: u8, level: u8, seekable: bool) -> Vec<u8> {
+        let mut encoder = FlacEncoder::new(
+            [("DATE", "2024-05-01T14:37:05.000Z"), ("AKASHA_DEVICE", "mic")],
+            44_100,
+            2,
+            bits,
+            level,
+            Cursor::new(Vec::new()),
+        )
+        .unwrap();
+        if !seekable {
+            encoder = encoder.without_seeking();
+        }
+        // Pieces that don't line up with blocks, or even sample frames
+        for piece in samples.chunks(999) {
+            encoder.encode_interleaved(piece).unwrap();
+        }
+        encoder.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn every_level_decodes_to_the_same_samples() {
+        let samples = tone(10_000, 2);
+        let expected: Vec<i32> = samples.iter().map(|s| quantize(*s, 16)).collect();
+        for level in 0..=8 {
+            let flac = encode(&samples, 16, level, true);
+            let mut reader = claxon::FlacReader::new(Cursor::new(flac)).unwrap();
+            let decoded: Vec<i32> = reader.samples().map(|s| s.unwrap()).collect();
+            assert_eq!(decoded, expected, "level {}", level);
+        }
+    }
+
+    #[test]
+    fn stream_info_has_the_totals_only_when_it_can_be_patched() {
+        let samples = tone(5_000, 2);
+        let reader = claxon::FlacReader::new(Cursor::new(encode(&samples, 24, 5, true))).unwrap();
+        let info = reader.streaminfo();
+        assert_eq!((info.sample_rate, info.channels, info.bits_per_sample), (44_100, 2, 24));
+        assert_eq!(info.samples, Some(5_000));
+        assert_ne!(info.md5sum, [0; 16]);
+
+        let reader = claxon::FlacReader::new(Cursor::new(encode(&samples, 24, 5, false))).unwrap();
+        assert_eq!(reader.streaminfo().samples, None);
+        assert_eq!(reader.streaminfo().md5sum, [0; 16]);
+    }
+
+    #[test]
+    fn writes_tags_as_vorbis_comments() {
+        let reader = claxon::FlacReader::new(Cursor::new(encode(&tone(100, 2), 16, 5, true))).unwrap();
+        assert!(reader.vendor().unwrap().starts_with("akasha "));
+        let tags: Vec<_> = reader.tags().collect();
+        assert_eq!(tags, [("DATE", "2024-05-01T14:37:05.000Z"), ("AKASHA_DEVICE", "mic")]);
+    }
+
+    #[test]
+    fn drops_a_trailing_partial_sample_frame() {
+        let mut samples = tone(1_000, 2);
+        samples.push(0.25);
+        let reader = claxon::FlacReader::new(Cursor::new(encode(&samples, 16, 5, true))).unwrap();
+        assert_eq!(reader.streaminfo().samples, Some(1_000));
+    }
+
+    #[test]
+    fn rejects_other_bit_depths() {
+        assert!(FlacEncoder::new([], 44_100, 2, 20, 5, Cursor::new(Vec::new())).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+use std::sync::{Arc, Mutex};
+
+use async_fn_stream::fn_stream;
+use futures_core::Stream;
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{Chunk, GainOpts};
+
+/// The AGC doesn't chase levels below this, so it holds its gain through pauses instead of
+/// slowly turning the background hiss up to the target.
+const AGC_FLOOR_DBFS: f32 = -65.;
+/// How far the AGC may turn a signal that's louder than the target down.
+const AGC_MAX_CUT_DB: f32 = 24.;
+/// Time constant of the level the AGC steers by; short enough to follow speech, long enough not to hear single syllables.
+const AGC_DETECTOR_SECS: f32 = 0.4;
+/// How quickly the limiter lets go after a peak.
+const LIMITER_RELEASE_SECS: f32 = 0.05;
+/// How often the applied gain is written down, for the segment's sidecar.
+pub const GAIN_TRACK_INTERVAL_SECS: u32 = 1;
+
+/// Parses a level such as `+12dB`, `-6 dB`, `-18dBFS` or a bare `12`, in dB.
+pub fn parse_db(s: &str) -> Result<f32, String> {
+    let lower = s.trim().to_ascii_lowercase();
+    let number = lower
+        .strip_suffix("dbfs")
+        .or_else(|| lower.strip_suffix("db"))
+        .unwrap_or(&lower)
+        .trim();
+    let db: f32 = number
+        .strip_prefix('+')
+        .unwrap_or(number)
+        .parse()
+        .map_err(|_| format!("`{}` is not a level in dB, e.g. +12dB", s))?;
+    if !db.is_finite() {
+        return Err(format!("`{}` is not a level in dB, e.g. +12dB", s));
+    }
+    Ok(db)
+}
+
+pub fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.)
+}
+
+/// Coefficient for a one-pole smoother that gets ~63% of the way to its target in `secs`.
+pub fn smoothing_coefficient(secs: f32, sample_rate: u32) -> f32 {
+    if secs <= 0. {
+        return 0.;
+    }
+    (-1. / (secs * sample_rate as f32)).exp()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct AgcSettings {
+    target_dbfs: f32,
+    max_gain_db: f32,
+    attack: f32,
+    release: f32,
+    detector: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ChannelState {
+    /// What the AGC is currently adding, on top of the fixed gain
+    agc_db: f32,
+    /// Smoothed mean square of the input, for the AGC
+    level: f32,
+    /// Smoothed peak of the output, for the limiter
+    limiter_envelope: f32,
+}
+
+/// How much gain was applied to one segment, so its original level can be worked back out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GainReport {
+    /// `--gain`
+    pub fixed_db: f32,
+    pub agc: bool,
+    pub track_interval_secs: u32,
+    /// Per channel, the average gain applied (fixed and AGC together) over each interval, in dB.
+    /// The limiter's brief cuts aren't included; see `limited_samples`.
+    pub track_db: Vec<Vec<f32>>,
+    /// Per channel, how many samples the limiter turned down to stay under `--limiter-ceiling`
+    pub limited_samples: Vec<u64>,
+}
+
+/// Applied gain, written down as the gain stage goes, until the recorder takes it for a segment.
+#[derive(Debug)]
+pub struct GainTrack {
+    report: GainReport,
+    interval_frames: u64,
+    frames_in_interval: u64,
+    sums_db: Vec<f64>,
+}
+
+impl GainTrack {
+    fn new(fixed_db: f32, agc: bool, channels: usize, sample_rate: u32) -> Self {
+        Self {
+            report: GainReport {
+                fixed_db,
+                agc,
+                track_interval_secs: GAIN_TRACK_INTERVAL_SECS,
+                track_db: vec![Vec::new(); channels],
+                limited_samples: vec![0; channels],
+            },
+            interval_frames: (GAIN_TRACK_INTERVAL_SECS * sample_rate) as u64,
+            frames_in_interval: 0,
+            sums_db: vec![0.; channels],
+        }
+    }
+
+    fn add_frame(&mut self, gains_db: impl Iterator<Item = f32>) {
+        for (sum, gain_db) in self.sums_db.iter_mut().zip(gains_db) {
+            *sum += gain_db as f64;
+        }
+        self.frames_in_interval += 1;
+        if self.frames_in_interval >= self.interval_frames {
+            self.close_interval();
+        }
+    }
+
+    fn close_interval(&mut self) {
+        for (track, sum) in self.report.track_db.iter_mut().zip(self.sums_db.iter_mut()) {
+            track.push((*sum / self.frames_in_interval as f64) as f32);
+            *sum = 0.;
+        }
+        self.frames_in_interval = 0;
+    }
+
+    /// Everything written down since the last `reset`, including the interval in progress.
+    pub fn report(&self) -> GainReport {
+        let mut report = self.report.clone();
+        if self.frames_in_interval > 0 {
+            for (track, sum) in report.track_db.iter_mut().zip(&self.sums_db) {
+                track.push((*sum / self.frames_in_interval as f64) as f32);
+            }
+        }
+        report
+    }
+
+    /// Starts over, for the next segment.
+    pub fn reset(&mut self) {
+        for track in &mut self.report.track_db {
+            track.clear();
+        }
+        self.report.limited_samples.iter_mut().for_each(|limited| *limited = 0);
+        self.sums_db.iter_mut().for_each(|sum| *sum = 0.);
+        self.frames_in_interval = 0;
+    }
+}
+
+/// Fixed gain, optionally an automatic gain control on top, then a safety limiter. Each channel is
+/// levelled on its own, so e.g. a quiet second mic isn't held down by a loud first one.
+pub struct Gain {
+    fixed_db: f32,
+    agc: Option<AgcSettings>,
+    ceiling: f32,
+    limiter_release: f32,
+    channels: Vec<ChannelState>,
+    track: Arc<Mutex<GainTrack>>,
+}
+
+impl Gain {
+    /// `None` if `opts` leave the signal alone.
+    pub fn from_opts(opts: &GainOpts, channels: usize, sample_rate: u32) -> Option<Self> {
+        if opts.gain == 0. && !opts.agc {
+            return None;
+        }
+        let agc = opts.agc.then(|| AgcSettings {
+            target_dbfs: opts.agc_target,
+            max_gain_db: opts.agc_max_gain,
+            attack: smoothing_coefficient(opts.agc_attack.as_secs_f32(), sample_rate),
+            release: smoothing_coefficient(opts.agc_release.as_secs_f32(), sample_rate),
+            detector: smoothing_coefficient(AGC_DETECTOR_SECS, sample_rate),
+        });
+        Some(Self {
+            fixed_db: opts.gain,
+            agc,
+            ceiling: db_to_amplitude(opts.limiter_ceiling),
+            limiter_release: smoothing_coefficient(LIMITER_RELEASE_SECS, sample_rate),
+            channels: vec![ChannelState::default(); channels],
+            track: Arc::new(Mutex::new(GainTrack::new(opts.gain, opts.agc, channels, sample_rate))),
+        })
+    }
+
+    /// Where the gain applied is written down, for the recorder to take at the end of each segment.
+    pub fn track(&self) -> Arc<Mutex<GainTrack>> {
+        self.track.clone()
+    }
+
+    /// Applies the gain to an interleaved chunk in place.
+    pub fn process(&mut self, chunk: &mut [f32]) {
+        let mut track = self.track.lock().unwrap_or_else(|e| e.into_inner());
+        let num_channels = self.channels.len();
+        let fixed = db_to_amplitude(self.fixed_db);
+        for frame in chunk.chunks_exact_mut(num_channels) {
+            for (channel, (sample, state)) in frame.iter_mut().zip(self.channels.iter_mut()).enumerate() {
+                let boosted = *sample * fixed;
+                if let Some(agc) = &self.agc {
+                    // Steer by the level after --gain, so the AGC's target is what comes out
+                    state.level = agc.detector * state.level + (1. - agc.detector) * boosted * boosted;
+                    let level_dbfs = 10. * state.level.max(f32::MIN_POSITIVE).log10();
+                    if level_dbfs > AGC_FLOOR_DBFS {
+                        let wanted = (agc.target_dbfs - level_dbfs).clamp(-AGC_MAX_CUT_DB, agc.max_gain_db);
+                        let coefficient = if wanted < state.agc_db { agc.attack } else { agc.release };
+                        state.agc_db = coefficient * state.agc_db + (1. - coefficient) * wanted;
+                    }
+                }
+                let mut out = boosted * db_to_amplitude(state.agc_db);
+                state.limiter_envelope = out.abs().max(state.limiter_envelope * self.limiter_release);
+                if state.limiter_envelope > self.ceiling {
+                    out *= self.ceiling / state.limiter_envelope;
+                    track.report.limited_samples[channel] += 1;
+                }
+                *sample = out;
+            }
+            track.add_frame(self.channels.iter().map(|state| self.fixed_db + state.agc_db));
+        }
+    }
+}
+
+/// Runs every chunk through `gain`.
+pub fn getstream_gain<S: Stream<Item = Chunk> + Unpin>(
+    mut audio_stream: S,
+    mut gain: Gain,
+) -> impl Stream<Item = Chunk> {
+    fn_stream(|emitter| async move {
+        while let Some(mut chunk) = audio_stream.next().await {
+            gain.process(&mut chunk);
+            emitter.emit(chunk).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_opts::rec_opts;
+
+    const SAMPLE_RATE: u32 = 8_000;
+
+    fn opts(args: &[&str]) -> GainOpts {
+        rec_opts(args).gain
+    }
+
+    /// `secs` of an interleaved 400 Hz sine, one channel per amplitude.
+    fn sine(amplitudes: &[f32], secs: f32) -> Vec<f32> {
+        let frames = (secs * SAMPLE_RATE as f32) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let phase = (2. * std::f32::consts::PI * 400. * i as f32 / SAMPLE_RATE as f32).sin();
+                amplitudes.iter().map(move |amplitude| amplitude * phase)
+            })
+            .collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn parses_levels() {
+        assert_eq!(parse_db("+12dB"), Ok(12.));
+        assert_eq!(parse_db("-6 dB"), Ok(-6.));
+        assert_eq!(parse_db("-18dBFS"), Ok(-18.));
+        assert_eq!(parse_db(" 3 "), Ok(3.));
+        assert!(parse_db("loud").is_err());
+        assert!(parse_db("inf dB").is_err());
+        assert!(parse_db("").is_err());
+    }
+
+    #[test]
+    fn nothing_to_do_without_gain_or_agc() {
+        assert!(Gain::from_opts(&opts(&[]), 1, SAMPLE_RATE).is_none());
+        assert!(Gain::from_opts(&opts(&["--gain", "0dB"]), 1, SAMPLE_RATE).is_none());
+        assert!(Gain::from_opts(&opts(&["--gain", "+6dB"]), 1, SAMPLE_RATE).is_some());
+        assert!(Gain::from_opts(&opts(&["--agc"]), 1, SAMPLE_RATE).is_some());
+    }
+
+    #[test]
+    fn fixed_gain_is_applied_and_tracked() {
+        let mut gain = Gain::from_opts(&opts(&["--gain", "+6dB"]), 2, SAMPLE_RATE).unwrap();
+        let mut chunk = sine(&[0.1, 0.2], 2.5);
+        gain.process(&mut chunk);
+        let left: Vec<f32> = chunk.iter().step_by(2).copied().collect();
+        let right: Vec<f32> = chunk.iter().skip(1).step_by(2).copied().collect();
+        assert!((peak(&left) - 0.1 * db_to_amplitude(6.)).abs() < 1e-3, "{}", peak(&left));
+        assert!((peak(&right) - 0.2 * db_to_amplitude(6.)).abs() < 1e-3, "{}", peak(&right));
+
+        let report = gain.track().lock().unwrap().report();
+        assert_eq!(report.fixed_db, 6.);
+        assert!(!report.agc);
+        assert_eq!(report.limited_samples, vec![0, 0]);
+        // Two whole intervals and the half in progress
+        for track in &report.track_db {
+            assert_eq!(track.len(), 3);
+            assert!(track.iter().all(|db| (db - 6.).abs() < 1e-4), "{:?}", track);
+        }
+    }
+
+    #[test]
+    fn limiter_holds_peaks_under_the_ceiling() {
+        let mut gain = Gain::from_opts(&opts(&["--gain", "+20dB"]), 1, SAMPLE_RATE).unwrap();
+        let mut chunk = sine(&[0.5], 1.);
+        gain.process(&mut chunk);
+        assert!(peak(&chunk) <= db_to_amplitude(-1.) + 1e-6, "{}", peak(&chunk));
+        assert!(gain.track().lock().unwrap().report().limited_samples[0] > 0);
+    }
+
+    #[test]
+    fn agc_levels_each_channel_on_its_own() {
+        let mut gain = Gain::from_opts(
+            &opts(&["--agc", "--agc-target", "-18dBFS", "--agc-attack", "100ms", "--agc-release", "1s"]),
+            2,
+            SAMPLE_RATE,
+        )
+        .unwrap();
+        // -43 dBFS RMS on the left, -3 dBFS on the right
+        let quiet = db_to_amplitude(-40.);
+        let loud = db_to_amplitude(0.);
+        let mut chunk = sine(&[quiet, loud], 10.);
+        gain.process(&mut chunk);
+
+        let settled = &chunk[chunk.len() - 2 * SAMPLE_RATE as usize..];
+        let rms_dbfs = |channel: usize| {
+            let samples: Vec<f32> = settled.iter().skip(channel).step_by(2).copied().collect();
+            let mean_square = samples.iter().map(|sample| sample * sample).sum::<f32>() / samples.len() as f32;
+            10. * mean_square.log10()
+        };
+        assert!((rms_dbfs(0) + 18.).abs() < 1.5, "{}", rms_dbfs(0));
+        assert!((rms_dbfs(1) + 18.).abs() < 1.5, "{}", rms_dbfs(1));
+
+        let report = gain.track().lock().unwrap().report();
+        assert!(report.agc);
+        assert!(*report.track_db[0].last().unwrap() > 20., "{:?}", report.track_db[0]);
+        assert!(*report.track_db[1].last().unwrap() < -10., "{:?}", report.track_db[1]);
+    }
+
+    #[test]
+    fn reset_starts_the_track_over() {
+        let mut gain = Gain::from_opts(&opts(&["--gain", "+20dB"]), 1, SAMPLE_RATE).unwrap();
+        let mut chunk = sine(&[0.5], 1.5);
+        gain.process(&mut chunk);
+        let track = gain.track();
+        track.lock().unwrap().reset();
+        let report = track.lock().unwrap().report();
+        assert_eq!(report.track_db, vec![Vec::<f32>::new()]);
+        assert_eq!(report.limited_samples, vec![0]);
+
+        let mut chunk = sine(&[0.01], 0.5);
+        gain.process(&mut chunk);
+        let report = track.lock().unwrap().report();
+        assert_eq!(report.track_db[0].len(), 1);
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use async_fn_stream::fn_stream;
+use futures_core::Stream;
+use futures_util::StreamExt;
+
+use crate::gain::{db_to_amplitude, smoothing_coefficient};
+use crate::{Chunk, GateOpts};
+
+/// The gate hears this far ahead of what it lets through, so it's already open when a word starts.
+const LOOKAHEAD_SECS: f32 = 0.005;
+/// How quickly the level the gate goes by falls away after a peak.
+const DETECTOR_RELEASE_SECS: f32 = 0.01;
+
+/// A noise gate: turns the signal down by `--gate-attenuation` while it stays below the threshold.
+/// Opens above `--gate-threshold`, but only closes again once the level has dropped `--gate-hysteresis`
+/// below it and `--gate-hold` has passed, so it doesn't chatter or cut off the ends of words.
+/// All channels open and close together, so a stereo image doesn't wander.
+pub struct Gate {
+    open_above: f32,
+    close_below: f32,
+    closed_gain: f32,
+    attack: f32,
+    release: f32,
+    detector_release: f32,
+    hold_frames: u64,
+    channels: usize,
+    envelope: f32,
+    is_open: bool,
+    frames_since_above: u64,
+    gain: f32,
+    /// Interleaved input waiting out the lookahead
+    delay: VecDeque<f32>,
+    /// Frames let through while closed, for the recorder to read and reset each segment
+    gated_frames: Arc<AtomicU64>,
+}
+
+impl Gate {
+    /// `None` if there's no `--gate-threshold`.
+    pub fn from_opts(opts: &GateOpts, channels: usize, sample_rate: u32) -> Option<Self> {
+        let threshold = opts.gate_threshold?;
+        let delay_frames = (LOOKAHEAD_SECS * sample_rate as f32) as usize;
+        Some(Self {
+            open_above: db_to_amplitude(threshold),
+            close_below: db_to_amplitude(threshold - opts.gate_hysteresis),
+            closed_gain: db_to_amplitude(-opts.gate_attenuation),
+            attack: smoothing_coefficient(opts.gate_attack.as_secs_f32(), sample_rate),
+            release: smoothing_coefficient(opts.gate_release.as_secs_f32(), sample_rate),
+            detector_release: smoothing_coefficient(DETECTOR_RELEASE_SECS, sample_rate),
+            hold_frames: (opts.gate_hold.as_secs_f32() * sample_rate as f32) as u64,
+            channels,
+            envelope: 0.,
+            // Start closed, so a recording that opens on hiss doesn't begin with a burst of it
+            is_open: false,
+            frames_since_above: u64::MAX,
+            gain: db_to_amplitude(-opts.gate_attenuation),
+            delay: VecDeque::from(vec![0.; delay_frames * channels]),
+            gated_frames: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// How many frames have come through closed; the recorder resets it at the end of each segment.
+    pub fn gated_frames(&self) -> Arc<AtomicU64> {
+        self.gated_frames.clone()
+    }
+
+    /// Gates an interleaved chunk in place. What comes out is `LOOKAHEAD_SECS` behind what went in.
+    pub fn process(&mut self, chunk: &mut [f32]) {
+        let mut gated = 0;
+        for frame in chunk.chunks_exact_mut(self.channels) {
+            let peak = frame.iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+            self.envelope = peak.max(self.envelope * self.detector_release);
+            if self.envelope > self.open_above {
+                self.is_open = true;
+                self.frames_since_above = 0;
+            } else {
+                self.frames_since_above = self.frames_since_above.saturating_add(1);
+                if self.is_open && self.envelope < self.close_below && self.frames_since_above > self.hold_frames {
+                    self.is_open = false;
+                }
+            }
+            let (target, coefficient) = if self.is_open {
+                (1., self.attack)
+            } else {
+                (self.closed_gain, self.release)
+            };
+            self.gain = coefficient * self.gain + (1. - coefficient) * target;
+            if !self.is_open {
+                gated += 1;
+            }
+
+            for sample in frame.iter_mut() {
+                self.delay.push_back(*sample);
+                *sample = self.delay.pop_front().unwrap_or_default() * self.gain;
+            }
+        }
+        self.gated_frames.fetch_add(gated, Ordering::Relaxed);
+    }
+
+    /// What's still waiting out the lookahead, for when the input ends.
+    fn drain(&mut self) -> Chunk {
+        let gain = self.gain;
+        self.delay.drain(..).map(|sample| sample * gain).collect()
+    }
+}
+
+/// Runs every chunk through `gate`, letting the last few milliseconds out when the input ends.
+pub fn getstream_gate<S: Stream<Item = Chunk> + Unpin>(
+    mut audio_stream: S,
+    mut gate: Gate,
+) -> impl Stream<Item = Chunk> {
+    fn_stream(|emitter| async move {
+        while let Some(mut chunk) = audio_stream.next().await {
+            gate.process(&mut chunk);
+            emitter.emit(chunk).await;
+        }
+        let tail = gate.drain();
+        if !tail.is_empty() {
+            emitter.emit(tail).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_opts::rec_opts;
+
+    const SAMPLE_RATE: u32 = 8_000;
+
+    fn gate(args: &[&str], channels: usize) -> Gate {
+        Gate::from_opts(&rec_opts(args).gate, channels, SAMPLE_RATE).unwrap()
+    }
+
+    /// `secs` of a mono 400 Hz tone with its peaks at `dbfs`, starting on one.
+    fn tone(dbfs: f32, secs: f32) -> Vec<f32> {
+        (0..(secs * SAMPLE_RATE as f32) as usize)
+            .map(|i| db_to_amplitude(dbfs) * (2. * std::f32::consts::PI * 400. * i as f32 / SAMPLE_RATE as f32).cos())
+            .collect()
+    }
+
+    /// Peak of `samples` between `from` and `to` seconds, in dBFS.
+    fn peak_dbfs(samples: &[f32], from: f32, to: f32) -> f32 {
+        let range = (from * SAMPLE_RATE as f32) as usize..(to * SAMPLE_RATE as f32) as usize;
+        let peak = samples[range].iter().fold(0f32, |peak, sample| peak.max(sample.abs()));
+        20. * peak.log10()
+    }
+
+    #[test]
+    fn needs_a_threshold() {
+        assert!(Gate::from_opts(&rec_opts(&[]).gate, 1, SAMPLE_RATE).is_none());
+    }
+
+    #[test]
+    fn stays_closed_below_the_threshold() {
+        let mut gate = gate(&["--gate-threshold", "-40dBFS"], 1);
+        let mut chunk = tone(-50., 1.);
+        gate.process(&mut chunk);
+        assert!((peak_dbfs(&chunk, 0.1, 1.) + 80.).abs() < 0.5, "{}", peak_dbfs(&chunk, 0.1, 1.));
+        assert_eq!(gate.gated_frames().load(Ordering::Relaxed), SAMPLE_RATE as u64);
+    }
+
+    #[test]
+    fn opens_ahead_of_a_loud_start_and_lets_it_through() {
+        let mut gate = gate(&["--gate-threshold", "-40dBFS"], 1);
+        let mut chunk = [vec![0.; SAMPLE_RATE as usize / 2], tone(-10., 0.5)].concat();
+        let input = chunk.clone();
+        gate.process(&mut chunk);
+        let lookahead = (LOOKAHEAD_SECS * SAMPLE_RATE as f32) as usize;
+        // Delayed by the lookahead, and already at full level by the time the tone comes out
+        let onset = SAMPLE_RATE as usize / 2 + lookahead;
+        for (out, in_) in chunk[onset + 10..].iter().zip(&input[SAMPLE_RATE as usize / 2 + 10..]) {
+            assert!((out - in_).abs() < 1e-3, "{} {}", out, in_);
+        }
+        assert_eq!(gate.gated_frames().load(Ordering::Relaxed), SAMPLE_RATE as u64 / 2);
+
+        let tail = gate.drain();
+        assert_eq!(tail.len(), lookahead);
+        assert!((tail[tail.len() - 1] - input[input.len() - 1]).abs() < 1e-6);
+    }
+
+    #[test]
+    fn holds_open_then_closes_once_the_level_drops() {
+        let mut gate = gate(&["--gate-threshold", "-40dBFS", "--gate-hold", "200ms"], 1);
+        let mut chunk = [tone(-10., 0.5), tone(-60., 1.5)].concat();
+        gate.process(&mut chunk);
+        // Inside the hold, the quiet part still comes through as it is
+        assert!((peak_dbfs(&chunk, 0.6, 0.65) + 60.).abs() < 0.5, "{}", peak_dbfs(&chunk, 0.6, 0.65));
+        // Well after it, closed and released
+        assert!((peak_dbfs(&chunk, 1.8, 2.) + 90.).abs() < 0.5, "{}", peak_dbfs(&chunk, 1.8, 2.));
+    }
+
+    #[test]
+    fn hysteresis_keeps_it_open_just_below_the_threshold() {
+        let mut gate = gate(&["--gate-threshold", "-40dBFS", "--gate-hysteresis", "6dB"], 1);
+        let mut chunk = [tone(-10., 0.5), tone(-43., 1.5)].concat();
+        gate.process(&mut chunk);
+        assert!((peak_dbfs(&chunk, 1.5, 2.) + 43.).abs() < 0.5, "{}", peak_dbfs(&chunk, 1.5, 2.));
+        assert_eq!(gate.gated_frames().load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn channels_open_together() {
+        let mut gate = gate(&["--gate-threshold", "-40dBFS"], 2);
+        let left = tone(-10., 1.);
+        let right = tone(-60., 1.);
+        let mut chunk: Vec<f32> = left.iter().zip(&right).flat_map(|(l, r)| [*l, *r]).collect();
+        gate.process(&mut chunk);
+        let right_out: Vec<f32> = chunk.iter().skip(1).step_by(2).copied().collect();
+        assert!((peak_dbfs(&right_out, 0.5, 1.) + 60.).abs() < 0.5, "{}", peak_dbfs(&right_out, 0.5, 1.));
+    }
+}
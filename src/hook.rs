@@ -0,0 +1,39 @@
+use std::process::Command;
+use std::thread;
+
+use log::{error, info, warn};
+
+/// Runs a user's hook command (e.g. `--clip-hook`) through the shell, in the background so the recording
+/// never waits on it. `AKASHA_EVENT` says what happened; `vars` are passed as more environment variables.
+pub fn run(command: &str, event: &str, vars: &[(&str, String)]) {
+    #[cfg(target_family = "windows")]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+    #[cfg(not(target_family = "windows"))]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command).env("AKASHA_EVENT", event);
+    for (key, value) in vars {
+        shell.env(key, value);
+    }
+    let mut child = match shell.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Could not run {} hook `{}`: {}", event, command, e);
+            return;
+        }
+    };
+    info!("Running {} hook `{}`", event, command);
+    let (event, command) = (event.to_owned(), command.to_owned());
+    thread::spawn(move || match child.wait() {
+        Ok(status) if !status.success() => warn!("{} hook `{}` exited with {}", event, command, status),
+        Ok(_) => {}
+        Err(e) => warn!("Could not wait for {} hook `{}`: {}", event, command, e),
+    });
+}
@@ -2,65 +2,140 @@
 // TODO: more intelligent microphone device selection logic -- maybe use an argument to pass mic name?
 // TODO: turn off console indicator with SIGHUP
 
+mod archive;
+mod attest;
+mod audio_info;
 mod bigdurations;
+mod catalog;
+mod clip;
+mod decode;
 mod display_volume;
+mod dither;
+mod encrypt;
+mod export;
+mod filter;
+mod flac_encoder;
+mod gain;
+mod gate;
+mod hook;
+mod manifest;
 mod microphone;
+mod name_template;
 mod noise_filter;
+mod ogg_demux;
+mod ogg_mux;
+mod opus_decoder;
+mod opus_encoder;
 mod quitmsg;
 mod record;
+mod segment_file;
+mod segment_meta;
+mod segment_stats;
+mod silence;
+#[cfg(test)]
+mod test_dir;
+#[cfg(test)]
+mod test_opts;
+mod time_zone;
+mod verify;
+mod vorbis_decoder;
+mod vorbis_encoder;
+mod wav;
 mod write_audio;
 
 extern crate chrono;
 
-use crate::FormatSelect::Ogg;
-use async_fn_stream::fn_stream;
-use chrono::{DateTime, Local};
+use chrono::{DateTime, FixedOffset};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
 use clap::{Parser, Subcommand, ValueEnum};
 use clap_duration::duration_range_value_parse;
 use cpal::traits::{DeviceTrait, HostTrait};
-use cpal::Host;
 use crossterm::event;
 use crossterm::event::KeyCode::Char;
 use crossterm::event::{Event, KeyModifiers};
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
 use duration_human::{DurationHuman, DurationHumanValidator};
 use enum_as_inner::EnumAsInner;
-use futures_core::Stream;
-use futures_util::{pin_mut, AsyncReadExt};
-use lazy_static::lazy_static;
 use log::{debug, error, info, trace, warn};
+use name_template::NameTemplate;
 use printrn::printrn;
 use quitmsg::QuitMsg;
 use signal_hook::low_level;
+use time_zone::NameTimeZone;
 use std::borrow::ToOwned;
 use std::error::Error;
-use std::ffi::OsString;
-use std::fmt::{Debug, Formatter};
-use std::ops::Deref;
+use std::fmt::Debug;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use std::{error, thread};
-use tokio::io::AsyncWriteExt;
+use std::thread;
 use tokio::runtime::Runtime;
-use tokio::sync::{RwLock, RwLockReadGuard};
+use tokio::sync::RwLock;
 use tokio::time::Instant;
+use opus_encoder::OpusApplication;
+use vorbis_encoder::VorbisBitrateStrategy;
+use wav::WavContainer;
 
 type Chunk = Vec<f32>;
 
-#[derive(Parser, Debug, ValueEnum, Clone)]
+#[derive(Parser, Debug, ValueEnum, Clone, Copy, PartialEq, Default)]
 pub enum FormatSelect {
     Wav,
+    #[default]
     Ogg,
+    Flac,
+    Opus,
 }
 
-impl Default for FormatSelect {
-    fn default() -> Self {
-        Ogg
+impl FormatSelect {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            FormatSelect::Wav => "wav",
+            FormatSelect::Ogg => "ogg",
+            FormatSelect::Flac => "flac",
+            FormatSelect::Opus => "opus",
+        }
     }
 }
 
+/// One `--format` value: which format to write, and optionally which subdirectory to put it in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputFormat {
+    format: FormatSelect,
+    subdir: Option<PathBuf>,
+}
+
+impl OutputFormat {
+    /// The directory this format's segments are named relative to, once its subdirectory is taken into account.
+    pub fn dir_for(&self, path_dir: &Path) -> PathBuf {
+        match &self.subdir {
+            Some(subdir) => path_dir.join(subdir),
+            None => path_dir.to_path_buf(),
+        }
+    }
+}
+
+fn parse_output_format(s: &str) -> Result<OutputFormat, String> {
+    let (format, subdir) = match s.split_once(':') {
+        Some((format, subdir)) => (format, Some(subdir)),
+        None => (s, None),
+    };
+    let format = FormatSelect::from_str(format, true)?;
+    let subdir = match subdir {
+        Some("") => return Err(format!("No subdirectory given in `{}`", s)),
+        Some(subdir) => {
+            let subdir = PathBuf::from(subdir);
+            if subdir.is_absolute() {
+                return Err(format!("Subdirectory `{}` must be relative to --path-dir", subdir.display()));
+            }
+            Some(subdir)
+        }
+        None => None,
+    };
+    Ok(OutputFormat { format, subdir })
+}
+
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
@@ -73,7 +148,220 @@ struct Cli {
 #[derive(Subcommand, Debug, Clone, EnumAsInner)]
 enum Commands {
     Probe(Probe),
-    Rec(Rec),
+    Rec(Box<Rec>),
+    /// Rebuild the segment catalog of a recording directory from the files in it
+    Index(Index),
+    /// List the segments in a recording directory, with the gaps between them
+    List(List),
+    /// Find the segment(s) covering a moment, and how far into them it is
+    Find(Find),
+    /// Stitch a stretch of wall-clock time together from the segments covering it, into one file
+    Export(Box<Export>),
+    /// Check every recorded segment against the manifest, and that it still decodes in full
+    Verify(Verify),
+    /// Work with the signed hash chain kept with `rec --attest`
+    #[command(subcommand)]
+    Attest(AttestCommands),
+    /// Decrypt segments recorded with `rec --encrypt-to`, including ones a crash left as `.partial`
+    Decrypt(Decrypt),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum AttestCommands {
+    /// Check that no chained segment was altered, removed or reordered
+    Verify(AttestVerify),
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct AttestVerify {
+    #[clap(value_hint = clap::ValueHint::DirPath)]
+    #[arg(help = "The recording directory to check [default: ~/Audio/akasha]\n")]
+    path_dir: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "HEX",
+        help = "Public key the chain must be signed with, e.g. the contents of attest.pub.\n\
+                Without it, the chain only has to be signed consistently with the key in its first link\n"
+    )]
+    public_key: Option<String>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct IdentityOpts {
+    #[arg(
+        long = "identity",
+        value_name = "PATH",
+        help = "age identity file (e.g. from age-keygen) to decrypt encrypted segments with.\n\
+                May be given more than once\n"
+    )]
+    identities: Vec<PathBuf>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct Decrypt {
+    #[arg(required = true, help = "Encrypted segment files (`*.age`, or `*.age.partial` after a crash)\n")]
+    files: Vec<PathBuf>,
+    #[arg(
+        short,
+        long,
+        value_name = "DIR",
+        help = "Where to write the decrypted files [default: next to each encrypted one]\n"
+    )]
+    output_dir: Option<PathBuf>,
+    #[command(flatten)]
+    identity: IdentityOpts,
+}
+
+const DEFAULT_NAME_PREFIX: &str = "akasha";
+const DEFAULT_TIME_FORMAT: &str = "%Y-%m-%d__%H_%M_%S__%a_%b__%z";
+
+/// How segments are named: what `rec` writes, and what the other commands need to read the names back.
+#[derive(clap::Args, Debug, Clone)]
+struct NamingOpts {
+    #[arg(short, long, default_value = DEFAULT_NAME_PREFIX, help = "What segment names start with\n")]
+    name_prefix: String,
+    #[arg(
+        short,
+        long,
+        default_value = DEFAULT_TIME_FORMAT,
+        value_parser = time_zone::parse_time_format,
+        help = "strftime format of the time in segment names, or `iso8601` for e.g. 20240501T143700+0000.\n\
+                Reading names back takes the one they were recorded with\n"
+    )]
+    time_format: String,
+    #[arg(
+        long,
+        default_value = "utc",
+        value_parser = time_zone::parse,
+        help = "Time zone segment names are written in: utc, local, or an IANA name such as Europe/Berlin.\n\
+                Names in a zone with DST don't sort by time when the clocks go back. Sidecars and the catalog\n\
+                are always UTC. Reading names back takes the one they were recorded with\n"
+    )]
+    time_zone: NameTimeZone,
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        value_parser = name_template::parse,
+        conflicts_with = "time_format",
+        help = "Where each segment goes under --path-dir, e.g. `{device}/%Y/%m/%d/{prefix}_{index:05}_%H%M%S`.\n\
+                Placeholders: {prefix}, {device}, {hostname}, {session}, {index} (or {index:05}) and {format};\n\
+                everything else is a strftime format. Subdirectories are created as needed.\n\
+                Reading names back takes the one they were recorded with\n\
+                [default: {prefix}__<--time-format>]\n"
+    )]
+    name_template: Option<NameTemplate>,
+}
+
+impl NamingOpts {
+    /// `--name-template`, or the one `--name-prefix` and `--time-format` make up.
+    fn name_template(&self) -> Result<NameTemplate, Box<dyn Error>> {
+        match &self.name_template {
+            Some(template) => Ok(template.clone()),
+            None => Ok(NameTemplate::from_time_format(&self.time_format)
+                .map_err(|e| format!("Bad --time-format: {}", e))?),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct Index {
+    #[clap(value_hint = clap::ValueHint::DirPath)]
+    #[arg(help = "The recording directory to index [default: ~/Audio/akasha]\n")]
+    path_dir: Option<PathBuf>,
+    #[command(flatten)]
+    naming: NamingOpts,
+    #[command(flatten)]
+    identity: IdentityOpts,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct List {
+    #[clap(value_hint = clap::ValueHint::DirPath)]
+    #[arg(help = "The recording directory to list [default: ~/Audio/akasha]\n")]
+    path_dir: Option<PathBuf>,
+    #[arg(long, value_parser = archive::parse_timestamp, help = "Only segments still recording at or after this time\n")]
+    since: Option<DateTime<FixedOffset>>,
+    #[arg(long, value_parser = archive::parse_timestamp, help = "Only segments that started before this time\n")]
+    until: Option<DateTime<FixedOffset>>,
+    #[arg(long, help = "Only segments recorded from a device whose name contains this\n")]
+    device: Option<String>,
+    #[arg(long, help = "Print JSON instead of a table\n")]
+    json: bool,
+    #[command(flatten)]
+    naming: NamingOpts,
+    #[command(flatten)]
+    identity: IdentityOpts,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct Find {
+    #[arg(
+        long,
+        value_parser = archive::parse_timestamp,
+        help = "The moment to look for, e.g. `yesterday 14:37`, `2024-05-01 14:37:05` or RFC 3339\n"
+    )]
+    at: DateTime<FixedOffset>,
+    #[clap(value_hint = clap::ValueHint::DirPath)]
+    #[arg(help = "The recording directory to search [default: ~/Audio/akasha]\n")]
+    path_dir: Option<PathBuf>,
+    #[command(flatten)]
+    naming: NamingOpts,
+    #[command(flatten)]
+    identity: IdentityOpts,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct Export {
+    #[arg(long, value_parser = archive::parse_timestamp, help = "Start of the stretch to export\n")]
+    from: DateTime<FixedOffset>,
+    #[arg(long, value_parser = archive::parse_timestamp, help = "End of the stretch to export\n")]
+    to: DateTime<FixedOffset>,
+    #[arg(short, long, help = "The file to write\n")]
+    output: PathBuf,
+    #[arg(
+        short = 'f',
+        long = "format",
+        help = "Format of the exported file [default: from the --output extension]\n"
+    )]
+    format: Option<FormatSelect>,
+    #[arg(
+        long,
+        help = "Only read segments in this format [default: the best one recorded, lossless first]\n"
+    )]
+    source_format: Option<FormatSelect>,
+    #[arg(long, default_value = "silence", help = "What to put where nothing was recorded\n")]
+    gaps: export::GapFill,
+    #[arg(
+        long = "tag",
+        value_name = "KEY=VALUE",
+        value_parser = segment_meta::parse_tag,
+        help = "Extra metadata tag to write into the exported file. May be given more than once\n"
+    )]
+    tags: Vec<(String, String)>,
+    #[clap(value_hint = clap::ValueHint::DirPath)]
+    #[arg(help = "The recording directory to export from [default: ~/Audio/akasha]\n")]
+    path_dir: Option<PathBuf>,
+    #[command(flatten)]
+    naming: NamingOpts,
+    #[command(flatten)]
+    identity: IdentityOpts,
+    #[command(flatten)]
+    wav: WavOpts,
+    #[command(flatten)]
+    ogg: OggOpts,
+    #[command(flatten)]
+    flac: FlacOpts,
+    #[command(flatten)]
+    opus: OpusOpts,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct Verify {
+    #[clap(value_hint = clap::ValueHint::DirPath)]
+    #[arg(help = "The recording directory to verify [default: ~/Audio/akasha]\n")]
+    path_dir: Option<PathBuf>,
+    #[command(flatten)]
+    identity: IdentityOpts,
 }
 
 #[derive(clap::Args, Debug, Clone)]
@@ -85,27 +373,30 @@ struct Probe {
 #[derive(clap::Args, Debug, Clone)]
 struct Rec {
     #[arg(
-        short,
-        long,
+        short = 'f',
+        long = "format",
+        value_name = "FORMAT[:SUBDIR]",
         default_value = "ogg",
-        help = "The format used to write the recording\n"
+        value_delimiter = ',',
+        value_parser = parse_output_format,
+        help = "The format(s) used to write the recording: wav, ogg, flac or opus.\n\
+                Give several (comma-separated, or repeated) to write them all from the same capture,\n\
+                optionally each into its own subdirectory, e.g. `-f flac:archive,opus:listen`\n"
     )]
-    format: FormatSelect,
+    formats: Vec<OutputFormat>,
     #[arg(short, long)]
     // #[clap(conflicts_with="list_devices")]
     #[clap(value_hint = clap::ValueHint::DirPath)]
     #[arg(help = "The directory in which we write our recording segment files\n")]
     path_dir: Option<PathBuf>,
-    #[arg(short, long, default_value = "akasha")]
-    name_prefix: String,
+    #[command(flatten)]
+    naming: NamingOpts,
     #[arg(short, long)]
     device: Option<String>,
     //#[structopt(long = 0f32)]
     #[arg(short, long, default_value="30min",
     value_parser = duration_range_value_parse!(min: 1s, max: 1h))]
     segment_dur: DurationHuman,
-    #[arg(short, long, default_value = "%Y-%m-%d__%H_%M_%S__%a_%b__%z")]
-    time_format: String,
     #[arg(
     long, value_parser = duration_range_value_parse!(min: 1s, max: 1h),
     help = "The duration after which to terminate the real-time volume display"
@@ -113,6 +404,519 @@ struct Rec {
     display_dur: Option<DurationHuman>,
     #[arg(long)]
     display: bool,
+    #[arg(
+        long = "tag",
+        value_name = "KEY=VALUE",
+        value_parser = segment_meta::parse_tag,
+        help = "Extra metadata tag to write into each segment. May be given more than once\n"
+    )]
+    tags: Vec<(String, String)>,
+    #[arg(
+        long,
+        help = "Add each finished segment to a hash chain signed with a local Ed25519 key,\n\
+                so any later edit, deletion or reordering can be detected with `akasha attest verify`\n"
+    )]
+    attest: bool,
+    #[arg(
+        long,
+        value_name = "PATH",
+        requires = "attest",
+        help = "Signing key for --attest, created if missing [default: ~/.config/akasha/attest.key]\n"
+    )]
+    attest_key: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "RECIPIENT",
+        value_parser = encrypt::parse_recipient,
+        help = "Encrypt each segment as it's written to this age public key (age1...), so the recorder\n\
+                never holds the key to read it back. Up to 64 KiB of each segment waits in memory to be\n\
+                encrypted, and a crash loses it. Only the audio is encrypted: sidecars, the catalog and\n\
+                the manifest stay plain text, with the device, host, tags, times and levels of each\n\
+                segment. May be given more than once\n"
+    )]
+    encrypt_to: Vec<age::x25519::Recipient>,
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Encrypt each segment to every age public key listed in this file, one per line\n"
+    )]
+    encrypt_to_file: Vec<PathBuf>,
+    #[command(flatten)]
+    wav: WavOpts,
+    #[command(flatten)]
+    ogg: OggOpts,
+    #[command(flatten)]
+    flac: FlacOpts,
+    #[command(flatten)]
+    opus: OpusOpts,
+    #[command(flatten)]
+    filter: FilterOpts,
+    #[command(flatten)]
+    gain: GainOpts,
+    #[command(flatten)]
+    gate: GateOpts,
+    #[command(flatten)]
+    clip: ClipOpts,
+    #[command(flatten)]
+    silence: SilenceOpts,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct SilenceOpts {
+    #[arg(
+        long,
+        default_value = "-70dBFS",
+        value_name = "DBFS",
+        allow_hyphen_values = true,
+        value_parser = gain::parse_db,
+        help = "Count a second of input as silent if every channel's RMS level is below this\n"
+    )]
+    silence_threshold: f32,
+    #[arg(
+        long,
+        default_value = "30s",
+        value_parser = parse_short_duration,
+        help = "Warn, and run --silence-hook, once the input has been silent or all zeros for this long\n"
+    )]
+    silence_after: Duration,
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Shell command to run when the input goes silent; AKASHA_EVENT=silence, AKASHA_SILENCE \
+                (digital or near) and AKASHA_SILENT_SECS are set\n"
+    )]
+    silence_hook: Option<String>,
+    #[arg(
+        long,
+        help = "Stop recording when the input goes silent, exiting with status 3\n"
+    )]
+    exit_on_silence: bool,
+}
+
+impl SilenceOpts {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.silence_threshold > 0. {
+            return Err("--silence-threshold can't be above 0dBFS".into());
+        }
+        if self.silence_after < Duration::from_secs(1) {
+            return Err("--silence-after must be at least 1s".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ClipOpts {
+    #[arg(
+        long,
+        default_value = "-0.1dBFS",
+        value_name = "DBFS",
+        allow_hyphen_values = true,
+        value_parser = gain::parse_db,
+        help = "Count an input sample at or above this level as clipped\n"
+    )]
+    clip_threshold: f32,
+    #[arg(
+        long,
+        default_value = "3s",
+        value_parser = parse_short_duration,
+        help = "Warn, and run --clip-hook, once the input has clipped in every second for this long\n"
+    )]
+    clip_alert_after: Duration,
+    #[arg(
+        long,
+        value_name = "COMMAND",
+        help = "Shell command to run when clipping persists; AKASHA_EVENT=clipping and AKASHA_CLIPPING_SECS are set\n"
+    )]
+    clip_hook: Option<String>,
+}
+
+impl ClipOpts {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.clip_threshold > 0. {
+            return Err("--clip-threshold can't be above 0dBFS".into());
+        }
+        if self.clip_alert_after < Duration::from_secs(1) {
+            return Err("--clip-alert-after must be at least 1s".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct GateOpts {
+    #[arg(
+        long,
+        value_name = "DBFS",
+        allow_hyphen_values = true,
+        value_parser = gain::parse_db,
+        help = "Noise gate: turn the input down while its level stays below this, e.g. -50dBFS\n"
+    )]
+    gate_threshold: Option<f32>,
+    #[arg(
+        long,
+        default_value = "6dB",
+        value_parser = gain::parse_db,
+        requires = "gate_threshold",
+        help = "How far below --gate-threshold the level has to fall before the gate closes again\n"
+    )]
+    gate_hysteresis: f32,
+    #[arg(
+        long,
+        default_value = "30dB",
+        value_parser = gain::parse_db,
+        requires = "gate_threshold",
+        help = "How far the gate turns the input down while closed\n"
+    )]
+    gate_attenuation: f32,
+    #[arg(
+        long,
+        default_value = "1ms",
+        value_parser = parse_short_duration,
+        requires = "gate_threshold",
+        help = "How quickly the gate opens\n"
+    )]
+    gate_attack: Duration,
+    #[arg(
+        long,
+        default_value = "200ms",
+        value_parser = parse_short_duration,
+        requires = "gate_threshold",
+        help = "How long the gate stays open after the level drops, so it doesn't close between words\n"
+    )]
+    gate_hold: Duration,
+    #[arg(
+        long,
+        default_value = "150ms",
+        value_parser = parse_short_duration,
+        requires = "gate_threshold",
+        help = "How gradually the gate closes\n"
+    )]
+    gate_release: Duration,
+}
+
+impl GateOpts {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.gate_threshold.is_some_and(|threshold| threshold > 0.) {
+            return Err("--gate-threshold can't be above 0dBFS".into());
+        }
+        if self.gate_hysteresis < 0. || self.gate_attenuation < 0. {
+            return Err("--gate-hysteresis and --gate-attenuation are amounts to go down by, so can't be negative".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct FilterOpts {
+    #[arg(
+        long,
+        value_name = "FREQ",
+        value_parser = filter::parse_frequency,
+        help = "High-pass the input at this corner frequency, e.g. 80Hz, to take out rumble and handling noise\n"
+    )]
+    highpass: Option<f32>,
+    #[arg(long, help = "Remove any DC offset the input has\n")]
+    dc_block: bool,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct GainOpts {
+    #[arg(
+        long,
+        default_value = "0dB",
+        allow_hyphen_values = true,
+        value_parser = gain::parse_db,
+        help = "Fixed gain applied to the input before anything else, e.g. +12dB\n"
+    )]
+    gain: f32,
+    #[arg(
+        long,
+        help = "Automatic gain control: slowly level each channel towards --agc-target, on top of --gain\n"
+    )]
+    agc: bool,
+    #[arg(
+        long,
+        default_value = "-18dBFS",
+        allow_hyphen_values = true,
+        value_parser = gain::parse_db,
+        help = "RMS level the AGC aims for\n"
+    )]
+    agc_target: f32,
+    #[arg(
+        long,
+        default_value = "30dB",
+        value_parser = gain::parse_db,
+        help = "Most gain the AGC may add\n"
+    )]
+    agc_max_gain: f32,
+    #[arg(
+        long,
+        default_value = "500ms",
+        value_parser = parse_short_duration,
+        help = "How quickly the AGC turns down when the input gets louder\n"
+    )]
+    agc_attack: Duration,
+    #[arg(
+        long,
+        default_value = "10s",
+        value_parser = parse_short_duration,
+        help = "How quickly the AGC turns up when the input gets quieter\n"
+    )]
+    agc_release: Duration,
+    #[arg(
+        long,
+        default_value = "-1dBFS",
+        allow_hyphen_values = true,
+        value_parser = gain::parse_db,
+        help = "Peaks after --gain and --agc are limited to this, so the added gain can't clip\n"
+    )]
+    limiter_ceiling: f32,
+}
+
+impl GainOpts {
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.gain.abs() > 60. {
+            return Err("--gain must be between -60dB and +60dB".into());
+        }
+        if self.agc_target > 0. || self.limiter_ceiling > 0. {
+            return Err("--agc-target and --limiter-ceiling can't be above 0dBFS".into());
+        }
+        if !(0. ..=60.).contains(&self.agc_max_gain) {
+            return Err("--agc-max-gain must be between 0dB and 60dB".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq)]
+enum WavBits {
+    /// 16-bit integer PCM
+    #[value(name = "16")]
+    Int16,
+    /// 24-bit integer PCM
+    #[value(name = "24")]
+    Int24,
+    /// 32-bit float
+    #[value(name = "32f")]
+    Float32,
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy, PartialEq)]
+enum WavDither {
+    /// Round to the nearest integer sample
+    None,
+    /// Add triangular noise before rounding, to decorrelate the quantization error
+    Tpdf,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct WavOpts {
+    #[arg(long, default_value = "32f", help = "Sample format of WAV segments\n")]
+    wav_bits: WavBits,
+    #[arg(
+        long,
+        default_value = "tpdf",
+        help = "Dither applied when writing integer WAV samples\n"
+    )]
+    wav_dither: WavDither,
+    #[arg(
+        long,
+        default_value = "auto",
+        help = "WAV container; RF64 lifts the 4 GiB limit of plain WAV\n"
+    )]
+    wav_container: WavContainer,
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy, PartialEq)]
+enum OggBitrateMode {
+    /// Variable bitrate, tuned to land near --ogg-bitrate
+    Vbr,
+    /// Variable bitrate at a fixed --ogg-quality
+    Quality,
+    /// Average bitrate of --ogg-bitrate
+    Abr,
+    /// Never exceed --ogg-max-bitrate (or --ogg-bitrate)
+    Constrained,
+    /// Stay within --ogg-min-bitrate and --ogg-max-bitrate, averaging --ogg-bitrate
+    Managed,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct OggOpts {
+    #[arg(
+        long,
+        help = "How the Vorbis encoder manages its bitrate [default: vbr, or quality if -q is given]\n"
+    )]
+    ogg_bitrate_mode: Option<OggBitrateMode>,
+    #[arg(
+        short = 'q',
+        long,
+        allow_negative_numbers = true,
+        value_parser = parse_ogg_quality,
+        help = "Vorbis quality from -1 to 10, as with oggenc\n"
+    )]
+    ogg_quality: Option<f32>,
+    #[arg(long, help = "Target Vorbis bitrate in kbit/s [default: 128]\n")]
+    ogg_bitrate: Option<u32>,
+    #[arg(long, help = "Minimum Vorbis bitrate in kbit/s, for the managed mode\n")]
+    ogg_min_bitrate: Option<u32>,
+    #[arg(long, help = "Maximum Vorbis bitrate in kbit/s, for the constrained & managed modes\n")]
+    ogg_max_bitrate: Option<u32>,
+    #[arg(
+        long,
+        help = "Hold Ogg pages back until they carry at least this many bytes of audio\n"
+    )]
+    ogg_minimum_page_data_size: Option<u16>,
+    #[arg(
+    long, default_value = "5s", value_parser = duration_range_value_parse!(min: 1s, max: 1h),
+    help = "Force pending Ogg pages out to disk at least this often, so a crash loses little audio.\n\
+            With --encrypt-to, pages still wait until they fill a 64 KiB age chunk, e.g. 22s at 24 kbit/s\n"
+    )]
+    ogg_flush_interval: DurationHuman,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct FlacOpts {
+    #[arg(
+        long,
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u8).range(0..=8),
+        help = "FLAC compression level, from 0 (fastest) to 8 (smallest)\n"
+    )]
+    flac_compression_level: u8,
+    #[arg(
+        long,
+        default_value = "24",
+        value_parser = PossibleValuesParser::new(["16", "24"]).map(|s| s.parse::<u8>().unwrap()),
+        help = "Bits per sample in FLAC segments\n"
+    )]
+    flac_bits: u8,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct OpusOpts {
+    #[arg(long, default_value_t = 24, help = "Opus bitrate in kbit/s\n")]
+    opus_bitrate: u32,
+    #[arg(
+        long,
+        default_value = "voip",
+        help = "What the Opus encoder should optimize for\n"
+    )]
+    opus_application: OpusApplication,
+    #[arg(
+        long,
+        default_value = "20",
+        value_parser = PossibleValuesParser::new(["2.5", "5", "10", "20", "40", "60"])
+            .map(|s| s.parse::<f32>().unwrap()),
+        help = "Opus frame duration in milliseconds\n"
+    )]
+    opus_frame_size: f32,
+}
+
+fn parse_ogg_quality(s: &str) -> Result<f32, String> {
+    let quality: f32 = s.parse().map_err(|_| format!("`{}` is not a number", s))?;
+    if !(-1.0..=10.0).contains(&quality) {
+        return Err("Vorbis quality must be between -1 and 10".into());
+    }
+    Ok(quality)
+}
+
+/// Parses time constants such as `500ms` or `2s`, which are too short for `duration_range_value_parse!`.
+fn parse_short_duration(s: &str) -> Result<Duration, String> {
+    let duration = DurationHuman::try_from(s).map_err(|e| e.to_string())?;
+    let duration = Duration::from(&duration);
+    if duration > Duration::from_secs(600) {
+        return Err("must be 10min or less".into());
+    }
+    Ok(duration)
+}
+
+impl Rec {
+    fn validate_formats(&self) -> Result<(), Box<dyn Error>> {
+        for (i, output) in self.formats.iter().enumerate() {
+            if self.formats[..i].contains(output) {
+                return Err(format!(
+                    "--format {} is given more than once for the same directory",
+                    output.format.extension()
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// With `--encrypt-to`, flushed Ogg and Opus pages still wait until they fill an age chunk,
+    /// which at low bitrates takes longer than `--ogg-flush-interval`.
+    fn warn_if_encryption_outlasts_flush(&self) {
+        let flush_secs = Duration::from(&self.ogg.ogg_flush_interval).as_secs_f64();
+        for output in &self.formats {
+            let kbps = match output.format {
+                FormatSelect::Ogg => self.ogg.ogg_bitrate.unwrap_or(OggOpts::DEFAULT_BITRATE_KBPS),
+                FormatSelect::Opus => self.opus.opus_bitrate,
+                FormatSelect::Wav | FormatSelect::Flac => continue,
+            };
+            let chunk_secs = encrypt::chunk_secs(kbps.saturating_mul(1000));
+            if chunk_secs > flush_secs {
+                warn!(
+                    "At {} kbit/s, {} audio takes about {:.0}s to fill a chunk of the encrypted stream, so a \
+                     crash can lose that much rather than --ogg-flush-interval's {}s",
+                    kbps,
+                    output.format.extension(),
+                    chunk_secs,
+                    flush_secs
+                );
+            }
+        }
+    }
+}
+
+impl OggOpts {
+    const DEFAULT_BITRATE_KBPS: u32 = 128;
+
+    fn bitrate_strategy(&self) -> Result<VorbisBitrateStrategy, Box<dyn Error>> {
+        let kbps = |k: u32| k.saturating_mul(1000);
+        let mode = match (self.ogg_bitrate_mode, self.ogg_quality) {
+            (Some(mode), None) => mode,
+            (None, Some(_)) | (Some(OggBitrateMode::Quality), Some(_)) => OggBitrateMode::Quality,
+            (Some(_), Some(_)) => {
+                return Err("--ogg-quality only applies to --ogg-bitrate-mode quality".into())
+            }
+            (None, None) => OggBitrateMode::Vbr,
+        };
+        if mode != OggBitrateMode::Managed && self.ogg_min_bitrate.is_some() {
+            return Err("--ogg-min-bitrate only applies to --ogg-bitrate-mode managed".into());
+        }
+        let bitrate = kbps(self.ogg_bitrate.unwrap_or(Self::DEFAULT_BITRATE_KBPS));
+        Ok(match mode {
+            OggBitrateMode::Vbr => VorbisBitrateStrategy::Vbr {
+                target_bitrate: bitrate,
+            },
+            OggBitrateMode::Quality => VorbisBitrateStrategy::QualityVbr {
+                // oggenc counts from -1 to 10; libvorbis wants -0.1 to 1.0
+                quality: self.ogg_quality.ok_or("--ogg-bitrate-mode quality needs -q")? / 10.,
+            },
+            OggBitrateMode::Abr => VorbisBitrateStrategy::Abr {
+                average_bitrate: bitrate,
+            },
+            OggBitrateMode::Constrained => VorbisBitrateStrategy::ConstrainedAbr {
+                maximum_bitrate: self.ogg_max_bitrate.map(kbps).unwrap_or(bitrate),
+            },
+            OggBitrateMode::Managed => {
+                if self.ogg_min_bitrate.is_none() && self.ogg_max_bitrate.is_none() {
+                    return Err(
+                        "--ogg-bitrate-mode managed needs --ogg-min-bitrate and/or --ogg-max-bitrate"
+                            .into(),
+                    );
+                }
+                VorbisBitrateStrategy::Managed {
+                    minimum_bitrate: self.ogg_min_bitrate.map(kbps),
+                    average_bitrate: self.ogg_bitrate.map(kbps),
+                    maximum_bitrate: self.ogg_max_bitrate.map(kbps),
+                }
+            }
+        })
+    }
 }
 
 #[derive(Parser, Debug, ValueEnum, Clone)]
@@ -133,6 +937,7 @@ struct TermSize {
     y: u16,
 }
 
+#[allow(dead_code)]
 impl TermSize {
     fn query() -> Self {
         match crossterm::terminal::size() {
@@ -170,6 +975,15 @@ pub struct ProgramState {
     // signals: RwLock<Signals>,
     interactive: RwLock<bool>,
     path_dir: RwLock<PathBuf>,
+    session_id: String,
+    segment_index: RwLock<u64>,
+    // Atomic rather than RwLock, since it's bumped from the audio callback thread
+    xruns: Arc<AtomicU64>,
+    split_requested: RwLock<bool>,
+    /// What to exit with once recording stops, if not 0
+    exit_code: AtomicI32,
+    attest_key: Option<ed25519_dalek::SigningKey>,
+    encrypt_to: Vec<age::x25519::Recipient>,
 }
 
 // impl Debug for ProgramState {
@@ -199,6 +1013,13 @@ impl ProgramState {
             display: RwLock::new(display),
             interactive: RwLock::new(interactive),
             path_dir: Default::default(),
+            session_id: segment_meta::new_session_id(),
+            segment_index: RwLock::new(0),
+            xruns: Arc::new(AtomicU64::new(0)),
+            split_requested: RwLock::new(false),
+            exit_code: AtomicI32::new(0),
+            attest_key: None,
+            encrypt_to: Vec::new(),
         }
     }
 
@@ -218,30 +1039,15 @@ impl ProgramState {
 async fn get_device_list(state: &ProgramState) -> Result<Vec<String>, Box<dyn Error>> {
     let mut out = Vec::new();
     for device in state.cpal_host.read().await.input_devices()? {
-        match device.name() {
-            Ok(name) => out.push(name),
-            Err(_) => (),
+        if let Ok(name) = device.name() {
+            out.push(name);
         }
     }
     Ok(out)
 }
 
-fn streamgen_gen_file_path<'a>(
-    rec: &'a Rec,
-    path_dir: PathBuf,
-) -> impl Stream<Item = PathBuf> + 'a {
-    fn_stream(|emitter| async move {
-        let now: DateTime<Local> = Local::now();
-        let timestamp_string = now.format(rec.time_format.as_str());
-        let mut recording_path = path_dir;
-        let basename = format!("{}__{}", rec.name_prefix, timestamp_string.to_string());
-        recording_path.push(basename);
-        emitter.emit(recording_path).await;
-    })
-}
-
 async fn display_probe_info_if_requested(state: &ProgramState) -> Result<bool, Box<dyn Error>> {
-    match state
+    if let ProbeOpts::InputDevices = state
         .cli
         .read()
         .await
@@ -250,16 +1056,10 @@ async fn display_probe_info_if_requested(state: &ProgramState) -> Result<bool, B
         .ok_or("Probe command not selected")?
         .type_
     {
-        ProbeOpts::InputDevices => {
-            match get_device_list(&state).await {
-                Ok(list) => {
-                    printrn!("{:#?}", list);
-                }
-                Err(_) => (),
-            }
-            return Ok(true);
+        if let Ok(list) = get_device_list(state).await {
+            printrn!("{:#?}", list);
         }
-        _ => {}
+        return Ok(true);
     }
     Ok(false)
 }
@@ -282,36 +1082,326 @@ async fn wait_between_errors(state: Arc<ProgramState>, err: Box<dyn Error>) {
     skippable_sleep(Duration::from_secs(wait_time), state.clone()).await;
 }
 
+fn resolve_path_dir(path_dir: &Option<PathBuf>) -> PathBuf {
+    match path_dir {
+        None => {
+            let mut path = dirs::home_dir().expect("Failed to determine home directory D:");
+            path.push("Audio");
+            path.push("akasha");
+            printrn!("Default path auto-detected: {}", path.to_string_lossy());
+            path
+        }
+        Some(path) => {
+            info!("Path set by user: {}", path.to_string_lossy());
+            path.to_owned()
+        }
+    }
+}
+
+fn run_index(index: &Index) -> Result<(), Box<dyn Error>> {
+    let path_dir = resolve_path_dir(&index.path_dir);
+    let identities = encrypt::Identities::load(&index.identity.identities)?;
+    let entries = catalog::rebuild(&path_dir, &index.naming.name_prefix, &index.naming.name_template()?, index.naming.time_zone, &identities)?;
+    let hours: f64 = entries.iter().map(|entry| entry.duration_secs).sum::<f64>() / 3600.;
+    println!(
+        "Indexed {} segments ({:.2} hours) into {}",
+        entries.len(),
+        hours,
+        catalog::catalog_path(&path_dir).display()
+    );
+    let partial = catalog::find_partial_files(&path_dir)?;
+    for path in &partial {
+        println!("{}: unfinished, unless it's still being recorded; not indexed", path.display());
+    }
+    Ok(())
+}
+
+fn run_list(list: &List) -> Result<(), Box<dyn Error>> {
+    let path_dir = resolve_path_dir(&list.path_dir);
+    let device = list.device.as_ref().map(|device| device.to_lowercase());
+    let identities = encrypt::Identities::load(&list.identity.identities)?;
+    let entries: Vec<_> = catalog::load(&path_dir, &list.naming.name_prefix, &list.naming.name_template()?, list.naming.time_zone, &identities)?
+        .into_iter()
+        .filter(|entry| match (list.since, entry.end_time()) {
+            (Some(since), Some(end)) => end >= since,
+            _ => true,
+        })
+        .filter(|entry| match (list.until, entry.start_time()) {
+            (Some(until), Some(start)) => start < until,
+            _ => true,
+        })
+        .filter(|entry| match (&device, &entry.device) {
+            (Some(wanted), Some(device)) => device.to_lowercase().contains(wanted),
+            (Some(_), None) => false,
+            (None, _) => true,
+        })
+        .collect();
+    let gaps = archive::find_gaps(&entries);
+    let total_hours = archive::recorded_secs(&entries) / 3600.;
+
+    if list.json {
+        let listing = serde_json::json!({
+            "segments": entries,
+            "gaps": gaps,
+            "total_hours": total_hours,
+        });
+        println!("{}", serde_json::to_string_pretty(&listing)?);
+        return Ok(());
+    }
+    let mut gaps_iter = gaps.iter().peekable();
+    for (i, entry) in entries.iter().enumerate() {
+        while let Some(gap) = gaps_iter.next_if(|gap| gap.before == i) {
+            println!("{:>29}  -- gap of {} --", "", archive::format_duration(gap.duration_secs));
+        }
+        println!(
+            "{:<29}  {:>11}  {:>10}  {:<4}  {}",
+            archive::format_stored_time(&entry.start),
+            archive::format_duration(entry.duration_secs),
+            archive::format_size(entry.size),
+            entry.format,
+            entry.path
+        );
+    }
+    let gap_secs: f64 = gaps.iter().map(|gap| gap.duration_secs).sum();
+    println!(
+        "{} segments, {:.2} hours recorded, {} gaps totalling {}",
+        entries.len(),
+        total_hours,
+        gaps.len(),
+        archive::format_duration(gap_secs)
+    );
+    Ok(())
+}
+
+/// Prints each segment file covering `find.at` with the offset into it in seconds, tab-separated.
+/// Exits with status 1 if nothing was being recorded at the time.
+fn run_find(find: &Find) -> Result<(), Box<dyn Error>> {
+    let path_dir = resolve_path_dir(&find.path_dir);
+    let identities = encrypt::Identities::load(&find.identity.identities)?;
+    let entries = catalog::load(&path_dir, &find.naming.name_prefix, &find.naming.name_template()?, find.naming.time_zone, &identities)?;
+    let found = archive::segments_at(&entries, find.at);
+    for (entry, offset) in &found {
+        println!("{}\t{:.3}", path_dir.join(&entry.path).display(), offset);
+    }
+    if found.is_empty() {
+        let at = archive::format_time(&find.at);
+        match archive::gap_around(&entries, find.at) {
+            (Some(before), Some(after)) => eprintln!(
+                "Nothing was recorded at {}: it falls in a gap from {} to {}",
+                at,
+                archive::format_time(&before),
+                archive::format_time(&after)
+            ),
+            (Some(before), None) => eprintln!(
+                "Nothing was recorded at {}: the last segment ended at {}",
+                at,
+                archive::format_time(&before)
+            ),
+            (None, Some(after)) => eprintln!(
+                "Nothing was recorded at {}: the first segment starts at {}",
+                at,
+                archive::format_time(&after)
+            ),
+            (None, None) => eprintln!("No segments found in {}", path_dir.display()),
+        }
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn run_export(export: &Export) -> Result<(), Box<dyn Error>> {
+    if export.from >= export.to {
+        return Err("--from must come before --to".into());
+    }
+    let format = match export.format {
+        Some(format) => format,
+        None => {
+            let extension = export
+                .output
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .ok_or("Give --format, or an --output file name with an extension")?;
+            FormatSelect::from_str(extension, true)
+                .map_err(|_| format!("Can't tell the format from `.{}`; give --format", extension))?
+        }
+    };
+    let path_dir = resolve_path_dir(&export.path_dir);
+    let identities = encrypt::Identities::load(&export.identity.identities)?;
+    let entries = catalog::load(&path_dir, &export.naming.name_prefix, &export.naming.name_template()?, export.naming.time_zone, &identities)?;
+    let sources = export::select_sources(&entries, export.from, export.to, export.source_format);
+    let expected_dur = (export.to - export.from).to_std().unwrap_or_default();
+    let writer = write_audio::writer_for(
+        format,
+        &export.wav,
+        &export.ogg,
+        &export.flac,
+        &export.opus,
+        expected_dur,
+    );
+    let summary = export::export(
+        &path_dir,
+        &sources,
+        export.from,
+        export.to,
+        export.gaps,
+        writer,
+        &export.output,
+        &export.tags,
+        &identities,
+    )?;
+    println!(
+        "Exported {} to {} from {} segments into {} ({})",
+        archive::format_time(&summary.start),
+        archive::format_time(&summary.end),
+        summary.sources,
+        summary.path.display(),
+        archive::format_duration(summary.frames as f64 / summary.sample_rate as f64)
+    );
+    for gap in &summary.gaps {
+        println!(
+            "  gap of {} from {} to {}",
+            archive::format_duration(gap.duration_secs),
+            archive::format_stored_time(&gap.start),
+            archive::format_stored_time(&gap.end)
+        );
+    }
+    Ok(())
+}
+
+/// Reports every missing, corrupted or truncated segment, exiting with status 1 if there were any.
+fn run_verify(verify: &Verify) -> Result<(), Box<dyn Error>> {
+    let path_dir = resolve_path_dir(&verify.path_dir);
+    let identities = encrypt::Identities::load(&verify.identity.identities)?;
+    let report = verify::verify(&path_dir, &identities)?;
+    for (path, problems) in &report.problems {
+        for problem in problems {
+            println!("{}: {}", path, problem);
+        }
+    }
+    for path in &report.unlisted {
+        println!("{}: not in the manifest", path);
+    }
+    for path in &report.partial {
+        println!("{}: unfinished, unless it's still being recorded", path);
+    }
+    println!(
+        "Checked {} segments: {} ok, {} with problems, {} not in the manifest, {} unfinished",
+        report.checked,
+        report.checked - report.problems.len(),
+        report.problems.len(),
+        report.unlisted.len(),
+        report.partial.len()
+    );
+    if report.hash_only > 0 {
+        println!(
+            "{} encrypted segments were only checked against their hashes; give --identity to decode them too",
+            report.hash_only
+        );
+    }
+    if !report.problems.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Reports each point where the chain breaks, exiting with status 1 if there were any.
+fn run_attest_verify(verify: &AttestVerify) -> Result<(), Box<dyn Error>> {
+    let path_dir = resolve_path_dir(&verify.path_dir);
+    let trusted_key = verify.public_key.as_deref().map(attest::parse_public_key).transpose()?;
+    let report = attest::verify(&path_dir, trusted_key.as_ref())?;
+    for gap in &report.breaks {
+        match &gap.path {
+            Some(path) => println!("Chain broken at link {} ({}): {}", gap.link, path, gap.reason),
+            None => println!("Chain broken at link {}: {}", gap.link, gap.reason),
+        }
+    }
+    for path in &report.unlisted {
+        println!("{}: not in the chain", path);
+    }
+    println!(
+        "Checked {} links signed by {}: {}",
+        report.links,
+        report.public_key.as_deref().unwrap_or("nobody"),
+        if report.breaks.is_empty() { "intact" } else { "BROKEN" }
+    );
+    if let Some(head) = &report.head {
+        println!("Chain head: {}", head);
+    }
+    if !report.breaks.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Decrypts each file next to itself (or into `--output-dir`), minus the `.age`. Whatever can be
+/// recovered from a `.partial` file is kept, with a warning; anything else that fails to decrypt is an error.
+fn run_decrypt(decrypt: &Decrypt) -> Result<(), Box<dyn Error>> {
+    let identities = encrypt::Identities::load(&decrypt.identity.identities)?;
+    if identities.is_empty() {
+        return Err("Give the --identity to decrypt with".into());
+    }
+    let mut failed = false;
+    for path in &decrypt.files {
+        let is_partial = path.extension() == Some(std::ffi::OsStr::new(segment_file::PARTIAL_EXTENSION));
+        let encrypted = if is_partial { path.with_extension("") } else { path.clone() };
+        let plain = encrypt::plain_path(&encrypted);
+        if plain == encrypted {
+            eprintln!("{}: not a .age file", path.display());
+            failed = true;
+            continue;
+        }
+        let target = match &decrypt.output_dir {
+            Some(dir) => dir.join(plain.file_name().unwrap_or_default()),
+            None => plain,
+        };
+        match encrypt::decrypt_to(path, &target, &identities) {
+            Ok((written, decrypted)) => match decrypted.cut_off {
+                None => println!("{} -> {}", path.display(), written.display()),
+                Some(reason) if is_partial => println!(
+                    "{} -> {} (recovered {}; the rest was lost when recording stopped: {})",
+                    path.display(),
+                    written.display(),
+                    archive::format_size(decrypted.bytes),
+                    reason
+                ),
+                Some(reason) => {
+                    eprintln!(
+                        "{}: damaged after {}: {}; what decrypted is in {}",
+                        path.display(),
+                        archive::format_size(decrypted.bytes),
+                        reason,
+                        written.display()
+                    );
+                    failed = true;
+                }
+            },
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
 async fn main_task(state: Arc<ProgramState>) {
     let args = state.cli.read().await;
     if let Some(rec) = args.cmd.as_rec() {
-        *state.path_dir.write().await = match &rec.path_dir {
-            None => {
-                let mut path = dirs::home_dir().expect("Failed to determine home directory D:");
-                path.push("Audio");
-                path.push("akasha");
-                printrn!("Default path auto-detected: {}", path.to_string_lossy());
-                PathBuf::from(path)
-            }
-            Some(path) => {
-                info!("Path set by user: {}", path.to_string_lossy());
-                path.to_owned()
-            }
-        };
+        *state.path_dir.write().await = resolve_path_dir(&rec.path_dir);
 
         if !state.path_dir.read().await.exists() {
             std::fs::create_dir_all(state.path_dir.read().await.to_owned())
                 .expect("Failed to create path");
         };
 
-        let new_file_name_stream =
-            streamgen_gen_file_path(&rec, state.path_dir.write().await.to_owned());
-        pin_mut!(new_file_name_stream);
-
-        let result = record::record_segments(new_file_name_stream, state.clone()).await;
+        let writers = write_audio::writers_for(rec);
+        let result = record::record_segments(writers, state.clone()).await;
 
         if let Err(e) = result {
-            wait_between_errors(state.clone(), e.into()).await;
+            wait_between_errors(state.clone(), e).await;
         }
     }
 }
@@ -335,6 +1425,11 @@ async fn handle_signals(state: Arc<ProgramState>) -> Result<(), Box<dyn Error>>
                 *state.display.write().await = state_cur;
                 info!("Display state toggled to: {}", state_cur);
             }
+            if key.code == Char('s') {
+                // End the current segment early, and start a new one
+                *state.split_requested.write().await = true;
+                info!("Segment split requested");
+            }
             if key.modifiers == KeyModifiers::CONTROL {
                 if key.code == Char('c') || key.code == Char('d') {
                     state.quit_msg.send_quit().await;
@@ -349,7 +1444,7 @@ async fn handle_signals(state: Arc<ProgramState>) -> Result<(), Box<dyn Error>>
             }
 
             if key.code == Char('I') {
-                state.update_raw_mode();
+                state.update_raw_mode().await?;
             }
         }
         // Unknown event, ignore
@@ -360,31 +1455,15 @@ async fn handle_signals(state: Arc<ProgramState>) -> Result<(), Box<dyn Error>>
 
 async fn signal_thread(state: Arc<ProgramState>) {
     loop {
-        match handle_signals(state.clone()).await {
-            Err(_) => {
-                warn!("Error in signal handler function");
-            }
-            Ok(_) => {}
-        };
+        if handle_signals(state.clone()).await.is_err() {
+            warn!("Error in signal handler function");
+        }
         if !*state.interactive.read().await {
             break;
         }
     }
 }
 
-async fn update_raw_mode<'a>(state: RwLockReadGuard<'a, bool>) -> Result<(), Box<dyn Error>> {
-    match *state {
-        true => {
-            enable_raw_mode()?;
-        }
-        false => {
-            disable_raw_mode()?;
-        }
-        _ => {}
-    }
-    Ok(())
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init_from_env(
@@ -394,7 +1473,77 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let args = Cli::parse();
     debug!("Args: {:#?}", &args);
-    let state = Arc::new(ProgramState::new(args));
+    if let Some(index) = args.cmd.as_index() {
+        return run_index(index);
+    }
+    if let Some(list) = args.cmd.as_list() {
+        return run_list(list);
+    }
+    if let Some(find) = args.cmd.as_find() {
+        return run_find(find);
+    }
+    if let Some(verify) = args.cmd.as_verify() {
+        return run_verify(verify);
+    }
+    if let Some(export) = args.cmd.as_export() {
+        export.ogg.bitrate_strategy()?;
+        return run_export(export);
+    }
+    if let Some(decrypt) = args.cmd.as_decrypt() {
+        return run_decrypt(decrypt);
+    }
+    if let Some(attest) = args.cmd.as_attest() {
+        return match attest {
+            AttestCommands::Verify(verify) => run_attest_verify(verify),
+        };
+    }
+    let mut attest_key = None;
+    let mut encrypt_to = Vec::new();
+    if let Some(rec) = args.cmd.as_rec() {
+        // Catch bad encoder settings now, rather than when the first segment starts
+        rec.validate_formats()?;
+        rec.ogg.bitrate_strategy()?;
+        rec.naming.name_template()?;
+        rec.gain.validate()?;
+        rec.gate.validate()?;
+        rec.clip.validate()?;
+        rec.silence.validate()?;
+        if rec.naming.time_zone.observes_dst() {
+            // iso8601 is there to sort by time, which it can't do across the hour the clocks go back
+            if rec.naming.name_template.is_none() && rec.naming.time_format == time_zone::ISO8601_TIME_FORMAT {
+                return Err(format!(
+                    "--time-format iso8601 names in {} would sort out of order when the clocks go back for DST; \
+                     use --time-zone utc",
+                    rec.naming.time_zone
+                )
+                .into());
+            }
+            warn!(
+                "Segment names are in {}, which changes offset for DST, so when the clocks go back they \
+                 won't sort in the order they were recorded; --time-zone utc avoids that",
+                rec.naming.time_zone
+            );
+        }
+        if rec.attest {
+            let key_path = rec.attest_key.clone().unwrap_or_else(attest::default_key_path);
+            let key = attest::load_or_create_key(&key_path)?;
+            info!(
+                "Signing the segment chain with {} (public key {})",
+                key_path.display(),
+                attest::public_key_hex(&key.verifying_key())
+            );
+            attest_key = Some(key);
+        }
+        encrypt_to = encrypt::load_recipients(&rec.encrypt_to, &rec.encrypt_to_file)?;
+        if !encrypt_to.is_empty() {
+            info!("Encrypting segments to {} recipient(s)", encrypt_to.len());
+            rec.warn_if_encryption_outlasts_flush();
+        }
+    }
+    let mut state = ProgramState::new(args);
+    state.attest_key = attest_key;
+    state.encrypt_to = encrypt_to;
+    let state = Arc::new(state);
     //debug!("Starting state: {:#?}", &state);
     let state_ptr = state.clone();
 
@@ -407,14 +1556,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
         }
     }
 
-    match display_probe_info_if_requested(&state).await {
-        Ok(quit_flag) => {
-            if quit_flag {
-                disable_raw_mode()?;
-                return Ok(()); // goodbye :3
-            }
-        }
-        _ => {}
+    if let Ok(true) = display_probe_info_if_requested(&state).await {
+        disable_raw_mode()?;
+        return Ok(()); // goodbye :3
     }
 
     // #[cfg(target_family = "unix")]
@@ -462,5 +1606,96 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .await
         .expect("Error updating raw mode.");
 
+    let exit_code = state.exit_code.load(Ordering::Relaxed);
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_opts::rec_opts;
+
+    fn strategy(args: &[&str]) -> Result<VorbisBitrateStrategy, Box<dyn Error>> {
+        rec_opts(args).ogg.bitrate_strategy()
+    }
+
+    fn output(format: FormatSelect, subdir: Option<&str>) -> OutputFormat {
+        OutputFormat { format, subdir: subdir.map(PathBuf::from) }
+    }
+
+    #[test]
+    fn parses_formats_with_subdirectories() {
+        assert_eq!(parse_output_format("flac"), Ok(output(FormatSelect::Flac, None)));
+        assert_eq!(parse_output_format("OPUS:listen/low"), Ok(output(FormatSelect::Opus, Some("listen/low"))));
+        for s in ["mp3", "flac:", "flac:/archive", ":archive"] {
+            assert!(parse_output_format(s).is_err(), "{:?} was accepted", s);
+        }
+        assert_eq!(output(FormatSelect::Wav, None).dir_for(Path::new("/a")), Path::new("/a"));
+        assert_eq!(output(FormatSelect::Wav, Some("b")).dir_for(Path::new("/a")), Path::new("/a/b"));
+    }
+
+    #[test]
+    fn takes_several_formats_comma_separated_or_repeated() {
+        assert_eq!(rec_opts(&[]).formats, [output(FormatSelect::Ogg, None)]);
+        let expected = [output(FormatSelect::Flac, Some("archive")), output(FormatSelect::Opus, Some("listen"))];
+        assert_eq!(rec_opts(&["-f", "flac:archive,opus:listen"]).formats, expected);
+        assert_eq!(rec_opts(&["-f", "flac:archive", "--format", "opus:listen"]).formats, expected);
+    }
+
+    #[test]
+    fn refuses_the_same_format_twice_in_one_directory() {
+        assert!(rec_opts(&["-f", "ogg,flac,ogg:copy,flac:copy"]).validate_formats().is_ok());
+        assert!(rec_opts(&["-f", "ogg,flac,ogg"]).validate_formats().is_err());
+        assert!(rec_opts(&["-f", "wav:a,wav:a"]).validate_formats().is_err());
+    }
+
+    #[test]
+    fn ogg_bitrate_modes_map_to_strategies() {
+        assert_eq!(strategy(&[]).unwrap(), VorbisBitrateStrategy::Vbr { target_bitrate: 128_000 });
+        assert_eq!(
+            strategy(&["--ogg-bitrate", "96"]).unwrap(),
+            VorbisBitrateStrategy::Vbr { target_bitrate: 96_000 }
+        );
+        assert_eq!(strategy(&["-q", "5"]).unwrap(), VorbisBitrateStrategy::QualityVbr { quality: 0.5 });
+        assert_eq!(
+            strategy(&["--ogg-bitrate-mode", "quality", "-q", "-1"]).unwrap(),
+            VorbisBitrateStrategy::QualityVbr { quality: -0.1 }
+        );
+        assert_eq!(
+            strategy(&["--ogg-bitrate-mode", "abr", "--ogg-bitrate", "64"]).unwrap(),
+            VorbisBitrateStrategy::Abr { average_bitrate: 64_000 }
+        );
+        assert_eq!(
+            strategy(&["--ogg-bitrate-mode", "constrained"]).unwrap(),
+            VorbisBitrateStrategy::ConstrainedAbr { maximum_bitrate: 128_000 }
+        );
+        assert_eq!(
+            strategy(&["--ogg-bitrate-mode", "constrained", "--ogg-bitrate", "96", "--ogg-max-bitrate", "160"]).unwrap(),
+            VorbisBitrateStrategy::ConstrainedAbr { maximum_bitrate: 160_000 }
+        );
+        assert_eq!(
+            strategy(&["--ogg-bitrate-mode", "managed", "--ogg-min-bitrate", "32", "--ogg-max-bitrate", "192"]).unwrap(),
+            VorbisBitrateStrategy::Managed {
+                minimum_bitrate: Some(32_000),
+                average_bitrate: None,
+                maximum_bitrate: Some(192_000),
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_ogg_options_that_dont_go_with_the_mode() {
+        for args in [
+            &["--ogg-bitrate-mode", "abr", "-q", "5"][..],
+            &["--ogg-bitrate-mode", "quality"],
+            &["--ogg-min-bitrate", "32"],
+            &["--ogg-bitrate-mode", "managed"],
+            &["--ogg-bitrate-mode", "managed", "--ogg-bitrate", "128"],
+        ] {
+            assert!(strategy(args).is_err(), "{:?} was accepted", args);
+        }
+    }
+}
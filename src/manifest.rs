@@ -0,0 +1,119 @@
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+/// Lives next to the catalog. Same layout as `sha256sum` output, so `sha256sum -c` can check it too.
+pub const MANIFEST_FILE_NAME: &str = "akasha-manifest.sha256";
+
+/// The hash a segment had when it was finalized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub sha256: String,
+    /// Relative to the manifest's directory
+    pub path: String,
+}
+
+pub fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST_FILE_NAME)
+}
+
+/// Adds one segment to the end of the manifest in `dir`.
+pub fn append(dir: &Path, entry: &ManifestEntry) -> Result<(), Box<dyn Error>> {
+    if entry.path.contains('\n') {
+        return Err(format!("Can't list `{}` in the manifest", entry.path.escape_debug()).into());
+    }
+    let mut f = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(manifest_path(dir))?;
+    f.write_all(format!("{}  {}\n", entry.sha256, entry.path).as_bytes())?;
+    f.sync_data()?;
+    Ok(())
+}
+
+/// Reads the manifest in `dir`, if there is one. Malformed lines are skipped with a warning.
+pub fn read(dir: &Path) -> Result<Option<Vec<ManifestEntry>>, Box<dyn Error>> {
+    let f = match File::open(manifest_path(dir)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(f).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        // `sha256sum` marks binary mode with a `*` in place of the second space
+        let parsed = line
+            .split_once("  ")
+            .or_else(|| line.split_once(" *"))
+            .filter(|(sha256, _)| sha256.len() == 64 && sha256.bytes().all(|b| b.is_ascii_hexdigit()));
+        match parsed {
+            Some((sha256, path)) => entries.push(ManifestEntry {
+                sha256: sha256.to_ascii_lowercase(),
+                path: path.to_owned(),
+            }),
+            None => warn!("Skipping bad manifest line {}", i + 1),
+        }
+    }
+    Ok(Some(entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn entry(sha256: &str, path: &str) -> ManifestEntry {
+        ManifestEntry {
+            sha256: sha256.to_owned(),
+            path: path.to_owned(),
+        }
+    }
+
+    #[test]
+    fn no_manifest_is_none() {
+        let dir = TestDir::new("manifest-none");
+        assert_eq!(read(dir.path()).unwrap(), None);
+    }
+
+    #[test]
+    fn reads_back_what_was_appended() {
+        let dir = TestDir::new("manifest-append");
+        let entries = [entry(&"ab".repeat(32), "a.ogg"), entry(&"cd".repeat(32), "opus/b c.opus")];
+        for entry in &entries {
+            append(dir.path(), entry).unwrap();
+        }
+        assert_eq!(read(dir.path()).unwrap().unwrap(), entries);
+    }
+
+    #[test]
+    fn reads_sha256sum_output_and_skips_bad_lines() {
+        let dir = TestDir::new("manifest-sha256sum");
+        let hash = "AB".repeat(32);
+        let contents = format!(
+            "{hash}  a.ogg\n\n{hash} *b.flac\nnot a hash  c.wav\n{short}  d.wav\n",
+            hash = hash,
+            short = &hash[..10]
+        );
+        fs::write(manifest_path(dir.path()), contents).unwrap();
+        let lower = hash.to_ascii_lowercase();
+        assert_eq!(
+            read(dir.path()).unwrap().unwrap(),
+            [entry(&lower, "a.ogg"), entry(&lower, "b.flac")]
+        );
+    }
+
+    #[test]
+    fn refuses_paths_with_newlines() {
+        let dir = TestDir::new("manifest-newline");
+        assert!(append(dir.path(), &entry(&"ab".repeat(32), "a\nb.ogg")).is_err());
+        assert_eq!(read(dir.path()).unwrap(), None);
+    }
+}
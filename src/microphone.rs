@@ -3,10 +3,9 @@ use crate::{Chunk, ProgramState};
 use cpal::traits::DeviceTrait;
 use cpal::traits::StreamTrait;
 use std::sync::{Arc, mpsc};
+use std::sync::atomic::Ordering;
 use async_fn_stream::{fn_stream};
-use log::debug;
-
-use crate::printrn;
+use log::{debug, warn};
 
 
 // TODO: genericafy ProgramState so that this function can be used in other programs
@@ -18,11 +17,16 @@ pub fn getstream_mic_input(
         let state = state.clone();
         // TODO: remove MPSC channel once async-fn-stream supports working across runtimes.
         let (tx, rx) = mpsc::channel::<Chunk>();
+        let xruns = state.xruns.clone();
 
         let input_stream = cpal::Device::build_input_stream(
             &input_device, &config,  move |data: &[f32], _: &cpal::InputCallbackInfo| {
             tx.send(data.to_vec()).unwrap();
-        }, move |_err| {}).expect("Failed to make stream :(");
+        }, move |err| {
+            // cpal reports overruns and the like here; the audio just has a hole in it
+            xruns.fetch_add(1, Ordering::Relaxed);
+            warn!("Audio input stream error: {}", err);
+        }).expect("Failed to make stream :(");
 
         input_stream.play().expect("Failed to play stream");
 
@@ -0,0 +1,349 @@
+use std::path::{Component, Path, PathBuf};
+
+use chrono::format::{self, Item, Parsed, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Utc};
+
+use crate::time_zone::NameTimeZone;
+
+/// What a segment's name can be built from.
+pub struct NameFields<'a> {
+    pub time: DateTime<Utc>,
+    /// What `time` is written in
+    pub time_zone: NameTimeZone,
+    pub prefix: &'a str,
+    pub device: &'a str,
+    pub hostname: &'a str,
+    pub session_id: &'a str,
+    pub index: u64,
+    /// File extension of the format being written, e.g. `ogg`
+    pub format: &'a str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Prefix,
+    Device,
+    Hostname,
+    Session,
+    Index,
+    Format,
+}
+
+impl Field {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "prefix" => Field::Prefix,
+            "device" => Field::Device,
+            "hostname" => Field::Hostname,
+            "session" => Field::Session,
+            "index" => Field::Index,
+            "format" => Field::Format,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    /// Literal text and strftime specifiers, for chrono to fill in
+    Time(String),
+    /// `{index:05}`: at least `width` digits, zero-padded
+    Field { field: Field, width: usize },
+}
+
+/// Where each segment goes under the recording directory, e.g. `{device}/%Y/%m/%d/{prefix}_{index:05}_%H%M%S`:
+/// placeholders in braces, chrono's strftime specifiers everywhere else, and `/` for subdirectories.
+/// The format's extension is added after.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+/// Parses and checks a template for `--name-template`, so a bad one is caught before recording starts.
+pub fn parse(s: &str) -> Result<NameTemplate, String> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut placeholder = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    placeholder.push(c);
+                }
+                if !closed {
+                    return Err(format!("Unclosed `{{{}`; write `{{{{` for a literal brace", placeholder));
+                }
+                let (name, spec) = match placeholder.split_once(':') {
+                    Some((name, spec)) => (name, Some(spec)),
+                    None => (placeholder.as_str(), None),
+                };
+                let field = Field::from_name(name).ok_or_else(|| {
+                    format!(
+                        "Unknown placeholder `{{{}}}`; use {{prefix}}, {{device}}, {{hostname}}, {{session}}, \
+                         {{index}} or {{format}}",
+                        placeholder
+                    )
+                })?;
+                let width = match spec {
+                    None => 0,
+                    Some(spec) if field == Field::Index => spec
+                        .strip_prefix('0')
+                        .unwrap_or(spec)
+                        .parse()
+                        .map_err(|_| format!("`{}` is not a width, as in {{index:05}}", spec))?,
+                    Some(_) => return Err(format!("Only {{index}} takes a width, not `{{{}}}`", placeholder)),
+                };
+                if !text.is_empty() {
+                    parts.push(Part::Time(std::mem::take(&mut text)));
+                }
+                parts.push(Part::Field { field, width });
+            }
+            '}' => return Err("Unmatched `}`; write `}}` for a literal brace".to_owned()),
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        parts.push(Part::Time(text));
+    }
+    for part in &parts {
+        if let Part::Time(text) = part {
+            if StrftimeItems::new(text).any(|item| item == Item::Error) {
+                return Err(format!("`{}` is not a valid strftime format", text));
+            }
+        }
+    }
+
+    let template = NameTemplate { parts };
+    let example = template.render(&NameFields {
+        time: Utc::now(),
+        time_zone: NameTimeZone::Utc,
+        prefix: "akasha",
+        device: "device",
+        hostname: "host",
+        session_id: "session",
+        index: 1,
+        format: "ogg",
+    });
+    let is_relative = example
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if !is_relative || s.ends_with('/') || example.file_name().is_none() {
+        return Err(format!(
+            "`{}` must name a file inside the recording directory, e.g. `{}`",
+            s, "{device}/%Y/%m/%d/{prefix}_{index:05}_%H%M%S"
+        ));
+    }
+    Ok(template)
+}
+
+impl NameTemplate {
+    /// The template `--name-prefix` and `--time-format` have always stood for: `<prefix>__<time_format>`.
+    pub fn from_time_format(time_format: &str) -> Result<Self, String> {
+        parse(&format!("{{prefix}}__{}", time_format.replace('{', "{{").replace('}', "}}")))
+    }
+
+    /// The segment's path relative to the recording directory, without an extension.
+    pub fn render(&self, fields: &NameFields) -> PathBuf {
+        let mut rendered = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Time(text) => rendered.push_str(&fields.time_zone.format(&fields.time, text)),
+                Part::Field { field: Field::Index, width } => {
+                    rendered.push_str(&format!("{:0width$}", fields.index, width = *width))
+                }
+                Part::Field { field, .. } => {
+                    let value = match field {
+                        Field::Prefix => fields.prefix,
+                        Field::Device => fields.device,
+                        Field::Hostname => fields.hostname,
+                        Field::Session => fields.session_id,
+                        Field::Format => fields.format,
+                        Field::Index => unreachable!(),
+                    };
+                    rendered.push_str(&path_safe(value));
+                }
+            }
+        }
+        Path::new(&rendered).to_path_buf()
+    }
+
+    /// Works back from a name `render` made, without its extension, to the time it was made for.
+    /// `{prefix}` has to be `prefix`; the other placeholders stand for anything within one directory.
+    /// Times without an offset are taken to be in `time_zone`.
+    pub fn parse_start(&self, name: &str, prefix: &str, time_zone: NameTimeZone) -> Option<DateTime<FixedOffset>> {
+        match_parts(&self.parts, name, &path_safe(prefix), time_zone, Parsed::new())
+    }
+}
+
+/// Matches `name` against `parts`, trying every split the placeholders allow until one parses all the way
+/// through to a real time.
+fn match_parts(
+    parts: &[Part],
+    name: &str,
+    prefix: &str,
+    time_zone: NameTimeZone,
+    parsed: Parsed,
+) -> Option<DateTime<FixedOffset>> {
+    let Some((part, rest)) = parts.split_first() else {
+        if !name.is_empty() {
+            return None;
+        }
+        // Fields are only range-checked here, so e.g. a month of 50 fails now rather than while parsing
+        return match parsed.offset {
+            Some(_) => parsed.to_datetime().ok(),
+            None => time_zone.read_naive(&parsed.to_naive_datetime_with_offset(0).ok()?),
+        };
+    };
+    match part {
+        Part::Time(text) => {
+            let mut parsed = parsed;
+            let name = format::parse_and_remainder(&mut parsed, name, StrftimeItems::new(text)).ok()?;
+            match_parts(rest, name, prefix, time_zone, parsed)
+        }
+        Part::Field { field: Field::Prefix, .. } => match_parts(rest, name.strip_prefix(prefix)?, prefix, time_zone, parsed),
+        Part::Field { field, .. } => {
+            let len = match field {
+                Field::Index => name.find(|c: char| !c.is_ascii_digit()).unwrap_or(name.len()),
+                _ => name.find('/').unwrap_or(name.len()),
+            };
+            // Longest first for an index, which is usually followed by a separator anyway
+            let mut ends: Vec<usize> = (1..=len).filter(|&end| name.is_char_boundary(end)).collect();
+            if *field == Field::Index {
+                ends.reverse();
+            }
+            ends.into_iter()
+                .find_map(|end| match_parts(rest, &name[end..], prefix, time_zone, parsed.clone()))
+        }
+    }
+}
+
+/// Keeps a value from adding directories of its own, or being empty. Device names especially can be anything.
+fn path_safe(value: &str) -> String {
+    let value = value.trim();
+    if value.is_empty() || value == "." || value == ".." {
+        return "unknown".to_owned();
+    }
+    value
+        .chars()
+        .map(|c| if c == '/' || c == '\\' || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn render(template: &str, device: &str) -> String {
+        parse(template)
+            .unwrap()
+            .render(&NameFields {
+                time: Utc.with_ymd_and_hms(2024, 5, 1, 14, 37, 5).unwrap(),
+                time_zone: NameTimeZone::Utc,
+                prefix: "akasha",
+                device,
+                hostname: "host",
+                session_id: "session",
+                index: 42,
+                format: "ogg",
+            })
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn fills_in_placeholders_and_time() {
+        assert_eq!(
+            render("{device}/%Y/%m/%d/{prefix}_{index:05}_%H%M%S", "mic"),
+            "mic/2024/05/01/akasha_00042_143705"
+        );
+        assert_eq!(render("{hostname}-{session}-{index}.{format}", "mic"), "host-session-42.ogg");
+    }
+
+    #[test]
+    fn doubled_braces_are_literal() {
+        assert_eq!(render("{{{prefix}}}", "mic"), "{akasha}");
+    }
+
+    #[test]
+    fn values_cant_add_directories() {
+        assert_eq!(render("{device}/x", "hw:0/usb\tmic"), "hw:0_usb_mic/x");
+        assert_eq!(render("{device}/x", ".."), "unknown/x");
+        assert_eq!(render("{device}/x", "  "), "unknown/x");
+    }
+
+    #[test]
+    fn keeps_the_old_prefix_and_time_format_naming() {
+        let template = NameTemplate::from_time_format("%Y{%m}").unwrap();
+        assert_eq!(template, parse("{prefix}__%Y{{%m}}").unwrap());
+    }
+
+    fn start(template: &str, name: &str, time_zone: NameTimeZone) -> Option<String> {
+        parse(template)
+            .unwrap()
+            .parse_start(name, "akasha", time_zone)
+            .map(|start| start.to_rfc3339())
+    }
+
+    #[test]
+    fn reads_the_time_back_out_of_a_name() {
+        let template = "{device}/%Y/%m/%d/{prefix}_{index:05}_%H%M%S";
+        let name = render(template, "hw:0 mic");
+        assert_eq!(start(template, &name, NameTimeZone::Utc), Some("2024-05-01T14:37:05+00:00".to_owned()));
+        let berlin = NameTimeZone::Named(chrono_tz::Europe::Berlin);
+        assert_eq!(start(template, &name, berlin), Some("2024-05-01T14:37:05+02:00".to_owned()));
+        assert_eq!(
+            start("{hostname}-{index}%Y%m%dT%H%M%S%z", "my-host-720240501T143705+0100", NameTimeZone::Utc),
+            Some("2024-05-01T14:37:05+01:00".to_owned())
+        );
+    }
+
+    #[test]
+    fn names_that_dont_fit_the_template_have_no_time() {
+        let template = "{device}/%Y/%m/%d/{prefix}_{index:05}_%H%M%S";
+        for name in [
+            "mic/2024/05/01/other_00042_143705",
+            "mic/2024/05/01/akasha_00042_1437",
+            "mic/sub/2024/05/01/akasha_00042_143705",
+            "2024/05/01/akasha_00042_143705",
+            "mic/2024/05/01/akasha_00042_143705_extra",
+        ] {
+            assert_eq!(start(template, name, NameTimeZone::Utc), None, "{:?} was read", name);
+        }
+        // No date to go on at all
+        assert_eq!(start("{prefix}_%H%M%S", "akasha_143705", NameTimeZone::Utc), None);
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        for template in [
+            "{nope}",
+            "{prefix:05}",
+            "{index:x}",
+            "{prefix",
+            "prefix}",
+            "%Q",
+            "/abs/{prefix}",
+            "../{prefix}",
+            "{prefix}/",
+            "",
+        ] {
+            assert!(parse(template).is_err(), "{:?} was accepted", template);
+        }
+    }
+}
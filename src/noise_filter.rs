@@ -20,27 +20,23 @@ impl DefaultDenoise for [f32; DenoiseState::FRAME_SIZE] {
 }
 
 
+// Not wired into the recording pipeline yet
+#[allow(dead_code)]
 pub async fn getstream_noise_filter<S: Stream<Item = Chunk> + Unpin>
-(mut mic_audio_stream: S, state: Arc<ProgramState>) -> impl Stream<Item = Chunk> {
-    let denoise = std::sync::RwLock::new(DenoiseState::new());
+(mut mic_audio_stream: S, _state: Arc<ProgramState>) -> impl Stream<Item = Chunk> {
+    let mut denoise = DenoiseState::new();
     let mut frame_output: DenoiseChunk = DefaultDenoise::default();
-    let mut frame_input: DenoiseChunk = DefaultDenoise::default();
     let mut buf: Chunk = Vec::new();
-    let mut buf_remainder: Chunk = Vec::new();
     fn_stream(|emitter| async move {
-        'outer: loop {
-            if let Some(mut chunk) = mic_audio_stream.next().await {
-                buf.extend(chunk);
-                if buf.len() >= DenoiseState::FRAME_SIZE {
-                    buf_remainder = buf.split_off(DenoiseState::FRAME_SIZE);
-                    frame_input = DenoiseChunk::try_from(buf.as_slice().clone()).unwrap();
-
-                } else {
-
-
-                }
+        while let Some(chunk) = mic_audio_stream.next().await {
+            buf.extend(chunk);
+            while buf.len() >= DenoiseState::FRAME_SIZE {
+                let buf_remainder = buf.split_off(DenoiseState::FRAME_SIZE);
+                let frame_input = DenoiseChunk::try_from(buf.as_slice()).unwrap();
+                denoise.process_frame(&mut frame_output, &frame_input);
+                buf = buf_remainder;
+                emitter.emit(frame_output.to_vec()).await;
             }
         }
-
     })
-}
\ No newline at end of file
+}
@@ -0,0 +1,125 @@
+use std::collections::VecDeque;
+use std::error::Error;
+use std::io::{self, Read};
+
+// CRC-32 with polynomial 0x04c11db7, unreflected, as Ogg page checksums use
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc_update(crc: u32, data: &[u8]) -> u32 {
+    data.iter()
+        .fold(crc, |crc, b| (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ b) as usize])
+}
+
+/// One packet out of an Ogg stream.
+#[derive(Debug, Clone)]
+pub struct OggPacket {
+    pub data: Vec<u8>,
+    /// Granule position of the page this packet ends on, if it's the last packet to end there
+    pub granule: Option<u64>,
+    pub is_last: bool,
+}
+
+/// Pulls packets back out of a single, unmultiplexed Ogg stream such as the ones `OggStream` writes.
+/// Pure Rust, since reading the framing is much simpler than writing it.
+pub struct OggPacketReader<R: Read> {
+    source: R,
+    ready: VecDeque<OggPacket>,
+    // Packet continued onto the next page
+    partial: Vec<u8>,
+    done: bool,
+}
+
+impl<R: Read> OggPacketReader<R> {
+    pub fn new(source: R) -> Self {
+        Self {
+            source,
+            ready: VecDeque::new(),
+            partial: Vec::new(),
+            done: false,
+        }
+    }
+
+    pub fn next_packet(&mut self) -> Result<Option<OggPacket>, Box<dyn Error>> {
+        while self.ready.is_empty() && !self.done {
+            self.read_page()?;
+        }
+        Ok(self.ready.pop_front())
+    }
+
+    fn read_page(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut header = [0; 27];
+        match self.source.read_exact(&mut header) {
+            Ok(()) => {}
+            // A stream cut off mid-page by a crash still has everything before it
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                self.done = true;
+                return Ok(());
+            }
+            Err(e) => return Err(e.into()),
+        }
+        if &header[..4] != b"OggS" {
+            return Err("Lost sync reading Ogg page".into());
+        }
+        let header_type = header[5];
+        let granule = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let mut lacing = vec![0; header[26] as usize];
+        let mut body = Vec::new();
+        let complete = self.source.read_exact(&mut lacing).is_ok() && {
+            body.resize(lacing.iter().map(|l| *l as usize).sum(), 0);
+            self.source.read_exact(&mut body).is_ok()
+        };
+        if !complete {
+            self.done = true;
+            return Ok(());
+        }
+        let stored_crc = u32::from_le_bytes(header[22..26].try_into().unwrap());
+        header[22..26].fill(0);
+        let crc = crc_update(crc_update(crc_update(0, &header), &lacing), &body);
+        if crc != stored_crc {
+            let sequence = u32::from_le_bytes(header[18..22].try_into().unwrap());
+            return Err(format!("Ogg page {} fails its checksum", sequence).into());
+        }
+        // A continued packet whose start we never saw is no use to anyone
+        if header_type & 0x01 == 0 {
+            self.partial.clear();
+        }
+        let mut offset = 0;
+        let mut ended = Vec::new();
+        for len in lacing {
+            self.partial.extend(&body[offset..offset + len as usize]);
+            offset += len as usize;
+            // A lacing value under 255 ends the packet
+            if len < 255 {
+                ended.push(std::mem::take(&mut self.partial));
+            }
+        }
+        let is_last_page = header_type & 0x04 != 0;
+        let count = ended.len();
+        for (i, data) in ended.into_iter().enumerate() {
+            let is_last_on_page = i + 1 == count;
+            self.ready.push_back(OggPacket {
+                data,
+                granule: (is_last_on_page && granule != u64::MAX).then_some(granule),
+                is_last: is_last_page && is_last_on_page,
+            });
+        }
+        if is_last_page {
+            self.done = true;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::io::Write;
+use std::mem::MaybeUninit;
+use std::os::raw::c_int;
+use std::slice;
+
+use ogg_next_sys::{
+    ogg_packet, ogg_page, ogg_stream_clear, ogg_stream_flush, ogg_stream_init,
+    ogg_stream_packetin, ogg_stream_pageout, ogg_stream_pageout_fill, ogg_stream_state,
+};
+
+/// Thin wrapper around a libogg stream, so that we get to decide when pages hit the disk.
+pub struct OggStream {
+    // Boxed so libogg never sees the state move around under it
+    state: Box<ogg_stream_state>,
+}
+
+impl OggStream {
+    pub fn new(serial: i32) -> Result<Self, Box<dyn Error>> {
+        let mut state = Box::new(MaybeUninit::<ogg_stream_state>::uninit());
+        if unsafe { ogg_stream_init(state.as_mut_ptr(), serial) } != 0 {
+            return Err("ogg_stream_init failed".into());
+        }
+        // SAFETY: ogg_stream_init succeeded, so the state is initialized
+        let state = unsafe { Box::from_raw(Box::into_raw(state) as *mut ogg_stream_state) };
+        Ok(Self { state })
+    }
+
+    /// Serial numbers should differ between chained/multiplexed streams, including ones
+    /// opened at the same moment, so pick one at random rather than hard-coding it.
+    pub fn new_with_random_serial() -> Result<Self, Box<dyn Error>> {
+        Self::new(fastrand::i32(..))
+    }
+
+    /// # Safety
+    /// `packet` must point at valid packet data for the duration of the call.
+    pub unsafe fn packet_in(&mut self, packet: &mut ogg_packet) -> Result<(), Box<dyn Error>> {
+        if ogg_stream_packetin(&mut *self.state, packet) != 0 {
+            return Err("ogg_stream_packetin failed".into());
+        }
+        Ok(())
+    }
+
+    /// Write out any pages libogg considers complete. With `minimum_page_data_size` set,
+    /// pages are held back until they carry at least that many bytes of packet data.
+    pub fn write_pending_pages<W: Write>(
+        &mut self,
+        sink: &mut W,
+        minimum_page_data_size: Option<u16>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut page = MaybeUninit::<ogg_page>::uninit();
+        loop {
+            let have_page = unsafe {
+                match minimum_page_data_size {
+                    Some(size) => {
+                        ogg_stream_pageout_fill(&mut *self.state, page.as_mut_ptr(), size as c_int)
+                    }
+                    None => ogg_stream_pageout(&mut *self.state, page.as_mut_ptr()),
+                }
+            } != 0;
+            if !have_page {
+                break;
+            }
+            self.write_page(sink, unsafe { page.assume_init_ref() })?;
+        }
+        Ok(())
+    }
+
+    /// Force everything submitted so far out onto pages, regardless of how full they are.
+    pub fn flush<W: Write>(&mut self, sink: &mut W) -> Result<(), Box<dyn Error>> {
+        let mut page = MaybeUninit::<ogg_page>::uninit();
+        while unsafe { ogg_stream_flush(&mut *self.state, page.as_mut_ptr()) } != 0 {
+            self.write_page(sink, unsafe { page.assume_init_ref() })?;
+        }
+        sink.flush()?;
+        Ok(())
+    }
+
+    fn write_page<W: Write>(&mut self, sink: &mut W, page: &ogg_page) -> Result<(), Box<dyn Error>> {
+        // SAFETY: libogg hands us pointers into its own buffers, valid until the next call
+        let (header, body) = unsafe {
+            (
+                slice::from_raw_parts(page.header, page.header_len as usize),
+                slice::from_raw_parts(page.body, page.body_len as usize),
+            )
+        };
+        sink.write_all(header)?;
+        sink.write_all(body)?;
+        Ok(())
+    }
+}
+
+impl Drop for OggStream {
+    fn drop(&mut self) {
+        unsafe {
+            ogg_stream_clear(&mut *self.state);
+        }
+    }
+}
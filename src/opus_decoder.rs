@@ -0,0 +1,95 @@
+use std::error::Error;
+use std::io::Read;
+
+use unsafe_libopus::{
+    opus_decode_float, opus_decoder_create, opus_decoder_destroy, opus_strerror,
+    OpusDecoder as RawOpusDecoder, OPUS_OK,
+};
+
+use crate::ogg_demux::OggPacketReader;
+
+/// libopus can decode at any Opus rate, but 48 kHz is what granule positions count in.
+pub const DECODE_RATE: u32 = 48_000;
+// 120 ms, the longest an Opus packet can be
+const MAX_FRAME_SIZE: usize = 5760;
+
+/// Owns the libopus decoder state, and frees it on drop.
+struct RawDecoder(*mut RawOpusDecoder);
+
+impl Drop for RawDecoder {
+    fn drop(&mut self) {
+        unsafe { opus_decoder_destroy(self.0) }
+    }
+}
+
+/// Ogg Opus decoder, producing interleaved f32 chunks at 48 kHz with the encoder's pre-skip removed.
+pub struct OpusDecoder<R: Read> {
+    decoder: RawDecoder,
+    packets: OggPacketReader<R>,
+    channels: usize,
+    // Samples per channel still to drop from the start of the stream
+    pre_skip: usize,
+    pcm: Vec<f32>,
+}
+
+impl<R: Read> OpusDecoder<R> {
+    pub fn new(source: R) -> Result<Self, Box<dyn Error>> {
+        let mut packets = OggPacketReader::new(source);
+        let head = packets.next_packet()?.ok_or("Empty Ogg stream")?.data;
+        if head.len() < 19 || !head.starts_with(b"OpusHead") {
+            return Err("Ogg stream doesn't start with an OpusHead packet".into());
+        }
+        let channels = head[9] as usize;
+        if head[18] != 0 {
+            return Err("Only mono and stereo Opus streams can be decoded".into());
+        }
+        let pre_skip = u16::from_le_bytes([head[10], head[11]]) as usize;
+        // OpusTags
+        packets.next_packet()?;
+
+        let mut error = 0;
+        let decoder = unsafe { opus_decoder_create(DECODE_RATE as i32, channels as i32, &mut error) };
+        if error != OPUS_OK {
+            return Err(format!("opus_decoder_create failed: {}", opus_strerror(error)).into());
+        }
+        Ok(Self {
+            decoder: RawDecoder(decoder),
+            packets,
+            channels,
+            pre_skip,
+            pcm: vec![0.; MAX_FRAME_SIZE * channels],
+        })
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    /// Decodes the next packet that yields audio. `None` at the end of the stream.
+    pub fn decode_chunk(&mut self) -> Result<Option<Vec<f32>>, Box<dyn Error>> {
+        loop {
+            let Some(packet) = self.packets.next_packet()? else {
+                return Ok(None);
+            };
+            let frames = unsafe {
+                opus_decode_float(
+                    self.decoder.0,
+                    packet.data.as_ptr(),
+                    packet.data.len() as i32,
+                    self.pcm.as_mut_ptr(),
+                    MAX_FRAME_SIZE as i32,
+                    0,
+                )
+            };
+            if frames < 0 {
+                return Err(format!("opus_decode_float failed: {}", opus_strerror(frames)).into());
+            }
+            let frames = frames as usize;
+            let skip = self.pre_skip.min(frames);
+            self.pre_skip -= skip;
+            if skip < frames {
+                return Ok(Some(self.pcm[skip * self.channels..frames * self.channels].to_vec()));
+            }
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use std::error::Error;
+use std::io::Write;
+use std::os::raw::c_long;
+
+use clap::ValueEnum;
+use ogg_next_sys::ogg_packet;
+use serde::Serialize;
+use rubato::{FftFixedIn, Resampler};
+use unsafe_libopus::{
+    opus_encode_float, opus_encoder_create, opus_encoder_ctl, opus_encoder_destroy, opus_strerror,
+    OpusEncoder as RawOpusEncoder, OPUS_APPLICATION_AUDIO, OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+    OPUS_APPLICATION_VOIP, OPUS_GET_LOOKAHEAD_REQUEST, OPUS_OK, OPUS_SET_BITRATE_REQUEST,
+};
+
+use crate::ogg_mux::OggStream;
+
+/// Ogg Opus always counts granule positions at 48 kHz, whatever rate we actually encode at.
+const GRANULE_RATE: u32 = 48_000;
+const OPUS_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+// Recommended maximum packet size, from the libopus docs
+const MAX_PACKET_SIZE: usize = 4000;
+const RESAMPLER_CHUNK_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OpusApplication {
+    /// Best for speech
+    Voip,
+    /// Best for music and other non-speech audio
+    Audio,
+    /// Lowest latency, at some cost in quality
+    LowDelay,
+}
+
+impl OpusApplication {
+    fn as_raw(self) -> i32 {
+        match self {
+            OpusApplication::Voip => OPUS_APPLICATION_VOIP,
+            OpusApplication::Audio => OPUS_APPLICATION_AUDIO,
+            OpusApplication::LowDelay => OPUS_APPLICATION_RESTRICTED_LOWDELAY,
+        }
+    }
+}
+
+fn check(ret: i32, what: &str) -> Result<(), Box<dyn Error>> {
+    if ret != OPUS_OK {
+        return Err(format!("{} failed: {}", what, opus_strerror(ret)).into());
+    }
+    Ok(())
+}
+
+/// The rate we hand to libopus: the device rate if Opus supports it, otherwise 48 kHz.
+pub fn encode_rate_for(input_rate: u32) -> u32 {
+    if OPUS_RATES.contains(&input_rate) {
+        input_rate
+    } else {
+        GRANULE_RATE
+    }
+}
+
+fn opus_head(channels: u8, pre_skip: u16, input_rate: u32) -> Vec<u8> {
+    let mut head = Vec::with_capacity(19);
+    head.extend(b"OpusHead");
+    head.push(1); // version
+    head.push(channels);
+    head.extend(pre_skip.to_le_bytes());
+    head.extend(input_rate.to_le_bytes());
+    head.extend(0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family: mono/stereo, no mapping table
+    head
+}
+
+fn opus_tags<'a>(tags: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<u8> {
+    let vendor = format!("akasha {}", env!("CARGO_PKG_VERSION"));
+    let comments: Vec<String> = tags
+        .into_iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect();
+    let mut out = Vec::new();
+    out.extend(b"OpusTags");
+    out.extend((vendor.len() as u32).to_le_bytes());
+    out.extend(vendor.as_bytes());
+    out.extend((comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        out.extend((comment.len() as u32).to_le_bytes());
+        out.extend(comment.as_bytes());
+    }
+    out
+}
+
+/// Owns the libopus encoder state, and frees it on drop.
+struct RawEncoder(*mut RawOpusEncoder);
+
+impl Drop for RawEncoder {
+    fn drop(&mut self) {
+        unsafe { opus_encoder_destroy(self.0) }
+    }
+}
+
+/// Ogg Opus encoder taking planar f32 blocks, resampling first if the device rate isn't one
+/// Opus supports.
+pub struct OpusEncoder<W: Write> {
+    encoder: RawEncoder,
+    resampler: Option<FftFixedIn<f32>>,
+    resampler_input: Vec<Vec<f32>>,
+    // Interleaved, at the encode rate, waiting to fill a frame
+    pending: Vec<f32>,
+    packet: Vec<u8>,
+    channels: usize,
+    input_rate: u32,
+    encode_rate: u32,
+    frame_size: usize,
+    lookahead: usize,
+    /// How far the resampler's output lags its input, in frames at the encode rate
+    resampler_delay: usize,
+    /// Frames out of the resampler so far
+    resampled_frames: u64,
+    pre_skip: u64,
+    input_frames: u64,
+    encoded_granules: u64,
+    packet_no: i64,
+    ogg: OggStream,
+    sink: W,
+    minimum_page_data_size: Option<u16>,
+}
+
+impl<W: Write> OpusEncoder<W> {
+    /// `frame_granules` is the frame duration in 48 kHz samples, e.g. 960 for 20 ms.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<'a>(
+        tags: impl IntoIterator<Item = (&'a str, &'a str)>,
+        input_rate: u32,
+        channels: u16,
+        bitrate: u32,
+        application: OpusApplication,
+        frame_granules: u32,
+        minimum_page_data_size: Option<u16>,
+        sink: W,
+    ) -> Result<Self, Box<dyn Error>> {
+        if channels != 1 && channels != 2 {
+            return Err(format!(
+                "Opus output supports mono or stereo, but the device has {} channels",
+                channels
+            )
+            .into());
+        }
+        let channels = channels as usize;
+        let encode_rate = encode_rate_for(input_rate);
+        let resampler = if encode_rate != input_rate {
+            Some(FftFixedIn::new(
+                input_rate as usize,
+                encode_rate as usize,
+                RESAMPLER_CHUNK_SIZE,
+                1,
+                channels,
+            )?)
+        } else {
+            None
+        };
+
+        let mut error = 0;
+        let encoder = unsafe {
+            opus_encoder_create(
+                encode_rate as i32,
+                channels as i32,
+                application.as_raw(),
+                &mut error,
+            )
+        };
+        check(error, "opus_encoder_create")?;
+        let encoder = RawEncoder(encoder);
+        let resampler_delay = resampler.as_ref().map(|r| r.output_delay()).unwrap_or(0);
+        let mut this = Self {
+            encoder,
+            resampler,
+            resampler_input: vec![Vec::new(); channels],
+            pending: Vec::new(),
+            packet: vec![0; MAX_PACKET_SIZE],
+            channels,
+            input_rate,
+            encode_rate,
+            frame_size: (frame_granules as u64 * encode_rate as u64 / GRANULE_RATE as u64) as usize,
+            lookahead: 0,
+            resampler_delay,
+            resampled_frames: 0,
+            pre_skip: 0,
+            input_frames: 0,
+            encoded_granules: 0,
+            packet_no: 0,
+            ogg: OggStream::new_with_random_serial()?,
+            sink,
+            minimum_page_data_size,
+        };
+        unsafe {
+            check(
+                opus_encoder_ctl!(this.encoder.0, OPUS_SET_BITRATE_REQUEST, bitrate as i32),
+                "Setting the Opus bitrate",
+            )?;
+            let mut lookahead = 0i32;
+            check(
+                opus_encoder_ctl!(this.encoder.0, OPUS_GET_LOOKAHEAD_REQUEST, &mut lookahead),
+                "Getting the Opus lookahead",
+            )?;
+            this.lookahead = lookahead as usize;
+        }
+        // Both the resampler and the encoder put this much silence ahead of the first real sample
+        this.pre_skip = this.to_granules((this.resampler_delay + this.lookahead) as u64);
+
+        // Each header goes on a page of its own, as RFC 7845 requires
+        let head = opus_head(channels as u8, this.pre_skip as u16, input_rate);
+        this.write_packet(head, 0, true, false)?;
+        this.flush()?;
+        this.write_packet(opus_tags(tags), 0, false, false)?;
+        this.flush()?;
+        Ok(this)
+    }
+
+    /// Encode one block of planar audio: one `Vec` of samples per channel, all of equal length.
+    pub fn encode_audio_block(&mut self, block: &[Vec<f32>]) -> Result<(), Box<dyn Error>> {
+        if block.len() != self.channels {
+            return Err(format!(
+                "Expected {} channels in audio block, got {}",
+                self.channels,
+                block.len()
+            )
+            .into());
+        }
+        self.input_frames += block[0].len() as u64;
+        match self.resampler.as_mut() {
+            Some(resampler) => {
+                for (buf, channel) in self.resampler_input.iter_mut().zip(block) {
+                    buf.extend(channel);
+                }
+                while self.resampler_input[0].len() >= resampler.input_frames_next() {
+                    let needed = resampler.input_frames_next();
+                    let chunk: Vec<Vec<f32>> = self
+                        .resampler_input
+                        .iter_mut()
+                        .map(|buf| buf.drain(..needed).collect())
+                        .collect();
+                    let resampled = resampler.process(&chunk, None)?;
+                    self.resampled_frames += resampled[0].len() as u64;
+                    interleave_into(&mut self.pending, &resampled);
+                }
+            }
+            None => interleave_into(&mut self.pending, block),
+        }
+        self.encode_pending_frames(false)
+    }
+
+    /// Push every packet encoded so far out to the sink, ending the current Ogg page early.
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.ogg.flush(&mut self.sink)
+    }
+
+    /// The rate libopus actually runs at, after any resampling
+    pub fn encode_rate(&self) -> u32 {
+        self.encode_rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    pub fn sink_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+
+    /// Drain the resampler and the encoder's lookahead, and mark the end of the stream.
+    pub fn finish(mut self) -> Result<W, Box<dyn Error>> {
+        if let Some(resampler) = self.resampler.as_mut() {
+            // Besides its delay, the resampler holds on to input that doesn't fill a whole FFT yet;
+            // feed it silence until all the real audio is out
+            let wanted = self.resampler_delay as u64
+                + (self.input_frames * self.encode_rate as u64).div_ceil(self.input_rate as u64);
+            while self.resampled_frames < wanted {
+                let needed = resampler.input_frames_next();
+                let chunk: Vec<Vec<f32>> = self
+                    .resampler_input
+                    .iter_mut()
+                    .map(|buf| {
+                        buf.resize(buf.len().max(needed), 0.);
+                        buf.drain(..needed).collect()
+                    })
+                    .collect();
+                let resampled = resampler.process(&chunk, None)?;
+                self.resampled_frames += resampled[0].len() as u64;
+                interleave_into(&mut self.pending, &resampled);
+            }
+        }
+        // Pad with silence so the lookahead gets flushed and the last frame is complete
+        let frame_len = self.frame_size * self.channels;
+        let mut padded_len = self.pending.len() + self.lookahead * self.channels;
+        padded_len = padded_len.div_ceil(frame_len).max(1) * frame_len;
+        self.pending.resize(padded_len, 0.);
+        self.encode_pending_frames(true)?;
+        self.ogg.flush(&mut self.sink)?;
+        Ok(self.sink)
+    }
+
+    fn encode_pending_frames(&mut self, end_of_stream: bool) -> Result<(), Box<dyn Error>> {
+        let frame_len = self.frame_size * self.channels;
+        // Granule position of the last real sample, for trimming the padding off the end
+        let last_granule =
+            self.pre_skip + self.input_frames * GRANULE_RATE as u64 / self.input_rate as u64;
+        let mut offset = 0;
+        while self.pending.len() - offset >= frame_len {
+            let len = unsafe {
+                opus_encode_float(
+                    self.encoder.0,
+                    self.pending[offset..].as_ptr(),
+                    self.frame_size as i32,
+                    self.packet.as_mut_ptr(),
+                    self.packet.len() as i32,
+                )
+            };
+            if len < 0 {
+                check(len, "opus_encode_float")?;
+            }
+            offset += frame_len;
+            self.encoded_granules += self.to_granules(self.frame_size as u64);
+            let is_last = end_of_stream && self.pending.len() - offset < frame_len;
+            let granule = (self.pre_skip + self.encoded_granules).min(last_granule);
+            let packet = self.packet[..len as usize].to_vec();
+            self.write_packet(packet, granule, false, is_last)?;
+            self.ogg
+                .write_pending_pages(&mut self.sink, self.minimum_page_data_size)?;
+        }
+        self.pending.drain(..offset);
+        Ok(())
+    }
+
+    fn write_packet(
+        &mut self,
+        mut data: Vec<u8>,
+        granule: u64,
+        beginning_of_stream: bool,
+        end_of_stream: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut packet = ogg_packet {
+            packet: data.as_mut_ptr(),
+            bytes: data.len() as c_long,
+            b_o_s: beginning_of_stream as c_long,
+            e_o_s: end_of_stream as c_long,
+            granulepos: granule as i64,
+            packetno: self.packet_no,
+        };
+        self.packet_no += 1;
+        // libogg copies the packet data, so `data` only has to outlive this call
+        unsafe { self.ogg.packet_in(&mut packet) }
+    }
+
+    fn to_granules(&self, samples: u64) -> u64 {
+        samples * GRANULE_RATE as u64 / self.encode_rate as u64
+    }
+}
+
+fn interleave_into(out: &mut Vec<f32>, planar: &[Vec<f32>]) {
+    let frames = planar.first().map(|c| c.len()).unwrap_or(0);
+    out.reserve(frames * planar.len());
+    for i in 0..frames {
+        for channel in planar {
+            out.push(channel[i]);
+        }
+    }
+}
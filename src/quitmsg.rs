@@ -9,7 +9,7 @@ impl QuitMsg {
     }
 
     pub async fn poll(&self) -> bool {
-        !self.flag.read().await.clone()
+        !*self.flag.read().await
     }
 
     pub async fn wait(&self) {
@@ -1,14 +1,37 @@
+use chrono::Utc;
 use cpal::traits::{DeviceTrait, HostTrait};
 use futures_core::Stream;
 use futures_util::{pin_mut, StreamExt};
 use std::error::Error;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use crate::attest;
+use crate::catalog;
+use crate::clip::{self, ClipDetector, ClipTrack};
 use crate::display_volume;
-use crate::{get_device_list, microphone, printrn, write_audio, FormatSelect, ProgramState};
-use log::{debug, info};
+use crate::encrypt::{self, SegmentSink};
+use crate::filter::{self, Filters};
+use crate::gain::{self, Gain, GainTrack};
+use crate::gate::{self, Gate};
+use crate::manifest::{self, ManifestEntry};
+use crate::name_template::{NameFields, NameTemplate};
+use crate::segment_file;
+use crate::segment_meta::{self, EndReason, SegmentMeta, SegmentSidecar};
+use crate::segment_stats::SegmentStats;
+use crate::silence::{self, SilenceDetector, SilenceTrack};
+use crate::time_zone::NameTimeZone;
+use crate::write_audio::SegmentWriter;
+use crate::{microphone, Chunk, OutputFormat, ProgramState};
+use age::x25519;
+use ed25519_dalek::SigningKey;
+use log::{error, info};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
 pub async fn search_for(state: Arc<ProgramState>, dev_name: &String) -> Result<cpal::Device, ()> {
     for device in state
@@ -29,69 +52,676 @@ pub async fn search_for(state: Arc<ProgramState>, dev_name: &String) -> Result<c
     }
     Err(())
 }
-pub async fn record_segments<S: Stream<Item = PathBuf> + Unpin>(
-    mut paths: S,
-    state: Arc<ProgramState>,
-) -> Result<S, Box<dyn Error>> {
-    while let Some(path) = paths.next().await {
-        info!("Begin recording segment...");
-        let host = cpal::default_host();
-        let input_device = match &state.cli.read().await.cmd.as_rec().unwrap().device {
-            Some(dev_name) => {
-                if let Ok(device) = search_for(state.clone(), &String::from("pipewire")).await {
-                    device
-                } else if let Ok(device) =
-                    search_for(state.clone(), &String::from("pulseaudio")).await
-                {
-                    device
-                } else if let Ok(device) = search_for(state.clone(), dev_name).await {
-                    device
-                } else {
-                    panic!("Could not find device: {}", dev_name);
-                }
+/// Where an open segment is being written, and where it goes once it's finished.
+struct SegmentPaths {
+    partial: PathBuf,
+    target: PathBuf,
+}
+
+/// Everything the recorder tracks about the segment in progress, shared by all outputs.
+struct Segment {
+    meta: SegmentMeta,
+    started: Instant,
+    stats: SegmentStats,
+    xrun_counter: Arc<AtomicU64>,
+    xruns_at_start: u64,
+    catalog_dir: PathBuf,
+    attest_key: Option<SigningKey>,
+    /// Segments are encrypted to these if there are any
+    encrypt_to: Vec<x25519::Recipient>,
+    name_template: NameTemplate,
+    name_prefix: String,
+    time_zone: NameTimeZone,
+    /// What the gain stage applied, if there is one
+    gain_track: Option<Arc<Mutex<GainTrack>>>,
+    /// Frames the noise gate held closed, if there is one
+    gated_frames: Option<Arc<AtomicU64>>,
+    /// Clipping at the input
+    clip_track: Arc<Mutex<ClipTrack>>,
+    /// Silence at the input
+    silence_track: Arc<Mutex<SilenceTrack>>,
+}
+
+impl Segment {
+    fn xruns(&self) -> u64 {
+        self.xrun_counter.load(Ordering::Relaxed) - self.xruns_at_start
+    }
+}
+
+/// Hashes finished segments and adds them to the catalog, the signed chain and the manifest, on the blocking
+/// pool so the capture task never waits on reading a whole file back. One at a time, in the order they finished.
+#[derive(Default)]
+struct Bookkeeping {
+    last: Option<JoinHandle<()>>,
+}
+
+impl Bookkeeping {
+    fn push(&mut self, job: impl FnOnce() + Send + 'static) {
+        let previous = self.last.take();
+        self.last = Some(tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
             }
-            None => host
-                .default_input_device()
-                .ok_or("No default input device available :c")?,
-        };
-        let mut supported_configs_range = input_device.supported_input_configs()?;
-        let supported_config = supported_configs_range
-            .next()
-            .ok_or("Could not get the first supported config from range")?
-            .with_max_sample_rate();
-        let mut config: cpal::StreamConfig = supported_config.into();
-        config.sample_rate = cpal::SampleRate(44_100);
-
-        info!("Current sample rate: {}", config.sample_rate.0);
-
-        let stream = microphone::getstream_mic_input(config.clone(), input_device, state.clone());
-        pin_mut!(stream);
-
-        let mut volume_stream_builder_inst = display_volume::VolumeStreamBuilder::new();
-        volume_stream_builder_inst.dur_of_display =
-            match state.cli.read().await.cmd.as_rec().unwrap().display_dur {
-                Some(human_dur) => Some(Duration::from(&human_dur)),
-                None => None,
+            if let Err(e) = tokio::task::spawn_blocking(job).await {
+                error!("Cataloguing a finished segment failed: {}", e);
+            }
+        }));
+    }
+
+    /// Waits for everything pushed so far.
+    async fn finish(&mut self) {
+        if let Some(last) = self.last.take() {
+            let _ = last.await;
+        }
+    }
+}
+
+/// Reads `path` back to hash it, then records it in the catalog, the chain (if `attest_key`) and the manifest.
+fn catalog_segment(catalog_dir: &Path, attest_key: Option<&SigningKey>, path: &Path, sidecar: &SegmentSidecar) {
+    let entry = match catalog::entry_from_sidecar(catalog_dir, path, sidecar) {
+        Ok(entry) => entry,
+        Err(e) => {
+            error!("Could not hash {}: {}", path.display(), e);
+            return;
+        }
+    };
+    if let Err(e) = catalog::append(catalog_dir, &entry) {
+        error!("Could not add {} to the catalog: {}", path.display(), e);
+    }
+    if let Some(key) = attest_key {
+        if let Err(e) = attest::append(catalog_dir, key, &entry) {
+            error!("Could not add {} to the signed chain: {}", path.display(), e);
+        }
+    }
+    let Some(sha256) = entry.sha256 else {
+        error!("{} has no hash, so it can't go in the manifest", path.display());
+        return;
+    };
+    let manifest_entry = ManifestEntry {
+        sha256,
+        path: entry.path,
+    };
+    if let Err(e) = manifest::append(catalog_dir, &manifest_entry) {
+        error!("Could not add {} to the manifest: {}", path.display(), e);
+    }
+}
+
+/// A `SegmentWriter`, and where its files go. `current` is cleared when the writer fails,
+/// so it sits out the rest of that segment.
+struct Output {
+    format: OutputFormat,
+    writer: Box<dyn SegmentWriter>,
+    current: Option<SegmentPaths>,
+    frames_written: u64,
+}
+
+impl Output {
+    /// `name` is the segment's path relative to the recording directory, as the name template renders it.
+    fn segment_path(&self, path_dir: &Path, name: &Path, encrypted: bool) -> PathBuf {
+        let mut file_name = self.format.dir_for(path_dir).join(name).into_os_string();
+        file_name.push(".");
+        file_name.push(self.writer.extension());
+        let path = PathBuf::from(file_name);
+        if encrypted {
+            encrypt::encrypted_path(&path)
+        } else {
+            path
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.current.is_some()
+    }
+
+    /// Opens the writer under the first name that's free, both finished and `.partial`, creating any
+    /// directories the name template calls for. The file is encrypted if the segment has recipients.
+    fn open(
+        &mut self,
+        path_dir: &Path,
+        config: &cpal::StreamConfig,
+        segment: &Segment,
+    ) -> Result<(), Box<dyn Error>> {
+        let meta = &segment.meta;
+        let recipients = &segment.encrypt_to[..];
+        let name = segment.name_template.render(&NameFields {
+            time: meta.start,
+            time_zone: segment.time_zone,
+            prefix: &segment.name_prefix,
+            device: &meta.device,
+            hostname: &meta.hostname,
+            session_id: &meta.session_id,
+            index: meta.index,
+            format: self.writer.extension(),
+        });
+        let path = self.segment_path(path_dir, &name, !recipients.is_empty());
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        for target in segment_file::candidates(&path) {
+            let partial = segment_file::partial_path(&target);
+            if target.exists() || partial.exists() {
+                continue;
+            }
+            let sink = match SegmentSink::create(&partial, recipients) {
+                Ok(sink) => sink,
+                // Lost a race for the name; try the next one
+                Err(e) if is_already_exists(e.as_ref()) => continue,
+                Err(e) => {
+                    let _ = std::fs::remove_file(&partial);
+                    return Err(e);
+                }
             };
-        volume_stream_builder_inst.time_of_start = *state.time_of_start.read().await;
-        let stream = volume_stream_builder_inst
-            .getstream_display_volume(stream, state.clone())
-            .await;
-        pin_mut!(stream);
-
-        let dur = state.cli.read().await.cmd.as_rec().unwrap().segment_dur;
-        let segment_dur = Duration::from(&dur);
-        match state.cli.read().await.cmd.as_rec().unwrap().format {
-            FormatSelect::Wav => {
-                write_audio::write_to_wav(&path, stream, &config, &segment_dur).await?;
+            match self.writer.open(sink, config, meta) {
+                Ok(()) => {
+                    self.current = Some(SegmentPaths { partial, target });
+                    self.frames_written = 0;
+                    return Ok(());
+                }
+                Err(e) => {
+                    // Don't leave an empty or headless file behind
+                    let _ = std::fs::remove_file(&partial);
+                    return Err(e);
+                }
             }
-            FormatSelect::Ogg => {
-                write_audio::write_to_ogg(&path, stream, &config, &segment_dur).await?;
+        }
+        Err(format!("No free file name left for {}", path.display()).into())
+    }
+
+    fn write_chunk(&mut self, chunk: &[f32], channels: usize) -> Result<(), Box<dyn Error>> {
+        self.writer.write_chunk(chunk)?;
+        self.frames_written += (chunk.len() / channels) as u64;
+        Ok(())
+    }
+
+    /// Finalizes the writer, moves the file to its real name and writes its sidecar, and leaves the
+    /// cataloguing to `bookkeeping`. Returns where the segment ended up.
+    fn finalize(
+        &mut self,
+        segment: &Segment,
+        end_reason: EndReason,
+        bookkeeping: &mut Bookkeeping,
+    ) -> Result<Option<PathBuf>, Box<dyn Error>> {
+        let Some(paths) = self.current.take() else {
+            return Ok(None);
+        };
+        let encoder_settings = self.writer.settings();
+        self.writer.finalize()?;
+        let path = segment_file::publish(&paths.partial, &paths.target)?;
+
+        let meta = &segment.meta;
+        let sidecar = SegmentSidecar {
+            file: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+            start: segment_meta::format_time(&meta.start),
+            end: segment_meta::format_time(&Utc::now()),
+            duration_secs: self.frames_written as f64 / meta.sample_rate as f64,
+            samples: self.frames_written,
+            sample_rate: meta.sample_rate,
+            channels: meta.channels,
+            device: meta.device.clone(),
+            hostname: meta.hostname.clone(),
+            session_id: meta.session_id.clone(),
+            segment_index: meta.index,
+            format: self.writer.extension().to_owned(),
+            encoder_settings,
+            peak_dbfs: segment.stats.peak_dbfs(),
+            rms_dbfs: segment.stats.rms_dbfs(),
+            clipped_samples: segment.stats.clipped_samples(),
+            xruns: segment.xruns(),
+            end_reason,
+            tags: meta
+                .user_tags
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect(),
+            gain: segment
+                .gain_track
+                .as_ref()
+                .map(|track| track.lock().unwrap_or_else(|e| e.into_inner()).report()),
+            gated_secs: segment
+                .gated_frames
+                .as_ref()
+                .map(|frames| frames.load(Ordering::Relaxed) as f64 / meta.sample_rate as f64),
+            input_clipping: Some(segment.clip_track.lock().unwrap_or_else(|e| e.into_inner()).report()),
+            silence: Some(segment.silence_track.lock().unwrap_or_else(|e| e.into_inner()).report()),
+        };
+        if let Err(e) = segment_meta::write_sidecar(&path, &sidecar) {
+            error!("Could not write sidecar for {}: {}", path.display(), e);
+        }
+        let catalog_dir = segment.catalog_dir.clone();
+        let attest_key = segment.attest_key.clone();
+        let cataloged_path = path.clone();
+        bookkeeping.push(move || catalog_segment(&catalog_dir, attest_key.as_ref(), &cataloged_path, &sidecar));
+        Ok(Some(path))
+    }
+
+    fn fail(&mut self, segment: &Segment, e: Box<dyn Error>, bookkeeping: &mut Bookkeeping) {
+        error!("Writing {} segment failed: {}", self.writer.extension(), e);
+        // Salvage what we can of the segment, if the writer can still manage it
+        let partial = self.current.as_ref().map(|paths| paths.partial.clone());
+        if let Err(e) = self.finalize(segment, EndReason::Error, bookkeeping) {
+            error!(
+                "Could not finalize failed {} segment, leaving {} behind: {}",
+                self.writer.extension(),
+                partial.unwrap_or_default().display(),
+                e
+            );
+        }
+    }
+}
+
+fn is_already_exists(e: &(dyn Error + 'static)) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::AlreadyExists)
+}
+
+fn open_segment(
+    outputs: &mut [Output],
+    config: &cpal::StreamConfig,
+    segment: &Segment,
+    bookkeeping: &mut Bookkeeping,
+) -> Result<(), Box<dyn Error>> {
+    for output in outputs.iter_mut() {
+        if let Err(e) = output.open(&segment.catalog_dir, config, segment) {
+            output.fail(segment, e, bookkeeping);
+        }
+    }
+    ensure_any_open(outputs)
+}
+
+fn write_chunk(
+    outputs: &mut [Output],
+    segment: &mut Segment,
+    chunk: &[f32],
+    bookkeeping: &mut Bookkeeping,
+) -> Result<(), Box<dyn Error>> {
+    let channels = segment.meta.channels as usize;
+    segment.stats.add_chunk(chunk);
+    for output in outputs.iter_mut().filter(|output| output.is_open()) {
+        if let Err(e) = output.write_chunk(chunk, channels) {
+            output.fail(segment, e, bookkeeping);
+        }
+    }
+    ensure_any_open(outputs)
+}
+
+fn finalize_segment(
+    outputs: &mut [Output],
+    segment: &Segment,
+    end_reason: EndReason,
+    bookkeeping: &mut Bookkeeping,
+) {
+    for output in outputs.iter_mut().filter(|output| output.is_open()) {
+        let bytes_written = output.writer.bytes_written();
+        let partial = output.current.as_ref().map(|paths| paths.partial.clone());
+        match output.finalize(segment, end_reason, bookkeeping) {
+            Ok(path) => info!(
+                "Finished {} segment {} ({} bytes)",
+                output.writer.extension(),
+                path.unwrap_or_default().display(),
+                bytes_written
+            ),
+            Err(e) => error!(
+                "Finalizing {} segment failed, leaving {} behind: {}",
+                output.writer.extension(),
+                partial.unwrap_or_default().display(),
+                e
+            ),
+        }
+    }
+    // The next chunk the detectors, gain and gate stages see belongs to the next segment
+    segment.clip_track.lock().unwrap_or_else(|e| e.into_inner()).reset();
+    segment.silence_track.lock().unwrap_or_else(|e| e.into_inner()).reset();
+    if let Some(track) = &segment.gain_track {
+        track.lock().unwrap_or_else(|e| e.into_inner()).reset();
+    }
+    if let Some(frames) = &segment.gated_frames {
+        frames.store(0, Ordering::Relaxed);
+    }
+}
+
+/// One writer failing shouldn't cost us the others, but if they've all failed there's no point carrying on.
+fn ensure_any_open(outputs: &[Output]) -> Result<(), Box<dyn Error>> {
+    if outputs.iter().any(|output| output.is_open()) {
+        Ok(())
+    } else {
+        Err("Every output format failed to write this segment".into())
+    }
+}
+
+/// Records from the microphone until quit, starting a new segment once `--segment-dur` has passed,
+/// or a split is requested. Every chunk goes to each of `writers`, named by `--name-template`.
+pub async fn record_segments(
+    writers: Vec<(OutputFormat, Box<dyn SegmentWriter>)>,
+    state: Arc<ProgramState>,
+) -> Result<(), Box<dyn Error>> {
+    let outputs: Vec<Output> = writers
+        .into_iter()
+        .map(|(format, writer)| Output {
+            format,
+            writer,
+            current: None,
+            frames_written: 0,
+        })
+        .collect();
+
+    info!("Begin recording...");
+    let host = cpal::default_host();
+    let input_device = match &state.cli.read().await.cmd.as_rec().unwrap().device {
+        Some(dev_name) => {
+            if let Ok(device) = search_for(state.clone(), &String::from("pipewire")).await {
+                device
+            } else if let Ok(device) =
+                search_for(state.clone(), &String::from("pulseaudio")).await
+            {
+                device
+            } else if let Ok(device) = search_for(state.clone(), dev_name).await {
+                device
+            } else {
+                panic!("Could not find device: {}", dev_name);
             }
         }
-        if state.quit_msg.poll().await {
+        None => host
+            .default_input_device()
+            .ok_or("No default input device available :c")?,
+    };
+    let mut supported_configs_range = input_device.supported_input_configs()?;
+    let supported_config = supported_configs_range
+        .next()
+        .ok_or("Could not get the first supported config from range")?
+        .with_max_sample_rate();
+    let mut config: cpal::StreamConfig = supported_config.into();
+    config.sample_rate = cpal::SampleRate(44_100);
+
+    info!("Current sample rate: {}", config.sample_rate.0);
+    let device_name = input_device.name().unwrap_or_default();
+
+    let stream = microphone::getstream_mic_input(config.clone(), input_device, state.clone());
+    pin_mut!(stream);
+
+    // Straight off the device, since that's where clipping can't be undone
+    let clip_detector = ClipDetector::from_opts(
+        &state.cli.read().await.cmd.as_rec().unwrap().clip,
+        config.channels as usize,
+        config.sample_rate.0,
+    );
+    let clip_track = clip_detector.track();
+    let stream = clip::getstream_clip_detect(stream, clip_detector);
+    pin_mut!(stream);
+
+    let silence_detector = SilenceDetector::from_opts(
+        &state.cli.read().await.cmd.as_rec().unwrap().silence,
+        config.channels as usize,
+        config.sample_rate.0,
+    );
+    let silence_track = silence_detector.track();
+    let stream = silence::getstream_silence_detect(stream, silence_detector, state.clone());
+    pin_mut!(stream);
+
+    // Filter first, so neither the AGC nor the meter is thrown by DC or rumble
+    let filters = Filters::from_opts(
+        &state.cli.read().await.cmd.as_rec().unwrap().filter,
+        config.channels as usize,
+        config.sample_rate.0,
+    );
+    let stream: Pin<Box<dyn Stream<Item = Chunk>>> = match filters {
+        Some(filters) => Box::pin(filter::getstream_filter(stream, filters)),
+        None => Box::pin(stream),
+    };
+
+    let gain = Gain::from_opts(
+        &state.cli.read().await.cmd.as_rec().unwrap().gain,
+        config.channels as usize,
+        config.sample_rate.0,
+    );
+    let gain_track = gain.as_ref().map(Gain::track);
+    let stream: Pin<Box<dyn Stream<Item = Chunk>>> = match gain {
+        Some(gain) => Box::pin(gain::getstream_gain(stream, gain)),
+        None => Box::pin(stream),
+    };
+
+    // After the gain, so the threshold applies to the level that's actually recorded
+    let gate = Gate::from_opts(
+        &state.cli.read().await.cmd.as_rec().unwrap().gate,
+        config.channels as usize,
+        config.sample_rate.0,
+    );
+    let gated_frames = gate.as_ref().map(Gate::gated_frames);
+    let stream: Pin<Box<dyn Stream<Item = Chunk>>> = match gate {
+        Some(gate) => Box::pin(gate::getstream_gate(stream, gate)),
+        None => Box::pin(stream),
+    };
+
+    let mut volume_stream_builder_inst = display_volume::VolumeStreamBuilder::new();
+    volume_stream_builder_inst.dur_of_display =
+        state.cli.read().await.cmd.as_rec().unwrap().display_dur.map(|human_dur| Duration::from(&human_dur));
+    volume_stream_builder_inst.time_of_start = *state.time_of_start.read().await;
+    volume_stream_builder_inst.channels = config.channels as usize;
+    volume_stream_builder_inst.sample_rate = config.sample_rate.0;
+    volume_stream_builder_inst.clip_track = Some(clip_track.clone());
+    let stream = volume_stream_builder_inst
+        .getstream_display_volume(stream, state.clone())
+        .await;
+    pin_mut!(stream);
+
+    let tracks = StageTracks {
+        gain_track,
+        gated_frames,
+        clip_track,
+        silence_track,
+    };
+    write_segments(stream, outputs, &config, &device_name, tracks, state).await
+}
+
+/// Handles on what the processing stages saw, for each segment's sidecar.
+struct StageTracks {
+    gain_track: Option<Arc<Mutex<GainTrack>>>,
+    gated_frames: Option<Arc<AtomicU64>>,
+    clip_track: Arc<Mutex<ClipTrack>>,
+    silence_track: Arc<Mutex<SilenceTrack>>,
+}
+
+/// Splits `stream` into segments of `--segment-dur` (or wherever a split is asked for) and writes
+/// each one to every output, until the stream ends.
+async fn write_segments<S: Stream<Item = Chunk> + Unpin>(
+    mut stream: S,
+    mut outputs: Vec<Output>,
+    config: &cpal::StreamConfig,
+    device_name: &str,
+    tracks: StageTracks,
+    state: Arc<ProgramState>,
+) -> Result<(), Box<dyn Error>> {
+    let dur = state.cli.read().await.cmd.as_rec().unwrap().segment_dur;
+    let segment_dur = Duration::from(&dur);
+    let user_tags = state.cli.read().await.cmd.as_rec().unwrap().tags.clone();
+    let name_template = state.cli.read().await.cmd.as_rec().unwrap().naming.name_template()?;
+    let name_prefix = state.cli.read().await.cmd.as_rec().unwrap().naming.name_prefix.clone();
+    let time_zone = state.cli.read().await.cmd.as_rec().unwrap().naming.time_zone;
+    let mut segment: Option<Segment> = None;
+    let mut bookkeeping = Bookkeeping::default();
+    let mut result = Ok(());
+    while let Some(chunk) = stream.next().await {
+        let current = match segment.as_mut() {
+            Some(current) => current,
+            None => {
+                info!("Begin recording segment...");
+                let index = {
+                    let mut segment_index = state.segment_index.write().await;
+                    *segment_index += 1;
+                    *segment_index
+                };
+                let meta = SegmentMeta {
+                    start: Utc::now(),
+                    device: device_name.to_owned(),
+                    hostname: segment_meta::hostname(),
+                    session_id: state.session_id.clone(),
+                    index,
+                    sample_rate: config.sample_rate.0,
+                    channels: config.channels,
+                    user_tags: user_tags.clone(),
+                };
+                let new_segment = Segment {
+                    meta,
+                    started: Instant::now(),
+                    stats: SegmentStats::new(config.channels as usize),
+                    xrun_counter: state.xruns.clone(),
+                    xruns_at_start: state.xruns.load(Ordering::Relaxed),
+                    catalog_dir: state.path_dir.read().await.clone(),
+                    attest_key: state.attest_key.clone(),
+                    encrypt_to: state.encrypt_to.clone(),
+                    name_template: name_template.clone(),
+                    name_prefix: name_prefix.clone(),
+                    time_zone,
+                    gain_track: tracks.gain_track.clone(),
+                    gated_frames: tracks.gated_frames.clone(),
+                    clip_track: tracks.clip_track.clone(),
+                    silence_track: tracks.silence_track.clone(),
+                };
+                if let Err(e) = open_segment(&mut outputs, config, &new_segment, &mut bookkeeping) {
+                    result = Err(e);
+                    break;
+                }
+                segment.insert(new_segment)
+            }
+        };
+        if let Err(e) = write_chunk(&mut outputs, current, &chunk, &mut bookkeeping) {
+            finalize_segment(&mut outputs, current, EndReason::Error, &mut bookkeeping);
+            segment = None;
+            result = Err(e);
             break;
         }
+        let end_reason = if current.started.elapsed() >= segment_dur {
+            Some(EndReason::Duration)
+        } else if *state.split_requested.read().await {
+            *state.split_requested.write().await = false;
+            Some(EndReason::Split)
+        } else {
+            None
+        };
+        if let Some(end_reason) = end_reason {
+            finalize_segment(&mut outputs, current, end_reason, &mut bookkeeping);
+            segment = None;
+        }
+    }
+    if let Some(current) = segment.as_ref() {
+        finalize_segment(&mut outputs, current, EndReason::Quit, &mut bookkeeping);
+    }
+    // Don't quit until every finished segment is in the catalog
+    bookkeeping.finish().await;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use async_fn_stream::fn_stream;
+    use clap::Parser;
+
+    use super::*;
+    use crate::decode;
+    use crate::encrypt::Identities;
+    use crate::test_dir::TestDir;
+    use crate::{write_audio, Cli};
+
+    const SAMPLE_RATE: u32 = 8_000;
+    /// A tenth of a second
+    const CHUNK_FRAMES: usize = 800;
+
+    /// `chunks` chunks, one every tenth of a second, each holding its own number (in thousandths) so the
+    /// segments can be matched back up with them. A split is asked for just before chunk `split_before`.
+    fn fake_stream(
+        state: Arc<ProgramState>,
+        chunks: usize,
+        split_before: Option<usize>,
+    ) -> impl Stream<Item = Chunk> {
+        fn_stream(move |emitter| async move {
+            for i in 0..chunks {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                if split_before == Some(i) {
+                    *state.split_requested.write().await = true;
+                }
+                emitter.emit(vec![i as f32 / 1000.; CHUNK_FRAMES]).await;
+            }
+        })
+    }
+
+    /// Records the fake stream in 1 s WAV segments, and returns each segment's first chunk, length in
+    /// chunks and end reason, in order.
+    async fn record(chunks: usize, split_before: Option<usize>) -> Vec<(usize, u64, EndReason)> {
+        let dir = TestDir::new("segments");
+        let cli = Cli::parse_from(["akasha", "rec", "--format", "wav", "--segment-dur", "1s"]);
+        let rec = cli.cmd.as_rec().unwrap().clone();
+        let state = Arc::new(ProgramState::new(cli));
+        *state.path_dir.write().await = dir.path().to_path_buf();
+        let config = cpal::StreamConfig {
+            channels: 1,
+            sample_rate: cpal::SampleRate(SAMPLE_RATE),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        let tracks = StageTracks {
+            gain_track: None,
+            gated_frames: None,
+            clip_track: ClipDetector::from_opts(&rec.clip, 1, SAMPLE_RATE).track(),
+            silence_track: SilenceDetector::from_opts(&rec.silence, 1, SAMPLE_RATE).track(),
+        };
+        let outputs = write_audio::writers_for(&rec)
+            .into_iter()
+            .map(|(format, writer)| Output {
+                format,
+                writer,
+                current: None,
+                frames_written: 0,
+            })
+            .collect();
+        let stream = Box::pin(fake_stream(state.clone(), chunks, split_before));
+        write_segments(stream, outputs, &config, "fake", tracks, state).await.unwrap();
+
+        let mut segments: Vec<_> = catalog::find_segment_files(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|path| {
+                let sidecar = std::fs::read(segment_meta::sidecar_path(&path)).unwrap();
+                let sidecar: SegmentSidecar = serde_json::from_slice(&sidecar).unwrap();
+                let mut reader = decode::open(&path, &Identities::default()).unwrap();
+                let first_sample = reader.read_chunk().unwrap().unwrap()[0];
+                (sidecar.segment_index, first_sample, sidecar.samples, sidecar.end_reason)
+            })
+            .collect();
+        segments.sort_by_key(|(index, ..)| *index);
+        segments
+            .into_iter()
+            .map(|(_, first_sample, samples, end_reason)| {
+                assert_eq!(samples % CHUNK_FRAMES as u64, 0);
+                ((first_sample * 1000.).round() as usize, samples / CHUNK_FRAMES as u64, end_reason)
+            })
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn splits_every_segment_dur() {
+        // A segment ends with the chunk that arrives once it's been open for 1 s,
+        // which is 11 chunks after the one that opened it
+        assert_eq!(
+            record(25, None).await,
+            vec![
+                (0, 11, EndReason::Duration),
+                (11, 11, EndReason::Duration),
+                (22, 3, EndReason::Quit),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn splits_when_asked() {
+        assert_eq!(
+            record(20, Some(4)).await,
+            vec![
+                (0, 5, EndReason::Split),
+                (5, 11, EndReason::Duration),
+                (16, 4, EndReason::Quit),
+            ]
+        );
     }
-    Ok(paths)
 }
@@ -0,0 +1,127 @@
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::encrypt;
+
+/// Segments are written under this extra extension, and only renamed to their real name
+/// once complete, so sync and backup tools never pick up a half-written file.
+pub const PARTIAL_EXTENSION: &str = "partial";
+// Give up looking for a free name eventually, rather than spinning forever on e.g. a read-only directory
+const MAX_SUFFIX: u32 = 1000;
+
+/// Creates a segment file, failing rather than truncating if something is already there.
+pub fn create_new(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(PARTIAL_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// `dir/name.ext` -> `dir/name__<n>.ext`, for disambiguating segments that would share a name.
+/// `n == 0` leaves the path alone.
+pub fn with_suffix(path: &Path, n: u32) -> PathBuf {
+    if n == 0 {
+        return path.to_path_buf();
+    }
+    // `name.ogg.age` -> `name__<n>.ogg.age`
+    if path.extension() == Some(OsStr::new(encrypt::ENCRYPTED_EXTENSION)) {
+        return encrypt::encrypted_path(&with_suffix(&encrypt::plain_path(path), n));
+    }
+    let mut name: OsString = path.file_stem().unwrap_or_default().to_owned();
+    name.push(format!("__{}", n));
+    if let Some(extension) = path.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    path.with_file_name(name)
+}
+
+/// Every name `path` may end up under, in order of preference.
+pub fn candidates(path: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+    (0..=MAX_SUFFIX).map(move |n| with_suffix(path, n))
+}
+
+/// Moves a finished `.partial` file to `target`, or to the first free suffixed name after it.
+/// Never overwrites anything. Returns where the file ended up.
+pub fn publish(partial: &Path, target: &Path) -> io::Result<PathBuf> {
+    for candidate in candidates(target) {
+        // Linking fails if the name is taken, which makes claiming it atomic
+        match fs::hard_link(partial, &candidate) {
+            Ok(()) => {
+                fs::remove_file(partial)?;
+                sync_parent_dir(&candidate)?;
+                return Ok(candidate);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            // Some filesystems (FAT, some network mounts) can't do hard links
+            Err(_) if !candidate.exists() => {
+                fs::rename(partial, &candidate)?;
+                sync_parent_dir(&candidate)?;
+                return Ok(candidate);
+            }
+            Err(_) => continue,
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("No free file name left for {}", target.display()),
+    ))
+}
+
+/// Makes the rename itself durable, not just the file contents.
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    #[cfg(target_family = "unix")]
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    #[test]
+    fn suffix_goes_before_the_extension() {
+        let path = Path::new("dir/akasha__2024-01-01__12_00_00.ogg");
+        assert_eq!(with_suffix(path, 0), path);
+        assert_eq!(with_suffix(path, 2), Path::new("dir/akasha__2024-01-01__12_00_00__2.ogg"));
+        assert_eq!(with_suffix(Path::new("dir/name"), 1), Path::new("dir/name__1"));
+    }
+
+    #[test]
+    fn suffix_goes_before_both_extensions_of_an_encrypted_file() {
+        assert_eq!(with_suffix(Path::new("dir/name.flac.age"), 3), Path::new("dir/name__3.flac.age"));
+    }
+
+    #[test]
+    fn partial_path_adds_an_extension() {
+        assert_eq!(partial_path(Path::new("dir/name.wav")), Path::new("dir/name.wav.partial"));
+    }
+
+    #[test]
+    fn publish_never_overwrites() {
+        let dir = TestDir::new("publish");
+        let target = dir.join("name.wav");
+        fs::write(&target, "first").unwrap();
+        let partial = partial_path(&target);
+        fs::write(&partial, "second").unwrap();
+
+        let published = publish(&partial, &target).unwrap();
+        assert_eq!(published, dir.join("name__1.wav"));
+        assert_eq!(fs::read_to_string(&target).unwrap(), "first");
+        assert_eq!(fs::read_to_string(&published).unwrap(), "second");
+        assert!(!partial.exists());
+    }
+}
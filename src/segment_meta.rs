@@ -0,0 +1,255 @@
+use std::error::Error;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::clip::ClipReport;
+use crate::gain::GainReport;
+use crate::silence::SilenceReport;
+use crate::segment_file;
+
+/// Everything we know about a segment when it starts, for tagging the files we write.
+#[derive(Debug, Clone)]
+pub struct SegmentMeta {
+    pub start: DateTime<Utc>,
+    pub device: String,
+    pub hostname: String,
+    pub session_id: String,
+    pub index: u64,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub user_tags: Vec<(String, String)>,
+}
+
+impl SegmentMeta {
+    /// Vorbis comment fields, as written into Vorbis, Opus and FLAC segments.
+    /// User tags come last, so they can add to but not hide ours.
+    pub fn vorbis_comments(&self) -> Vec<(String, String)> {
+        let mut comments = vec![
+            (
+                "DATE".to_owned(),
+                format_time(&self.start),
+            ),
+            (
+                "ENCODER".to_owned(),
+                format!("akasha {}", env!("CARGO_PKG_VERSION")),
+            ),
+            ("AKASHA_DEVICE".to_owned(), self.device.clone()),
+            ("AKASHA_HOSTNAME".to_owned(), self.hostname.clone()),
+            ("AKASHA_SESSION".to_owned(), self.session_id.clone()),
+            ("AKASHA_SEGMENT_INDEX".to_owned(), self.index.to_string()),
+            ("AKASHA_SAMPLE_RATE".to_owned(), self.sample_rate.to_string()),
+        ];
+        comments.extend(self.user_tags.iter().cloned());
+        comments
+    }
+}
+
+/// Identifies one run of akasha, so segments from the same session can be grouped.
+pub fn new_session_id() -> String {
+    format!(
+        "{}-{:x}",
+        Local::now().format("%Y%m%dT%H%M%S"),
+        std::process::id()
+    )
+}
+
+pub fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// Parses `KEY=VALUE` pairs for `--tag`. Keys must be valid Vorbis comment field names.
+pub fn parse_tag(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("`{}` is not of the form KEY=VALUE", s))?;
+    if key.is_empty() || !key.bytes().all(|b| (0x20..=0x7d).contains(&b) && b != b'=') {
+        return Err(format!("`{}` is not a valid tag name", key));
+    }
+    Ok((key.to_ascii_uppercase(), value.to_owned()))
+}
+
+/// Why a segment file was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndReason {
+    /// Reached --segment-dur
+    Duration,
+    /// The program was asked to quit
+    Quit,
+    /// The writer failed, and this is what could be salvaged
+    Error,
+    /// Split early on request
+    Split,
+}
+
+/// Contents of the `<segment>.json` sidecar written next to every finished segment.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentSidecar {
+    pub file: String,
+    pub start: String,
+    pub end: String,
+    pub duration_secs: f64,
+    /// Sample frames, i.e. samples per channel
+    pub samples: u64,
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub device: String,
+    pub hostname: String,
+    pub session_id: String,
+    pub segment_index: u64,
+    pub format: String,
+    pub encoder_settings: serde_json::Value,
+    /// Per channel; `null` for digital silence
+    pub peak_dbfs: Vec<Option<f32>>,
+    pub rms_dbfs: Vec<Option<f32>>,
+    pub clipped_samples: u64,
+    pub xruns: u64,
+    pub end_reason: EndReason,
+    pub tags: Vec<String>,
+    /// What `--gain` and `--agc` did to the level, if anything
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gain: Option<GainReport>,
+    /// How long the noise gate was closed for, if there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gated_secs: Option<f64>,
+    /// Samples at or near full scale as they came in from the device, before any gain, where
+    /// `clipped_samples` counts what was written
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub input_clipping: Option<ClipReport>,
+    /// How much of the input was silent, and whether it went on long enough to be flagged
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub silence: Option<SilenceReport>,
+}
+
+/// Times in sidecars, tags and the catalog are always UTC, so archives from machines in different
+/// time zones, or from either side of a DST change, compare and sort as they should.
+pub fn format_time(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+pub fn sidecar_path(segment_path: &Path) -> PathBuf {
+    let mut name = segment_path.as_os_str().to_owned();
+    name.push(".json");
+    PathBuf::from(name)
+}
+
+/// Writes the sidecar for `segment_path`, going through a `.partial` file like the segment itself.
+pub fn write_sidecar(segment_path: &Path, sidecar: &SegmentSidecar) -> Result<PathBuf, Box<dyn Error>> {
+    let target = sidecar_path(segment_path);
+    let partial = segment_file::partial_path(&target);
+    let mut f = segment_file::create_new(&partial)?;
+    serde_json::to_writer_pretty(&mut f, sidecar)?;
+    f.write_all(b"\n")?;
+    f.sync_all()?;
+    Ok(segment_file::publish(&partial, &target)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::test_dir::TestDir;
+
+    fn sidecar() -> SegmentSidecar {
+        SegmentSidecar {
+            file: "segment.ogg".to_owned(),
+            start: "2024-05-01T14:37:05.000Z".to_owned(),
+            end: "2024-05-01T15:07:05.000Z".to_owned(),
+            duration_secs: 1800.,
+            samples: 79_380_000,
+            sample_rate: 44_100,
+            channels: 2,
+            device: "mic".to_owned(),
+            hostname: "host".to_owned(),
+            session_id: "session".to_owned(),
+            segment_index: 3,
+            format: "ogg".to_owned(),
+            encoder_settings: serde_json::json!({ "quality": 0.5 }),
+            peak_dbfs: vec![Some(-3.5), None],
+            rms_dbfs: vec![Some(-20.25), None],
+            clipped_samples: 0,
+            xruns: 1,
+            end_reason: EndReason::Duration,
+            tags: vec!["ARTIST=me".to_owned()],
+            gain: None,
+            gated_secs: None,
+            input_clipping: None,
+            silence: None,
+        }
+    }
+
+    #[test]
+    fn comments_carry_the_segment_and_then_user_tags() {
+        let meta = SegmentMeta {
+            start: Utc.with_ymd_and_hms(2024, 5, 1, 14, 37, 5).unwrap(),
+            device: "mic".to_owned(),
+            hostname: "host".to_owned(),
+            session_id: "session".to_owned(),
+            index: 7,
+            sample_rate: 48_000,
+            channels: 2,
+            user_tags: vec![("ARTIST".to_owned(), "me".to_owned()), ("DATE".to_owned(), "mine".to_owned())],
+        };
+        let comments = meta.vorbis_comments();
+        let comments: Vec<(&str, &str)> = comments.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(
+            comments,
+            [
+                ("DATE", "2024-05-01T14:37:05.000Z"),
+                ("ENCODER", concat!("akasha ", env!("CARGO_PKG_VERSION"))),
+                ("AKASHA_DEVICE", "mic"),
+                ("AKASHA_HOSTNAME", "host"),
+                ("AKASHA_SESSION", "session"),
+                ("AKASHA_SEGMENT_INDEX", "7"),
+                ("AKASHA_SAMPLE_RATE", "48000"),
+                ("ARTIST", "me"),
+                ("DATE", "mine"),
+            ]
+        );
+    }
+
+    #[test]
+    fn sidecar_goes_next_to_the_segment_and_reads_back() {
+        let dir = TestDir::new("sidecar");
+        let segment = dir.join("segment.ogg");
+        let path = write_sidecar(&segment, &sidecar()).unwrap();
+        assert_eq!(path, dir.join("segment.ogg.json"));
+        assert!(!segment_file::partial_path(&path).exists());
+
+        let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(json["end_reason"], "duration");
+        assert_eq!(json["peak_dbfs"], serde_json::json!([-3.5, null]));
+        // Stages that weren't used leave nothing behind
+        for field in ["gain", "gated_secs", "input_clipping", "silence"] {
+            assert!(json.get(field).is_none(), "{} was written", field);
+        }
+        let read: SegmentSidecar = serde_json::from_value(json).unwrap();
+        assert_eq!(read.samples, 79_380_000);
+        assert_eq!(read.rms_dbfs, [Some(-20.25), None]);
+    }
+
+    #[test]
+    fn sidecar_never_overwrites() {
+        let dir = TestDir::new("sidecar-taken");
+        let segment = dir.join("segment.ogg");
+        std::fs::write(sidecar_path(&segment), "taken").unwrap();
+        let path = write_sidecar(&segment, &sidecar()).unwrap();
+        assert_ne!(path, sidecar_path(&segment));
+        assert_eq!(std::fs::read_to_string(sidecar_path(&segment)).unwrap(), "taken");
+    }
+
+    #[test]
+    fn parses_tags() {
+        assert_eq!(parse_tag("artist=Someone"), Ok(("ARTIST".to_owned(), "Someone".to_owned())));
+        // Only the first `=` splits
+        assert_eq!(parse_tag("NOTE=a=b"), Ok(("NOTE".to_owned(), "a=b".to_owned())));
+        assert_eq!(parse_tag("EMPTY="), Ok(("EMPTY".to_owned(), String::new())));
+        for tag in ["no-equals", "=value", "BAD~KEY=x", "ÜBER=x", "TAB\t=x"] {
+            assert!(parse_tag(tag).is_err(), "{:?} was accepted", tag);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+/// Signal statistics for one segment, accumulated chunk by chunk.
+#[derive(Debug, Clone)]
+pub struct SegmentStats {
+    channels: usize,
+    frames: u64,
+    peak: Vec<f32>,
+    sum_of_squares: Vec<f64>,
+    clipped_samples: u64,
+}
+
+/// Samples this close to full scale count as clipped; float input can't tell us more than that.
+const CLIP_THRESHOLD: f32 = 0.999;
+
+/// Converts an amplitude ratio to dBFS. Digital silence has no finite level, so it comes out as `None`.
+pub fn amplitude_to_dbfs(amplitude: f32) -> Option<f32> {
+    if amplitude > 0. {
+        Some(20. * amplitude.log10())
+    } else {
+        None
+    }
+}
+
+impl SegmentStats {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            channels,
+            frames: 0,
+            peak: vec![0.; channels],
+            sum_of_squares: vec![0.; channels],
+            clipped_samples: 0,
+        }
+    }
+
+    /// Accumulate one interleaved chunk
+    pub fn add_chunk(&mut self, chunk: &[f32]) {
+        for frame in chunk.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let amplitude = sample.abs();
+                self.peak[channel] = self.peak[channel].max(amplitude);
+                self.sum_of_squares[channel] += (*sample as f64) * (*sample as f64);
+                if amplitude >= CLIP_THRESHOLD {
+                    self.clipped_samples += 1;
+                }
+            }
+        }
+        self.frames += (chunk.len() / self.channels) as u64;
+    }
+
+    pub fn clipped_samples(&self) -> u64 {
+        self.clipped_samples
+    }
+
+    pub fn peak_dbfs(&self) -> Vec<Option<f32>> {
+        self.peak.iter().map(|peak| amplitude_to_dbfs(*peak)).collect()
+    }
+
+    pub fn rms_dbfs(&self) -> Vec<Option<f32>> {
+        self.sum_of_squares
+            .iter()
+            .map(|sum| {
+                let mean = if self.frames > 0 { sum / self.frames as f64 } else { 0. };
+                amplitude_to_dbfs(mean.sqrt() as f32)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_db(actual: Option<f32>, expected: f32) {
+        let actual = actual.unwrap();
+        assert!((actual - expected).abs() < 0.01, "{} dBFS, expected {}", actual, expected);
+    }
+
+    #[test]
+    fn measures_each_channel_on_its_own() {
+        let mut stats = SegmentStats::new(2);
+        // Left: a square wave at half scale; right: nothing at all
+        for _ in 0..10 {
+            stats.add_chunk(&[0.5, 0., -0.5, 0.]);
+        }
+        assert_db(stats.peak_dbfs()[0], -6.02);
+        assert_db(stats.rms_dbfs()[0], -6.02);
+        assert_eq!(stats.peak_dbfs()[1], None);
+        assert_eq!(stats.rms_dbfs()[1], None);
+        assert_eq!(stats.clipped_samples(), 0);
+    }
+
+    #[test]
+    fn rms_spans_every_chunk() {
+        let mut stats = SegmentStats::new(1);
+        stats.add_chunk(&[1.; 100]);
+        stats.add_chunk(&[0.; 300]);
+        // Mean square of a quarter, i.e. RMS of a half
+        assert_db(stats.rms_dbfs()[0], -6.02);
+        assert_db(stats.peak_dbfs()[0], 0.);
+    }
+
+    #[test]
+    fn counts_samples_at_full_scale_as_clipped() {
+        let mut stats = SegmentStats::new(2);
+        stats.add_chunk(&[1., -1., 0.9995, 0.998, -1.5, 0.]);
+        assert_eq!(stats.clipped_samples(), 4);
+    }
+
+    #[test]
+    fn nothing_recorded_is_silence() {
+        let stats = SegmentStats::new(1);
+        assert_eq!(stats.peak_dbfs(), [None]);
+        assert_eq!(stats.rms_dbfs(), [None]);
+    }
+}
@@ -0,0 +1,310 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+
+use async_fn_stream::fn_stream;
+use futures_core::Stream;
+use futures_util::StreamExt;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::segment_stats::amplitude_to_dbfs;
+use crate::{hook, Chunk, ProgramState, SilenceOpts};
+
+/// What `rec --exit-on-silence` exits with, so a supervisor can tell it from a crash.
+pub const SILENCE_EXIT_CODE: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SilenceKind {
+    /// Nothing but exact zeros: usually muted hardware or the wrong device
+    Digital,
+    /// Below `--silence-threshold`, but not quite zero
+    Near,
+}
+
+impl SilenceKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SilenceKind::Digital => "digital",
+            SilenceKind::Near => "near",
+        }
+    }
+}
+
+/// How much of one segment's input was silent, in whole seconds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SilenceReport {
+    /// Seconds that were all exact zeros
+    pub digital_secs: u64,
+    /// Seconds below `--silence-threshold`, digital silence included
+    pub below_threshold_secs: u64,
+    /// Set if the input had been silent for `--silence-after` at some point during the segment
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flagged: Option<SilenceKind>,
+}
+
+/// Silence seen by the detector, until the recorder takes it for a segment.
+#[derive(Debug, Default)]
+pub struct SilenceTrack {
+    report: SilenceReport,
+}
+
+impl SilenceTrack {
+    /// What's been seen since the last `reset`.
+    pub fn report(&self) -> SilenceReport {
+        self.report.clone()
+    }
+
+    /// Starts over, for the next segment.
+    pub fn reset(&mut self) {
+        self.report = SilenceReport::default();
+    }
+}
+
+/// Looks at the input a second at a time for digital silence and for levels below `--silence-threshold`.
+/// Once either has gone on for `--silence-after` it warns, whether or not `--display` is on, runs
+/// `--silence-hook`, and with `--exit-on-silence` asks the program to quit.
+pub struct SilenceDetector {
+    threshold_dbfs: f32,
+    after_secs: u64,
+    hook: Option<String>,
+    exit_on_silence: bool,
+    channels: usize,
+    block_frames: u64,
+    track: Arc<Mutex<SilenceTrack>>,
+    frames_in_block: u64,
+    /// Per channel, over the second so far
+    sums_of_squares: Vec<f64>,
+    all_zero: bool,
+    /// Consecutive silent seconds
+    run_secs: u64,
+    /// Whether every one of them was digital silence
+    run_digital: bool,
+    alerted: bool,
+    exit_requested: bool,
+}
+
+impl SilenceDetector {
+    pub fn from_opts(opts: &SilenceOpts, channels: usize, sample_rate: u32) -> Self {
+        Self {
+            threshold_dbfs: opts.silence_threshold,
+            after_secs: opts.silence_after.as_secs().max(1),
+            hook: opts.silence_hook.clone(),
+            exit_on_silence: opts.exit_on_silence,
+            channels,
+            block_frames: sample_rate as u64,
+            track: Arc::new(Mutex::new(SilenceTrack::default())),
+            frames_in_block: 0,
+            sums_of_squares: vec![0.; channels],
+            all_zero: true,
+            run_secs: 0,
+            run_digital: true,
+            alerted: false,
+            exit_requested: false,
+        }
+    }
+
+    /// Where silence is written down, for the recorder to take at the end of each segment.
+    pub fn track(&self) -> Arc<Mutex<SilenceTrack>> {
+        self.track.clone()
+    }
+
+    pub fn process(&mut self, chunk: &[f32]) {
+        for frame in chunk.chunks_exact(self.channels) {
+            for (sum, sample) in self.sums_of_squares.iter_mut().zip(frame) {
+                *sum += (*sample as f64) * (*sample as f64);
+                self.all_zero &= *sample == 0.;
+            }
+            self.frames_in_block += 1;
+            if self.frames_in_block >= self.block_frames {
+                self.end_block();
+            }
+        }
+    }
+
+    /// Whether `--exit-on-silence` has been triggered; only says so once.
+    fn take_exit_request(&mut self) -> bool {
+        std::mem::take(&mut self.exit_requested)
+    }
+
+    fn end_block(&mut self) {
+        // The loudest channel decides, so one dead mic next to a live one doesn't count as silence
+        let loudest_dbfs = self
+            .sums_of_squares
+            .iter()
+            .filter_map(|sum| amplitude_to_dbfs((sum / self.frames_in_block as f64).sqrt() as f32))
+            .fold(f32::NEG_INFINITY, f32::max);
+        let digital = self.all_zero;
+        let silent = digital || loudest_dbfs < self.threshold_dbfs;
+        self.frames_in_block = 0;
+        self.sums_of_squares.iter_mut().for_each(|sum| *sum = 0.);
+        self.all_zero = true;
+
+        let mut track = self.track.lock().unwrap_or_else(|e| e.into_inner());
+        if !silent {
+            if self.alerted {
+                info!("Input is back after {}s of silence", self.run_secs);
+            }
+            self.run_secs = 0;
+            self.run_digital = true;
+            self.alerted = false;
+            return;
+        }
+        track.report.below_threshold_secs += 1;
+        if digital {
+            track.report.digital_secs += 1;
+        }
+        self.run_secs += 1;
+        self.run_digital &= digital;
+        if self.run_secs < self.after_secs {
+            return;
+        }
+        let kind = if self.run_digital {
+            SilenceKind::Digital
+        } else {
+            SilenceKind::Near
+        };
+        // Every segment the silence reaches gets flagged, not just the one it started in
+        track.report.flagged = Some(kind);
+        if self.alerted {
+            return;
+        }
+        self.alerted = true;
+        match kind {
+            SilenceKind::Digital => warn!(
+                "Input has been all zeros for {}s; is the device muted, or the wrong one?",
+                self.run_secs
+            ),
+            SilenceKind::Near => warn!(
+                "Input has been below {}dBFS for {}s; is the microphone connected?",
+                self.threshold_dbfs, self.run_secs
+            ),
+        }
+        if let Some(command) = &self.hook {
+            hook::run(
+                command,
+                "silence",
+                &[
+                    ("AKASHA_SILENCE", kind.as_str().to_owned()),
+                    ("AKASHA_SILENT_SECS", self.run_secs.to_string()),
+                ],
+            );
+        }
+        if self.exit_on_silence {
+            warn!("Stopping, as asked by --exit-on-silence");
+            self.exit_requested = true;
+        }
+    }
+}
+
+/// Runs every chunk past `detector`, leaving it as it is, and quits if it asks to.
+pub fn getstream_silence_detect<S: Stream<Item = Chunk> + Unpin>(
+    mut audio_stream: S,
+    mut detector: SilenceDetector,
+    state: Arc<ProgramState>,
+) -> impl Stream<Item = Chunk> {
+    fn_stream(|emitter| async move {
+        while let Some(chunk) = audio_stream.next().await {
+            detector.process(&chunk);
+            if detector.take_exit_request() {
+                state.exit_code.store(SILENCE_EXIT_CODE, Ordering::Relaxed);
+                state.quit_msg.send_quit().await;
+            }
+            emitter.emit(chunk).await;
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_opts::rec_opts;
+
+    const SAMPLE_RATE: u32 = 1_000;
+
+    fn detector(args: &[&str], channels: usize) -> SilenceDetector {
+        SilenceDetector::from_opts(&rec_opts(args).silence, channels, SAMPLE_RATE)
+    }
+
+    /// One second of interleaved DC, one channel per amplitude; the detector only looks at RMS, so that will do.
+    fn second(amplitudes: &[f32]) -> Vec<f32> {
+        (0..SAMPLE_RATE).flat_map(|_| amplitudes.iter().copied()).collect()
+    }
+
+    fn report(detector: &SilenceDetector) -> SilenceReport {
+        detector.track().lock().unwrap().report()
+    }
+
+    #[test]
+    fn counts_digital_and_near_silent_seconds() {
+        let mut detector = detector(&["--silence-threshold", "-70dBFS"], 1);
+        detector.process(&second(&[0.]));
+        detector.process(&second(&[0.0001]));
+        detector.process(&second(&[0.1]));
+        // Half a second doesn't count until the rest of it comes in
+        detector.process(&second(&[0.])[..SAMPLE_RATE as usize / 2]);
+        assert_eq!(
+            report(&detector),
+            SilenceReport { digital_secs: 1, below_threshold_secs: 2, flagged: None }
+        );
+    }
+
+    #[test]
+    fn flags_silence_that_goes_on_long_enough() {
+        let mut detector = detector(&["--silence-after", "3s"], 1);
+        for _ in 0..2 {
+            detector.process(&second(&[0.]));
+        }
+        assert_eq!(report(&detector).flagged, None);
+        detector.process(&second(&[0.]));
+        assert_eq!(report(&detector).flagged, Some(SilenceKind::Digital));
+
+        // The next segment is flagged too while the silence lasts, as near silence once it isn't all zeros
+        detector.track().lock().unwrap().reset();
+        detector.process(&second(&[0.00001]));
+        assert_eq!(report(&detector).flagged, Some(SilenceKind::Near));
+    }
+
+    #[test]
+    fn a_loud_second_ends_the_run() {
+        let mut detector = detector(&["--silence-after", "3s"], 1);
+        for amplitude in [0., 0., 0.1, 0., 0.] {
+            detector.process(&second(&[amplitude]));
+        }
+        assert_eq!(report(&detector).flagged, None);
+        assert_eq!(report(&detector).below_threshold_secs, 4);
+    }
+
+    #[test]
+    fn loudest_channel_decides() {
+        let mut detector = detector(&["--silence-after", "1s"], 2);
+        detector.process(&second(&[0., 0.1]));
+        assert_eq!(report(&detector), SilenceReport::default());
+        detector.process(&second(&[0., 0.00001]));
+        assert_eq!(report(&detector).flagged, Some(SilenceKind::Near));
+        assert_eq!(report(&detector).digital_secs, 0);
+    }
+
+    #[test]
+    fn asks_to_exit_once() {
+        let mut detector = detector(&["--silence-after", "2s", "--exit-on-silence"], 1);
+        detector.process(&second(&[0.]));
+        assert!(!detector.take_exit_request());
+        detector.process(&second(&[0.]));
+        assert!(detector.take_exit_request());
+        assert!(!detector.take_exit_request());
+        detector.process(&second(&[0.]));
+        assert!(!detector.take_exit_request());
+    }
+
+    #[test]
+    fn sidecar_leaves_out_an_unflagged_segment() {
+        let report = SilenceReport { digital_secs: 2, below_threshold_secs: 3, flagged: None };
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"digital_secs":2,"below_threshold_secs":3}"#
+        );
+        let flagged = SilenceReport { flagged: Some(SilenceKind::Near), ..report };
+        assert!(serde_json::to_string(&flagged).unwrap().ends_with(r#""flagged":"near"}"#));
+    }
+}
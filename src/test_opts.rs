@@ -0,0 +1,9 @@
+use clap::Parser;
+
+use crate::{Cli, Rec};
+
+/// `akasha rec` with `args`, parsed as they would be from the command line.
+pub fn rec_opts(args: &[&str]) -> Rec {
+    let cli = Cli::parse_from(["akasha", "rec"].iter().chain(args));
+    *cli.cmd.into_rec().unwrap()
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, FixedOffset, Local, NaiveDate, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+
+/// `--time-format iso8601`: ISO 8601's basic format, which sorts chronologically as long as the offset
+/// doesn't change, and has no colons to upset Windows or SMB shares.
+pub const ISO8601_TIME_FORMAT: &str = "%Y%m%dT%H%M%S%z";
+
+/// Expands the presets `--time-format` takes, and checks the rest are valid strftime formats.
+pub fn parse_time_format(s: &str) -> Result<String, String> {
+    let format = match s {
+        "iso8601" => ISO8601_TIME_FORMAT,
+        format => format,
+    };
+    if StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Err(format!("`{}` is not a valid strftime format", format));
+    }
+    Ok(format.to_owned())
+}
+
+/// The time zone segment names are written in, for `--time-zone`.
+/// Sidecars and the catalog are always in UTC, whatever this is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameTimeZone {
+    Utc,
+    Local,
+    Named(Tz),
+}
+
+/// Parses `utc`, `local`, or an IANA zone name such as `Europe/Berlin`.
+pub fn parse(s: &str) -> Result<NameTimeZone, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "utc" | "z" => Ok(NameTimeZone::Utc),
+        "local" => Ok(NameTimeZone::Local),
+        _ => s
+            .parse()
+            .map(NameTimeZone::Named)
+            .map_err(|_| format!("`{}` is not utc, local or an IANA time zone such as Europe/Berlin", s)),
+    }
+}
+
+impl fmt::Display for NameTimeZone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameTimeZone::Utc => write!(f, "UTC"),
+            NameTimeZone::Local => write!(f, "local time"),
+            NameTimeZone::Named(tz) => write!(f, "{}", tz),
+        }
+    }
+}
+
+impl NameTimeZone {
+    /// `time` in this zone, formatted with a strftime `format`.
+    pub fn format(&self, time: &DateTime<Utc>, format: &str) -> String {
+        match self {
+            NameTimeZone::Utc => time.format(format).to_string(),
+            NameTimeZone::Local => time.with_timezone(&Local).format(format).to_string(),
+            NameTimeZone::Named(tz) => time.with_timezone(tz).format(format).to_string(),
+        }
+    }
+
+    /// Reads a time written without an offset as being in this zone. Of the two readings
+    /// an hour repeated by DST gives, the earlier is taken.
+    pub fn read_naive(&self, naive: &NaiveDateTime) -> Option<DateTime<FixedOffset>> {
+        match self {
+            NameTimeZone::Utc => Some(Utc.from_utc_datetime(naive).fixed_offset()),
+            NameTimeZone::Local => Some(Local.from_local_datetime(naive).earliest()?.fixed_offset()),
+            NameTimeZone::Named(tz) => Some(tz.from_local_datetime(naive).earliest()?.fixed_offset()),
+        }
+    }
+
+    /// Whether this zone's offset changes during the year, so that names written in it can sort out
+    /// of order (and, without `%z`, repeat) when the clocks go back.
+    pub fn observes_dst(&self) -> bool {
+        let year = Utc::now().year();
+        let offset_on = |month| {
+            let naive = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(12, 0, 0)?;
+            let time = Utc.from_utc_datetime(&naive);
+            Some(match self {
+                NameTimeZone::Utc => 0,
+                NameTimeZone::Local => time.with_timezone(&Local).offset().fix().local_minus_utc(),
+                NameTimeZone::Named(tz) => time.with_timezone(tz).offset().fix().local_minus_utc(),
+            })
+        };
+        offset_on(1) != offset_on(7)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn berlin() -> NameTimeZone {
+        parse("Europe/Berlin").unwrap()
+    }
+
+    fn naive(s: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn parses_zones() {
+        assert_eq!(parse("utc"), Ok(NameTimeZone::Utc));
+        assert_eq!(parse("Z"), Ok(NameTimeZone::Utc));
+        assert_eq!(parse("Local"), Ok(NameTimeZone::Local));
+        assert_eq!(berlin(), NameTimeZone::Named(Tz::Europe__Berlin));
+        assert!(parse("Mars/Olympus_Mons").is_err());
+    }
+
+    #[test]
+    fn expands_the_iso8601_preset() {
+        assert_eq!(parse_time_format("iso8601").unwrap(), ISO8601_TIME_FORMAT);
+        assert_eq!(parse_time_format("%Y-%m-%d").unwrap(), "%Y-%m-%d");
+        assert!(parse_time_format("%Q").is_err());
+    }
+
+    #[test]
+    fn formats_in_the_zone() {
+        let time = Utc.with_ymd_and_hms(2024, 7, 1, 12, 0, 0).unwrap();
+        assert_eq!(NameTimeZone::Utc.format(&time, ISO8601_TIME_FORMAT), "20240701T120000+0000");
+        assert_eq!(berlin().format(&time, ISO8601_TIME_FORMAT), "20240701T140000+0200");
+    }
+
+    #[test]
+    fn repeated_hour_reads_as_the_first_time_round() {
+        // Clocks in Berlin went back from 03:00 CEST to 02:00 CET on 27 October 2024
+        let read = berlin().read_naive(&naive("2024-10-27 02:30:00")).unwrap();
+        assert_eq!(read.offset().local_minus_utc(), 2 * 3600);
+        assert_eq!(read.with_timezone(&Utc), Utc.with_ymd_and_hms(2024, 10, 27, 0, 30, 0).unwrap());
+        // Either side of it there's only one reading
+        let after = berlin().read_naive(&naive("2024-10-27 03:30:00")).unwrap();
+        assert_eq!(after.offset().local_minus_utc(), 3600);
+    }
+
+    #[test]
+    fn skipped_hour_has_no_reading() {
+        // ...and forward from 02:00 CET to 03:00 CEST on 31 March 2024
+        assert_eq!(berlin().read_naive(&naive("2024-03-31 02:30:00")), None);
+        assert!(NameTimeZone::Utc.read_naive(&naive("2024-03-31 02:30:00")).is_some());
+    }
+
+    #[test]
+    fn knows_which_zones_observe_dst() {
+        assert!(!NameTimeZone::Utc.observes_dst());
+        assert!(berlin().observes_dst());
+        assert!(!parse("Asia/Tokyo").unwrap().observes_dst());
+    }
+}
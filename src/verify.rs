@@ -0,0 +1,159 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::audio_info;
+use crate::catalog;
+use crate::decode;
+use crate::encrypt::{self, Identities};
+use crate::manifest::{self, ManifestEntry};
+
+// Decoded lengths are in whole samples, and Opus decodes at a different rate than it was fed
+const DURATION_TOLERANCE_SECS: f64 = 0.01;
+
+/// Something wrong with a segment listed in the manifest.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    Missing,
+    /// The file's hash no longer matches the one recorded when it was finalized
+    Corrupted,
+    /// The file doesn't decode cleanly from start to end
+    Undecodable(String),
+    /// The file decodes, but to less audio than it should hold
+    Truncated { expected_secs: f64, decoded_secs: f64 },
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing"),
+            Problem::Corrupted => write!(f, "corrupted: SHA-256 doesn't match the manifest"),
+            Problem::Undecodable(e) => write!(f, "corrupted: does not decode: {}", e),
+            Problem::Truncated {
+                expected_secs,
+                decoded_secs,
+            } => write!(
+                f,
+                "truncated: {:.3} s of audio, expected {:.3} s",
+                decoded_secs, expected_secs
+            ),
+        }
+    }
+}
+
+/// What `verify` found.
+pub struct Report {
+    /// Every manifest entry that has problems, with all of them
+    pub problems: Vec<(String, Vec<Problem>)>,
+    pub checked: usize,
+    /// Segment files on disk the manifest doesn't know about
+    pub unlisted: Vec<String>,
+    /// `.partial` files: still being recorded, or orphaned by a recording that never finished them
+    pub partial: Vec<String>,
+    /// Encrypted segments that could only be hash-checked, for want of an identity to decrypt them
+    pub hash_only: usize,
+}
+
+/// Checks every segment in the manifest in `dir`: that it's there, that its hash still matches,
+/// and that it decodes in full to as much audio as its headers and the catalog say it holds.
+/// Encrypted segments are decrypted with `identities`; without any, only their hashes are checked.
+pub fn verify(dir: &Path, identities: &Identities) -> Result<Report, Box<dyn Error>> {
+    let entries = manifest::read(dir)?.ok_or_else(|| {
+        format!(
+            "No {} in {}; segments only get one as they're recorded",
+            manifest::MANIFEST_FILE_NAME,
+            dir.display()
+        )
+    })?;
+    let durations: HashMap<String, f64> = catalog::read(dir)?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| (entry.path, entry.duration_secs))
+        .collect();
+
+    let mut problems = Vec::new();
+    let mut hash_only = 0;
+    for entry in &entries {
+        debug!("Verifying {}", entry.path);
+        let encrypted = encrypt::is_encrypted(&dir.join(&entry.path)).unwrap_or(false);
+        let decode = !encrypted || !identities.is_empty();
+        if !decode {
+            hash_only += 1;
+        }
+        let expected_secs = durations.get(&entry.path).copied();
+        let found = check_segment(dir, entry, expected_secs, decode.then_some(identities));
+        if !found.is_empty() {
+            problems.push((entry.path.clone(), found));
+        }
+    }
+
+    let listed: HashSet<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+    let relative = |path: &PathBuf| path.strip_prefix(dir).unwrap_or(path).to_string_lossy().into_owned();
+    let unlisted = catalog::find_segment_files(dir)?
+        .iter()
+        .map(relative)
+        .filter(|path| !listed.contains(path.as_str()))
+        .collect();
+    let partial = catalog::find_partial_files(dir)?.iter().map(relative).collect();
+    Ok(Report {
+        problems,
+        checked: entries.len(),
+        unlisted,
+        partial,
+        hash_only,
+    })
+}
+
+/// Decodes the segment as well as hashing it if there are `identities` to decode it with.
+fn check_segment(
+    dir: &Path,
+    entry: &ManifestEntry,
+    expected_secs: Option<f64>,
+    identities: Option<&Identities>,
+) -> Vec<Problem> {
+    let path = dir.join(&entry.path);
+    if !path.exists() {
+        return vec![Problem::Missing];
+    }
+    let mut problems = Vec::new();
+    match catalog::sha256_file(&path) {
+        Ok(sha256) if sha256 == entry.sha256 => {}
+        Ok(_) => problems.push(Problem::Corrupted),
+        Err(e) => problems.push(Problem::Undecodable(e.to_string())),
+    }
+    let Some(identities) = identities else {
+        return problems;
+    };
+    let decoded_secs = match decoded_duration(&path, identities) {
+        Ok(secs) => secs,
+        Err(e) => {
+            problems.push(Problem::Undecodable(e.to_string()));
+            return problems;
+        }
+    };
+    // What the file's own headers claim, then what the recorder wrote down
+    let header_secs = audio_info::read(&path, identities).ok().map(|info| info.duration_secs());
+    let expected_secs = header_secs.into_iter().chain(expected_secs).reduce(f64::max);
+    if let Some(expected_secs) = expected_secs {
+        if decoded_secs + DURATION_TOLERANCE_SECS < expected_secs {
+            problems.push(Problem::Truncated {
+                expected_secs,
+                decoded_secs,
+            });
+        }
+    }
+    problems
+}
+
+fn decoded_duration(path: &Path, identities: &Identities) -> Result<f64, Box<dyn Error>> {
+    let mut reader = decode::open(path, identities)?;
+    let channels = reader.channels().max(1) as usize;
+    let mut frames = 0;
+    while let Some(chunk) = reader.read_chunk()? {
+        frames += chunk.len() / channels;
+    }
+    Ok(frames as f64 / reader.sample_rate() as f64)
+}
@@ -0,0 +1,149 @@
+use std::error::Error;
+use std::io::Read;
+use std::mem::MaybeUninit;
+use std::os::raw::{c_int, c_long};
+use std::ptr;
+
+use aotuv_lancer_vorbis_sys::{
+    vorbis_block, vorbis_block_clear, vorbis_block_init, vorbis_dsp_clear, vorbis_dsp_state,
+    vorbis_synthesis, vorbis_synthesis_blockin, vorbis_synthesis_headerin, vorbis_synthesis_init,
+    vorbis_synthesis_pcmout, vorbis_synthesis_read,
+};
+use ogg_next_sys::ogg_packet;
+
+use crate::ogg_demux::{OggPacket, OggPacketReader};
+use crate::vorbis_encoder::{check, VorbisComment, VorbisInfo};
+
+struct SynthesisDsp {
+    dsp: Box<vorbis_dsp_state>,
+    block: Box<vorbis_block>,
+}
+
+impl SynthesisDsp {
+    fn new(info: &mut VorbisInfo) -> Result<Self, Box<dyn Error>> {
+        let mut dsp = Box::new(MaybeUninit::<vorbis_dsp_state>::uninit());
+        check(
+            unsafe { vorbis_synthesis_init(dsp.as_mut_ptr(), &mut *info.0) },
+            "vorbis_synthesis_init",
+        )?;
+        let mut dsp = unsafe { Box::from_raw(Box::into_raw(dsp) as *mut vorbis_dsp_state) };
+        let mut block = Box::new(MaybeUninit::<vorbis_block>::uninit());
+        if let Err(e) = check(
+            unsafe { vorbis_block_init(&mut *dsp, block.as_mut_ptr()) },
+            "vorbis_block_init",
+        ) {
+            unsafe { vorbis_dsp_clear(&mut *dsp) };
+            return Err(e);
+        }
+        let block = unsafe { Box::from_raw(Box::into_raw(block) as *mut vorbis_block) };
+        Ok(Self { dsp, block })
+    }
+}
+
+impl Drop for SynthesisDsp {
+    fn drop(&mut self) {
+        unsafe {
+            vorbis_block_clear(&mut *self.block);
+            vorbis_dsp_clear(&mut *self.dsp);
+        }
+    }
+}
+
+/// Borrows a demuxed packet as a libvorbis `ogg_packet`. libvorbis only reads through the pointer.
+fn raw_packet(packet: &mut OggPacket, packetno: i64) -> ogg_packet {
+    ogg_packet {
+        packet: packet.data.as_mut_ptr(),
+        bytes: packet.data.len() as c_long,
+        b_o_s: (packetno == 0) as c_long,
+        e_o_s: packet.is_last as c_long,
+        granulepos: packet.granule.map_or(-1, |g| g as i64),
+        packetno,
+    }
+}
+
+/// Ogg Vorbis decoder, producing interleaved f32 chunks.
+pub struct VorbisDecoder<R: Read> {
+    // Field order is drop order, as in `VorbisEncoder`
+    dsp: SynthesisDsp,
+    _comment: VorbisComment,
+    info: VorbisInfo,
+    packets: OggPacketReader<R>,
+    packetno: i64,
+}
+
+impl<R: Read> VorbisDecoder<R> {
+    pub fn new(source: R) -> Result<Self, Box<dyn Error>> {
+        let mut packets = OggPacketReader::new(source);
+        let mut info = VorbisInfo::new();
+        let mut comment = VorbisComment::new();
+        for packetno in 0..3 {
+            let mut packet = packets
+                .next_packet()?
+                .ok_or("Ogg stream ends inside the Vorbis headers")?;
+            let mut raw = raw_packet(&mut packet, packetno);
+            check(
+                unsafe { vorbis_synthesis_headerin(&mut *info.0, &mut *comment.0, &mut raw) },
+                "vorbis_synthesis_headerin",
+            )?;
+        }
+        let dsp = SynthesisDsp::new(&mut info)?;
+        Ok(Self {
+            dsp,
+            _comment: comment,
+            info,
+            packets,
+            packetno: 3,
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.info.0.rate as u32
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.info.0.channels as u16
+    }
+
+    /// Decodes up to the next packet that yields audio. `None` at the end of the stream.
+    pub fn decode_chunk(&mut self) -> Result<Option<Vec<f32>>, Box<dyn Error>> {
+        loop {
+            let chunk = self.pending_pcm();
+            if !chunk.is_empty() {
+                return Ok(Some(chunk));
+            }
+            let Some(mut packet) = self.packets.next_packet()? else {
+                return Ok(None);
+            };
+            let mut raw = raw_packet(&mut packet, self.packetno);
+            self.packetno += 1;
+            unsafe {
+                // Undecodable packets are skipped, as a player would
+                if vorbis_synthesis(&mut *self.dsp.block, &mut raw) == 0 {
+                    check(
+                        vorbis_synthesis_blockin(&mut *self.dsp.dsp, &mut *self.dsp.block),
+                        "vorbis_synthesis_blockin",
+                    )?;
+                }
+            }
+        }
+    }
+
+    fn pending_pcm(&mut self) -> Vec<f32> {
+        let channels = self.channels() as usize;
+        let mut out = Vec::new();
+        unsafe {
+            let mut pcm: *mut *mut f32 = ptr::null_mut();
+            let frames = vorbis_synthesis_pcmout(&mut *self.dsp.dsp, &mut pcm);
+            if frames > 0 {
+                out.reserve(frames as usize * channels);
+                for i in 0..frames as usize {
+                    for channel in 0..channels {
+                        out.push(*(*pcm.add(channel)).add(i));
+                    }
+                }
+                vorbis_synthesis_read(&mut *self.dsp.dsp, frames as c_int);
+            }
+        }
+        out
+    }
+}
@@ -0,0 +1,397 @@
+use std::error::Error;
+use std::ffi::CString;
+use std::io::Write;
+use std::mem::MaybeUninit;
+use std::os::raw::{c_int, c_long};
+use std::ptr;
+
+use aotuv_lancer_vorbis_sys::{
+    vorbis_analysis, vorbis_analysis_blockout, vorbis_analysis_buffer, vorbis_analysis_headerout,
+    vorbis_analysis_init, vorbis_analysis_wrote, vorbis_bitrate_addblock,
+    vorbis_bitrate_flushpacket, vorbis_block, vorbis_block_clear, vorbis_block_init,
+    vorbis_comment, vorbis_comment_add_tag, vorbis_comment_clear, vorbis_comment_init,
+    vorbis_dsp_clear, vorbis_dsp_state, vorbis_encode_ctl, vorbis_encode_init,
+    vorbis_encode_init_vbr, vorbis_encode_setup_init, vorbis_encode_setup_managed, vorbis_info,
+    vorbis_info_clear, vorbis_info_init, OV_ECTL_RATEMANAGE2_SET,
+};
+use ogg_next_sys::ogg_packet;
+use serde::Serialize;
+
+use crate::ogg_mux::OggStream;
+
+/// How libvorbis should spend its bits. Bitrates are in bit/s.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum VorbisBitrateStrategy {
+    /// Pure VBR, with the quality mode picked to land near a target bitrate
+    Vbr { target_bitrate: u32 },
+    /// Pure VBR at a fixed quality, in libvorbis' -0.1..1.0 range
+    QualityVbr { quality: f32 },
+    /// Bitrate management engine keeps the average near the target
+    Abr { average_bitrate: u32 },
+    /// Bitrate management engine treats the maximum as a hard limit
+    ConstrainedAbr { maximum_bitrate: u32 },
+    /// Full control over the bitrate management engine; at least one bound must be set
+    Managed {
+        minimum_bitrate: Option<u32>,
+        average_bitrate: Option<u32>,
+        maximum_bitrate: Option<u32>,
+    },
+}
+
+fn bitrate_or_unset(bitrate: Option<u32>) -> c_long {
+    bitrate.map(|b| b as c_long).unwrap_or(-1)
+}
+
+pub(crate) fn check(ret: c_int, what: &str) -> Result<(), Box<dyn Error>> {
+    if ret < 0 {
+        return Err(format!("{} failed with libvorbis error {}", what, ret).into());
+    }
+    Ok(())
+}
+
+// The libvorbis structs get boxed so they keep a fixed address, as libvorbis
+// holds pointers between them. Each one clears itself on drop.
+
+pub(crate) struct VorbisInfo(pub(crate) Box<vorbis_info>);
+
+impl VorbisInfo {
+    pub(crate) fn new() -> Self {
+        let mut info = Box::new(MaybeUninit::<vorbis_info>::uninit());
+        unsafe {
+            vorbis_info_init(info.as_mut_ptr());
+            Self(Box::from_raw(Box::into_raw(info) as *mut vorbis_info))
+        }
+    }
+}
+
+impl Drop for VorbisInfo {
+    fn drop(&mut self) {
+        unsafe { vorbis_info_clear(&mut *self.0) }
+    }
+}
+
+pub(crate) struct VorbisComment(pub(crate) Box<vorbis_comment>);
+
+impl VorbisComment {
+    pub(crate) fn new() -> Self {
+        let mut comment = Box::new(MaybeUninit::<vorbis_comment>::uninit());
+        unsafe {
+            vorbis_comment_init(comment.as_mut_ptr());
+            Self(Box::from_raw(Box::into_raw(comment) as *mut vorbis_comment))
+        }
+    }
+
+    fn add_tag(&mut self, tag: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let tag = CString::new(tag)?;
+        let value = CString::new(value)?;
+        unsafe { vorbis_comment_add_tag(&mut *self.0, tag.as_ptr(), value.as_ptr()) };
+        Ok(())
+    }
+}
+
+impl Drop for VorbisComment {
+    fn drop(&mut self) {
+        unsafe { vorbis_comment_clear(&mut *self.0) }
+    }
+}
+
+struct VorbisDsp {
+    dsp: Box<vorbis_dsp_state>,
+    block: Box<vorbis_block>,
+}
+
+impl VorbisDsp {
+    fn new(info: &mut VorbisInfo) -> Result<Self, Box<dyn Error>> {
+        let mut dsp = Box::new(MaybeUninit::<vorbis_dsp_state>::uninit());
+        check(
+            unsafe { vorbis_analysis_init(dsp.as_mut_ptr(), &mut *info.0) },
+            "vorbis_analysis_init",
+        )?;
+        let mut dsp = unsafe { Box::from_raw(Box::into_raw(dsp) as *mut vorbis_dsp_state) };
+        let mut block = Box::new(MaybeUninit::<vorbis_block>::uninit());
+        if let Err(e) = check(
+            unsafe { vorbis_block_init(&mut *dsp, block.as_mut_ptr()) },
+            "vorbis_block_init",
+        ) {
+            unsafe { vorbis_dsp_clear(&mut *dsp) };
+            return Err(e);
+        }
+        let block = unsafe { Box::from_raw(Box::into_raw(block) as *mut vorbis_block) };
+        Ok(Self { dsp, block })
+    }
+}
+
+impl Drop for VorbisDsp {
+    fn drop(&mut self) {
+        unsafe {
+            vorbis_block_clear(&mut *self.block);
+            vorbis_dsp_clear(&mut *self.dsp);
+        }
+    }
+}
+
+/// Ogg Vorbis encoder taking planar f32 blocks.
+///
+/// Unlike `vorbis_rs`, this exposes the managed bitrate modes and lets the caller
+/// force pending Ogg pages out to the sink, which bounds how much audio a crash can take with it.
+pub struct VorbisEncoder<W: Write> {
+    // Field order is drop order: the DSP state must be cleared before the info it points into
+    dsp: VorbisDsp,
+    _comment: VorbisComment,
+    _info: VorbisInfo,
+    ogg: OggStream,
+    sink: W,
+    channels: usize,
+    minimum_page_data_size: Option<u16>,
+}
+
+impl<W: Write> VorbisEncoder<W> {
+    pub fn new<'a>(
+        tags: impl IntoIterator<Item = (&'a str, &'a str)>,
+        sample_rate: u32,
+        channels: u16,
+        strategy: VorbisBitrateStrategy,
+        minimum_page_data_size: Option<u16>,
+        mut sink: W,
+    ) -> Result<Self, Box<dyn Error>> {
+        if channels == 0 || channels > 255 {
+            return Err(format!("Vorbis can not encode {} channels", channels).into());
+        }
+        let mut info = VorbisInfo::new();
+        let vi: *mut vorbis_info = &mut *info.0;
+        let ch = channels as c_long;
+        let rate = sample_rate as c_long;
+        unsafe {
+            match strategy {
+                VorbisBitrateStrategy::Vbr { target_bitrate } => {
+                    check(
+                        vorbis_encode_setup_managed(vi, ch, rate, -1, target_bitrate as c_long, -1),
+                        "vorbis_encode_setup_managed",
+                    )?;
+                    // Turning the bitrate management engine off again selects a true VBR mode
+                    check(
+                        vorbis_encode_ctl(vi, OV_ECTL_RATEMANAGE2_SET as c_int, ptr::null_mut()),
+                        "vorbis_encode_ctl",
+                    )?;
+                    check(vorbis_encode_setup_init(vi), "vorbis_encode_setup_init")?;
+                }
+                VorbisBitrateStrategy::QualityVbr { quality } => {
+                    check(
+                        vorbis_encode_init_vbr(vi, ch, rate, quality),
+                        "vorbis_encode_init_vbr",
+                    )?;
+                }
+                VorbisBitrateStrategy::Abr { average_bitrate } => {
+                    check(
+                        vorbis_encode_init(vi, ch, rate, -1, average_bitrate as c_long, -1),
+                        "vorbis_encode_init",
+                    )?;
+                }
+                VorbisBitrateStrategy::ConstrainedAbr { maximum_bitrate } => {
+                    check(
+                        vorbis_encode_init(vi, ch, rate, maximum_bitrate as c_long, -1, -1),
+                        "vorbis_encode_init",
+                    )?;
+                }
+                VorbisBitrateStrategy::Managed {
+                    minimum_bitrate,
+                    average_bitrate,
+                    maximum_bitrate,
+                } => {
+                    check(
+                        vorbis_encode_init(
+                            vi,
+                            ch,
+                            rate,
+                            bitrate_or_unset(maximum_bitrate),
+                            bitrate_or_unset(average_bitrate),
+                            bitrate_or_unset(minimum_bitrate),
+                        ),
+                        "vorbis_encode_init",
+                    )?;
+                }
+            }
+        }
+
+        let mut comment = VorbisComment::new();
+        for (tag, value) in tags {
+            comment.add_tag(tag, value)?;
+        }
+
+        let mut dsp = VorbisDsp::new(&mut info)?;
+        let mut ogg = OggStream::new_with_random_serial()?;
+
+        let mut header = MaybeUninit::<ogg_packet>::uninit();
+        let mut header_comment = MaybeUninit::<ogg_packet>::uninit();
+        let mut header_code = MaybeUninit::<ogg_packet>::uninit();
+        unsafe {
+            check(
+                vorbis_analysis_headerout(
+                    &mut *dsp.dsp,
+                    &mut *comment.0,
+                    header.as_mut_ptr(),
+                    header_comment.as_mut_ptr(),
+                    header_code.as_mut_ptr(),
+                ),
+                "vorbis_analysis_headerout",
+            )?;
+            ogg.packet_in(header.assume_init_mut())?;
+            ogg.packet_in(header_comment.assume_init_mut())?;
+            ogg.packet_in(header_code.assume_init_mut())?;
+        }
+        // The Vorbis spec wants audio data to start on a fresh page
+        ogg.flush(&mut sink)?;
+
+        Ok(Self {
+            dsp,
+            _comment: comment,
+            _info: info,
+            ogg,
+            sink,
+            channels: channels as usize,
+            minimum_page_data_size,
+        })
+    }
+
+    /// Encode one block of planar audio: one `Vec` of samples per channel, all of equal length.
+    pub fn encode_audio_block(&mut self, block: &[Vec<f32>]) -> Result<(), Box<dyn Error>> {
+        if block.len() != self.channels {
+            return Err(format!(
+                "Expected {} channels in audio block, got {}",
+                self.channels,
+                block.len()
+            )
+            .into());
+        }
+        let sample_count = block[0].len();
+        if block.iter().any(|channel| channel.len() != sample_count) {
+            return Err("Audio block channels are of unequal length".into());
+        }
+        if sample_count == 0 {
+            return Ok(());
+        }
+        unsafe {
+            let buffer = vorbis_analysis_buffer(&mut *self.dsp.dsp, sample_count as c_int);
+            for (i, channel) in block.iter().enumerate() {
+                ptr::copy_nonoverlapping(channel.as_ptr(), *buffer.add(i), sample_count);
+            }
+            check(
+                vorbis_analysis_wrote(&mut *self.dsp.dsp, sample_count as c_int),
+                "vorbis_analysis_wrote",
+            )?;
+        }
+        self.write_pending_blocks()
+    }
+
+    /// Push every packet encoded so far out to the sink, ending the current Ogg page early.
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.ogg.flush(&mut self.sink)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    pub fn sink_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+
+    /// Mark the end of the stream, and write everything that remains.
+    pub fn finish(mut self) -> Result<W, Box<dyn Error>> {
+        check(
+            unsafe { vorbis_analysis_wrote(&mut *self.dsp.dsp, 0) },
+            "vorbis_analysis_wrote",
+        )?;
+        self.write_pending_blocks()?;
+        self.ogg.flush(&mut self.sink)?;
+        Ok(self.sink)
+    }
+
+    fn write_pending_blocks(&mut self) -> Result<(), Box<dyn Error>> {
+        let dsp: *mut vorbis_dsp_state = &mut *self.dsp.dsp;
+        let block: *mut vorbis_block = &mut *self.dsp.block;
+        unsafe {
+            while vorbis_analysis_blockout(dsp, block) == 1 {
+                check(vorbis_analysis(block, ptr::null_mut()), "vorbis_analysis")?;
+                check(vorbis_bitrate_addblock(block), "vorbis_bitrate_addblock")?;
+                let mut packet = MaybeUninit::<ogg_packet>::uninit();
+                while vorbis_bitrate_flushpacket(dsp, packet.as_mut_ptr()) == 1 {
+                    self.ogg.packet_in(packet.assume_init_mut())?;
+                    self.ogg
+                        .write_pending_pages(&mut self.sink, self.minimum_page_data_size)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ogg_demux::OggPacketReader;
+
+    fn encode(strategy: VorbisBitrateStrategy, minimum_page_data_size: Option<u16>, secs: usize) -> Vec<u8> {
+        let mut encoder =
+            VorbisEncoder::new([("TITLE", "test")], 44100, 2, strategy, minimum_page_data_size, Vec::new()).unwrap();
+        let mut rng = fastrand::Rng::with_seed(1);
+        let noise: Vec<f32> = (0..44100 * secs).map(|_| rng.f32() - 0.5).collect();
+        for block in noise.chunks(1024) {
+            encoder.encode_audio_block(&[block.to_vec(), block.to_vec()]).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    /// Maximum, nominal and minimum bitrate from the identification header.
+    fn header_bitrates(ogg: &[u8]) -> [i32; 3] {
+        let header = OggPacketReader::new(ogg).next_packet().unwrap().unwrap().data;
+        assert_eq!(&header[..7], b"\x01vorbis");
+        let field = |at: usize| i32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        [field(16), field(20), field(24)]
+    }
+
+    fn page_count(ogg: &[u8]) -> usize {
+        ogg.windows(4).filter(|w| w == b"OggS").count()
+    }
+
+    #[test]
+    fn bitrate_strategies_reach_the_encoder() {
+        let header = |strategy| header_bitrates(&encode(strategy, None, 1));
+        assert_eq!(header(VorbisBitrateStrategy::Vbr { target_bitrate: 128_000 }), [-1, 128_000, -1]);
+        assert_eq!(header(VorbisBitrateStrategy::Abr { average_bitrate: 96_000 }), [-1, 96_000, -1]);
+        assert_eq!(header(VorbisBitrateStrategy::ConstrainedAbr { maximum_bitrate: 160_000 })[0], 160_000);
+        assert_eq!(
+            header(VorbisBitrateStrategy::Managed {
+                minimum_bitrate: Some(64_000),
+                average_bitrate: None,
+                maximum_bitrate: Some(192_000),
+            }),
+            [192_000, 128_000, 64_000]
+        );
+        // Quality mode leaves the bounds unset, and only estimates a nominal bitrate
+        let [maximum, nominal, minimum] = header(VorbisBitrateStrategy::QualityVbr { quality: 0.3 });
+        assert_eq!((maximum, minimum), (0, 0));
+        assert!(nominal > 0);
+    }
+
+    #[test]
+    fn minimum_page_data_size_makes_fewer_bigger_pages() {
+        let strategy = VorbisBitrateStrategy::Abr { average_bitrate: 192_000 };
+        let default_pages = page_count(&encode(strategy, None, 5));
+        let big_pages = page_count(&encode(strategy, Some(16_000), 5));
+        assert!(big_pages < default_pages / 2, "{} pages, {} by default", big_pages, default_pages);
+    }
+
+    #[test]
+    fn rejects_channel_counts_vorbis_cant_carry() {
+        let strategy = VorbisBitrateStrategy::Vbr { target_bitrate: 128_000 };
+        for channels in [0, 256] {
+            assert!(VorbisEncoder::new([], 44100, channels, strategy, None, Vec::new()).is_err());
+        }
+    }
+}
@@ -0,0 +1,580 @@
+use std::error::Error;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use clap::ValueEnum;
+
+// RIFF/WAVE layout we write:
+//   "RIFF"|"RF64" <size> "WAVE"
+//   "JUNK"|"ds64" <28 bytes>   reserved up front, so a RIFF file can become RF64 in place
+//   "fmt " <size> <format>
+//   "LIST" <size> "INFO" <fields>   only if there are tags
+//   "data" <size> <samples>
+const DS64_OFFSET: u64 = 12;
+const DS64_BODY_LEN: u32 = 28;
+const FMT_OFFSET: u64 = DS64_OFFSET + 8 + DS64_BODY_LEN as u64;
+/// The most a plain RIFF file can say it holds, past its first 8 bytes.
+const RIFF_LIMIT: u64 = u32::MAX as u64;
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+// The tail of the KSDATAFORMAT_SUBTYPE_* GUIDs, after the format tag
+const SUBFORMAT_GUID_TAIL: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleFormat {
+    Int,
+    Float,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavSpec {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+    pub sample_format: SampleFormat,
+}
+
+impl WavSpec {
+    pub fn bytes_per_frame(&self) -> u64 {
+        self.channels as u64 * (self.bits_per_sample / 8) as u64
+    }
+}
+
+/// Which RIFF flavour to write. Classic WAV tops out at 4 GiB; RF64 (EBU Tech 3306) doesn't.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum WavContainer {
+    /// Plain WAV, switching to RF64 if the segment outgrows it
+    Auto,
+    /// Plain WAV only; segments that would outgrow it fail
+    Riff,
+    /// Always RF64
+    Rf64,
+}
+
+/// Streaming WAV writer, keeping the header valid after every `flush`
+/// so that a crash leaves a playable file behind.
+pub struct WavWriter<W: Write + Seek> {
+    spec: WavSpec,
+    container: WavContainer,
+    is_rf64: bool,
+    // Header written once with unknown sizes, for sinks that can't go back and fix it
+    streaming: bool,
+    /// Where a plain RIFF file has to become RF64; only ever lowered by tests
+    riff_limit: u64,
+    data_size_offset: u64,
+    data_len: u64,
+    sink: W,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    /// `comments` are Vorbis-comment-style tags, written into a `LIST`/`INFO` chunk; see `info_chunk_body`.
+    pub fn new(
+        sink: W,
+        spec: WavSpec,
+        container: WavContainer,
+        comments: &[(String, String)],
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::start(sink, spec, container, comments, false, RIFF_LIMIT)
+    }
+
+    /// As `new`, switching to RF64 (or failing, with `WavContainer::Riff`) once the RIFF size passes `riff_limit`.
+    #[cfg(test)]
+    fn with_riff_limit(sink: W, spec: WavSpec, container: WavContainer, riff_limit: u64) -> Result<Self, Box<dyn Error>> {
+        Self::start(sink, spec, container, &[], false, riff_limit)
+    }
+
+    /// For sinks that can only be written front to back. The header never gets its sizes filled in;
+    /// they're left at the maximum, which readers take to mean "read to the end of the file".
+    pub fn new_streaming(sink: W, spec: WavSpec, comments: &[(String, String)]) -> Result<Self, Box<dyn Error>> {
+        Self::start(sink, spec, WavContainer::Riff, comments, true, RIFF_LIMIT)
+    }
+
+    fn start(
+        mut sink: W,
+        spec: WavSpec,
+        container: WavContainer,
+        comments: &[(String, String)],
+        streaming: bool,
+        riff_limit: u64,
+    ) -> Result<Self, Box<dyn Error>> {
+        match (spec.sample_format, spec.bits_per_sample) {
+            (SampleFormat::Int, 8 | 16 | 24 | 32) | (SampleFormat::Float, 32) => {}
+            (format, bits) => {
+                return Err(format!("Unsupported WAV sample format: {:?} {}-bit", format, bits).into())
+            }
+        }
+        if spec.channels == 0 {
+            return Err("WAV files need at least one channel".into());
+        }
+        let fmt = fmt_chunk_body(&spec);
+        let unknown_size = if streaming { u32::MAX } else { 0 };
+        sink.write_all(b"RIFF")?;
+        sink.write_all(&unknown_size.to_le_bytes())?;
+        sink.write_all(b"WAVE")?;
+        sink.write_all(b"JUNK")?;
+        sink.write_all(&DS64_BODY_LEN.to_le_bytes())?;
+        sink.write_all(&[0; DS64_BODY_LEN as usize])?;
+        sink.write_all(b"fmt ")?;
+        sink.write_all(&(fmt.len() as u32).to_le_bytes())?;
+        sink.write_all(&fmt)?;
+        let info = info_chunk_body(comments);
+        if !info.is_empty() {
+            sink.write_all(b"LIST")?;
+            sink.write_all(&(info.len() as u32).to_le_bytes())?;
+            sink.write_all(&info)?;
+        }
+        sink.write_all(b"data")?;
+        sink.write_all(&unknown_size.to_le_bytes())?;
+        let mut this = Self {
+            spec,
+            container,
+            is_rf64: container == WavContainer::Rf64,
+            streaming,
+            riff_limit,
+            data_size_offset: FMT_OFFSET + 8 + fmt.len() as u64 + list_len(&info) + 4,
+            data_len: 0,
+            sink,
+        };
+        this.update_header()?;
+        Ok(this)
+    }
+
+    pub fn write_sample_f32(&mut self, sample: f32) -> Result<(), Box<dyn Error>> {
+        self.reserve(4)?;
+        self.sink.write_all(&sample.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Writes the low `bits_per_sample` bits of an integer sample. 8-bit WAV is unsigned.
+    pub fn write_sample_int(&mut self, sample: i32) -> Result<(), Box<dyn Error>> {
+        let len = (self.spec.bits_per_sample / 8) as usize;
+        self.reserve(len as u64)?;
+        if len == 1 {
+            self.sink.write_all(&[(sample + 128) as u8])?;
+        } else {
+            self.sink.write_all(&sample.to_le_bytes()[..len])?;
+        }
+        Ok(())
+    }
+
+    pub fn spec(&self) -> &WavSpec {
+        &self.spec
+    }
+
+    pub fn is_rf64(&self) -> bool {
+        self.is_rf64
+    }
+
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    /// Brings the header up to date with what's been written so far, and flushes the sink.
+    pub fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+        self.update_header()?;
+        self.sink.flush()?;
+        Ok(())
+    }
+
+    /// Pads the data chunk to an even length as RIFF requires, and writes the final header.
+    pub fn finalize(mut self) -> Result<W, Box<dyn Error>> {
+        if self.data_len % 2 == 1 {
+            self.sink.write_all(&[0])?;
+        }
+        self.flush()?;
+        Ok(self.sink)
+    }
+
+    fn riff_size(&self) -> u64 {
+        self.data_size_offset + 4 + self.data_len + self.data_len % 2 - 8
+    }
+
+    fn reserve(&mut self, len: u64) -> Result<(), Box<dyn Error>> {
+        self.data_len += len;
+        if !self.is_rf64 && !self.streaming && self.riff_size() > self.riff_limit {
+            if self.container == WavContainer::Riff {
+                self.data_len -= len;
+                return Err("WAV segment reached the 4 GiB RIFF limit; use --wav-container auto or rf64, or a shorter --segment-dur".into());
+            }
+            self.is_rf64 = true;
+        }
+        Ok(())
+    }
+
+    fn update_header(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.streaming {
+            return Ok(());
+        }
+        let riff_size = self.riff_size();
+        self.sink.seek(SeekFrom::Start(0))?;
+        if self.is_rf64 {
+            self.sink.write_all(b"RF64")?;
+            self.sink.write_all(&u32::MAX.to_le_bytes())?;
+            self.sink.seek(SeekFrom::Start(DS64_OFFSET))?;
+            self.sink.write_all(b"ds64")?;
+            self.sink.write_all(&DS64_BODY_LEN.to_le_bytes())?;
+            self.sink.write_all(&riff_size.to_le_bytes())?;
+            self.sink.write_all(&self.data_len.to_le_bytes())?;
+            let frames = self.data_len / self.spec.bytes_per_frame();
+            self.sink.write_all(&frames.to_le_bytes())?;
+            self.sink.write_all(&0u32.to_le_bytes())?; // no table entries
+            self.sink.seek(SeekFrom::Start(self.data_size_offset))?;
+            self.sink.write_all(&u32::MAX.to_le_bytes())?;
+        } else {
+            self.sink.write_all(b"RIFF")?;
+            self.sink.write_all(&(riff_size as u32).to_le_bytes())?;
+            self.sink.seek(SeekFrom::Start(self.data_size_offset))?;
+            self.sink.write_all(&(self.data_len as u32).to_le_bytes())?;
+        }
+        self.sink.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+/// WAVEFORMATEX, or WAVEFORMATEXTENSIBLE where Microsoft says it's required:
+/// more than two channels, or more than 16 bits per sample.
+fn fmt_chunk_body(spec: &WavSpec) -> Vec<u8> {
+    let format_tag = match spec.sample_format {
+        SampleFormat::Int => WAVE_FORMAT_PCM,
+        SampleFormat::Float => WAVE_FORMAT_IEEE_FLOAT,
+    };
+    let extensible = spec.channels > 2 || spec.bits_per_sample > 16;
+    let block_align = spec.bytes_per_frame() as u16;
+    let mut out = Vec::with_capacity(40);
+    out.extend(if extensible { WAVE_FORMAT_EXTENSIBLE } else { format_tag }.to_le_bytes());
+    out.extend(spec.channels.to_le_bytes());
+    out.extend(spec.sample_rate.to_le_bytes());
+    out.extend((spec.sample_rate * block_align as u32).to_le_bytes());
+    out.extend(block_align.to_le_bytes());
+    out.extend(spec.bits_per_sample.to_le_bytes());
+    if extensible {
+        let channel_mask: u32 = match spec.channels {
+            1 => 0x4, // front centre
+            2 => 0x3, // front left | front right
+            _ => 0,   // unassigned
+        };
+        out.extend(22u16.to_le_bytes());
+        out.extend(spec.bits_per_sample.to_le_bytes()); // valid bits
+        out.extend(channel_mask.to_le_bytes());
+        out.extend(format_tag.to_le_bytes());
+        out.extend(SUBFORMAT_GUID_TAIL);
+    } else if spec.sample_format == SampleFormat::Float {
+        out.extend(0u16.to_le_bytes());
+    }
+    out
+}
+
+/// `LIST` chunk body holding `comments` as RIFF INFO fields: `DATE` as ICRD and `ENCODER` as ISFT, which
+/// players show, and every comment as a `KEY=VALUE` line of ICMT, so tags of our own survive too.
+/// Newlines and backslashes in values are escaped as `\n` and `\\`. Empty if there's nothing to say.
+fn info_chunk_body(comments: &[(String, String)]) -> Vec<u8> {
+    if comments.is_empty() {
+        return Vec::new();
+    }
+    let lookup = |key: &str| comments.iter().find(|(k, _)| k == key).map(|(_, value)| value.clone());
+    let lines: Vec<String> = comments
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value.replace('\\', "\\\\").replace('\n', "\\n")))
+        .collect();
+    let fields = [
+        (b"ICRD", lookup("DATE")),
+        (b"ISFT", lookup("ENCODER")),
+        (b"ICMT", Some(lines.join("\n"))),
+    ];
+    let mut out = b"INFO".to_vec();
+    for (id, text) in fields {
+        let Some(text) = text else { continue };
+        // NUL-terminated, and padded to an even length
+        let text = text.replace('\0', "");
+        out.extend(id);
+        out.extend((text.len() as u32 + 1).to_le_bytes());
+        out.extend(text.as_bytes());
+        out.push(0);
+        if out.len() % 2 == 1 {
+            out.push(0);
+        }
+    }
+    out
+}
+
+/// Bytes the `LIST` chunk for `info` takes up, header included.
+fn list_len(info: &[u8]) -> u64 {
+    if info.is_empty() { 0 } else { 8 + info.len() as u64 }
+}
+
+/// Reads back WAV and RF64 files, as written by `WavWriter` or most anything else producing PCM or float.
+pub struct WavReader<R: Read + Seek> {
+    spec: WavSpec,
+    frames: u64,
+    frames_read: u64,
+    source: R,
+}
+
+fn u16_le(b: &[u8]) -> u16 {
+    u16::from_le_bytes([b[0], b[1]])
+}
+
+fn u32_le(b: &[u8]) -> u32 {
+    u32::from_le_bytes(b[..4].try_into().unwrap())
+}
+
+impl<R: Read + Seek> WavReader<R> {
+    /// Parses the header, leaving `source` at the start of the samples.
+    pub fn new(mut source: R) -> Result<Self, Box<dyn Error>> {
+        let file_len = source.seek(SeekFrom::End(0))?;
+        source.seek(SeekFrom::Start(0))?;
+        let mut header = [0; 12];
+        source.read_exact(&mut header)?;
+        if !matches!(&header[..4], b"RIFF" | b"RF64") || &header[8..12] != b"WAVE" {
+            return Err("Not a WAVE file".into());
+        }
+        let mut ds64_data_len = None;
+        let mut spec = None;
+        loop {
+            let mut chunk_header = [0; 8];
+            source.read_exact(&mut chunk_header)?;
+            let len = u32_le(&chunk_header[4..]) as u64;
+            // Checked before allocating for the body, so a corrupt length can't ask for gigabytes
+            let remaining = file_len.saturating_sub(source.stream_position()?);
+            if matches!(&chunk_header[..4], b"ds64" | b"fmt ") && len > remaining {
+                return Err(format!(
+                    "WAV {} chunk says it's {} bytes, past the end of the file",
+                    String::from_utf8_lossy(&chunk_header[..4]).trim_end(),
+                    len
+                )
+                .into());
+            }
+            match &chunk_header[..4] {
+                b"ds64" => {
+                    let mut body = vec![0; len as usize];
+                    source.read_exact(&mut body)?;
+                    let data_len = body.get(8..16).ok_or("Truncated ds64 chunk")?;
+                    ds64_data_len = Some(u64::from_le_bytes(data_len.try_into()?));
+                }
+                b"fmt " => {
+                    let mut body = vec![0; len as usize];
+                    source.read_exact(&mut body)?;
+                    spec = Some(parse_fmt_chunk(&body)?);
+                }
+                b"data" => {
+                    let spec: WavSpec = spec.ok_or("WAV data before fmt chunk")?;
+                    let data_start = source.stream_position()?;
+                    let data_len = match (len, ds64_data_len) {
+                        (0xffff_ffff, Some(ds64)) => ds64,
+                        // A writer that died before fixing up the header; take what's there
+                        (0, _) => file_len - data_start,
+                        (len, _) => len,
+                    };
+                    let data_len = data_len.min(file_len - data_start);
+                    return Ok(Self {
+                        spec,
+                        frames: data_len / spec.bytes_per_frame().max(1),
+                        frames_read: 0,
+                        source,
+                    });
+                }
+                _ => {
+                    source.seek(SeekFrom::Current((len + len % 2) as i64))?;
+                }
+            }
+        }
+    }
+
+    pub fn spec(&self) -> &WavSpec {
+        &self.spec
+    }
+
+    /// Total length in sample frames
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Reads up to `max_frames` interleaved frames as floats in -1..1. Empty at the end of the data.
+    pub fn read_frames(&mut self, max_frames: usize) -> Result<Vec<f32>, Box<dyn Error>> {
+        let frames = (max_frames as u64).min(self.frames - self.frames_read);
+        let sample_len = (self.spec.bits_per_sample / 8) as usize;
+        let mut bytes = vec![0; frames as usize * self.spec.bytes_per_frame() as usize];
+        self.source.read_exact(&mut bytes)?;
+        self.frames_read += frames;
+        let samples = bytes.chunks_exact(sample_len);
+        Ok(match (self.spec.sample_format, sample_len) {
+            (SampleFormat::Float, 4) => samples.map(|b| f32::from_le_bytes(b.try_into().unwrap())).collect(),
+            (SampleFormat::Float, 8) => samples
+                .map(|b| f64::from_le_bytes(b.try_into().unwrap()) as f32)
+                .collect(),
+            (SampleFormat::Int, 1) => samples.map(|b| (b[0] as f32 - 128.) / 128.).collect(),
+            (SampleFormat::Int, len) => {
+                let scale = 1. / (1u64 << (len * 8 - 1)) as f32;
+                samples
+                    .map(|b| {
+                        // Left-align into an i32 so the sign comes along, then shift back down
+                        let mut word = [0; 4];
+                        word[4 - len..].copy_from_slice(b);
+                        (i32::from_le_bytes(word) >> ((4 - len) * 8)) as f32 * scale
+                    })
+                    .collect()
+            }
+            (format, _) => {
+                return Err(format!(
+                    "Unsupported WAV sample format: {:?} {}-bit",
+                    format, self.spec.bits_per_sample
+                )
+                .into())
+            }
+        })
+    }
+}
+
+fn parse_fmt_chunk(body: &[u8]) -> Result<WavSpec, Box<dyn Error>> {
+    if body.len() < 16 {
+        return Err("WAV fmt chunk is too short".into());
+    }
+    let mut format_tag = u16_le(body);
+    if format_tag == WAVE_FORMAT_EXTENSIBLE && body.len() >= 26 {
+        format_tag = u16_le(&body[24..]);
+    }
+    let sample_format = match format_tag {
+        WAVE_FORMAT_PCM => SampleFormat::Int,
+        WAVE_FORMAT_IEEE_FLOAT => SampleFormat::Float,
+        tag => return Err(format!("Unsupported WAV format tag {:#x}", tag).into()),
+    };
+    let bits_per_sample = u16_le(&body[14..]);
+    if bits_per_sample == 0 || !bits_per_sample.is_multiple_of(8) || bits_per_sample > 64 {
+        return Err(format!("Unsupported WAV sample size of {} bits", bits_per_sample).into());
+    }
+    // Integer samples are decoded through an i32
+    if sample_format == SampleFormat::Int && bits_per_sample > 32 {
+        return Err(format!("Unsupported WAV integer sample size of {} bits", bits_per_sample).into());
+    }
+    Ok(WavSpec {
+        channels: u16_le(&body[2..]),
+        sample_rate: u32_le(&body[4..]),
+        bits_per_sample,
+        sample_format,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const SPEC: WavSpec = WavSpec {
+        channels: 2,
+        sample_rate: 8_000,
+        bits_per_sample: 16,
+        sample_format: SampleFormat::Int,
+    };
+    /// Small enough that a few hundred frames cross it
+    const LIMIT: u64 = 1_000;
+
+    fn write(container: WavContainer, frames: i32) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut writer = WavWriter::with_riff_limit(Cursor::new(Vec::new()), SPEC, container, LIMIT)?;
+        for i in 0..frames {
+            writer.write_sample_int(i)?;
+            writer.write_sample_int(-i)?;
+        }
+        Ok(writer.finalize()?.into_inner())
+    }
+
+    fn u64_at(bytes: &[u8], at: usize) -> u64 {
+        u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn stays_riff_under_the_limit() {
+        let bytes = write(WavContainer::Auto, 100).unwrap();
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(u32_le(&bytes[4..]) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[12..16], b"JUNK");
+        assert_eq!(WavReader::new(Cursor::new(bytes)).unwrap().frames(), 100);
+    }
+
+    #[test]
+    fn becomes_rf64_in_place_past_the_limit() {
+        let bytes = write(WavContainer::Auto, 600).unwrap();
+        assert_eq!(&bytes[..4], b"RF64");
+        assert_eq!(u32_le(&bytes[4..]), u32::MAX);
+        assert_eq!(&bytes[12..16], b"ds64");
+        assert_eq!(u32_le(&bytes[16..]), DS64_BODY_LEN);
+        // ds64: RIFF size, data size, frame count, table length
+        assert_eq!(u64_at(&bytes, 20), bytes.len() as u64 - 8);
+        assert_eq!(u64_at(&bytes, 28), 600 * 4);
+        assert_eq!(u64_at(&bytes, 36), 600);
+        assert_eq!(u32_le(&bytes[44..]), 0);
+
+        let mut reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.frames(), 600);
+        let samples = reader.read_frames(600).unwrap();
+        assert_eq!(samples.len(), 1200);
+        assert_eq!(samples[1198], 599. / 32768.);
+        assert_eq!(samples[1199], -599. / 32768.);
+    }
+
+    #[test]
+    fn rf64_from_the_start_when_asked() {
+        let bytes = write(WavContainer::Rf64, 10).unwrap();
+        assert_eq!(&bytes[..4], b"RF64");
+        assert_eq!(WavReader::new(Cursor::new(bytes)).unwrap().frames(), 10);
+    }
+
+    #[test]
+    fn riff_only_fails_at_the_limit() {
+        let err = write(WavContainer::Riff, 600).unwrap_err();
+        assert!(err.to_string().contains("4 GiB RIFF limit"), "{}", err);
+    }
+
+    #[test]
+    fn streaming_header_is_read_to_the_end_of_the_file() {
+        let mut writer = WavWriter::new_streaming(Cursor::new(Vec::new()), SPEC, &[]).unwrap();
+        for i in 0..50 {
+            writer.write_sample_int(i).unwrap();
+            writer.write_sample_int(i).unwrap();
+        }
+        let bytes = writer.finalize().unwrap().into_inner();
+        assert_eq!(u32_le(&bytes[4..]), u32::MAX);
+        assert_eq!(WavReader::new(Cursor::new(bytes)).unwrap().frames(), 50);
+    }
+
+    #[test]
+    fn tags_go_in_an_info_chunk_ahead_of_the_data() {
+        let comments: Vec<(String, String)> = [
+            ("DATE", "2024-01-01T12:00:00.000Z"),
+            ("ENCODER", "akasha 1.0"),
+            ("NOTE", "two\nlines"),
+        ]
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect();
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), SPEC, WavContainer::Auto, &comments).unwrap();
+        for i in 0..3 {
+            writer.write_sample_int(i).unwrap();
+            writer.write_sample_int(i).unwrap();
+        }
+        let bytes = writer.finalize().unwrap().into_inner();
+        let find = |needle: &[u8]| bytes.windows(needle.len()).position(|window| window == needle).unwrap();
+        assert!(find(b"LIST") < find(b"data"));
+        assert_eq!(&bytes[find(b"LIST") + 8..find(b"LIST") + 12], b"INFO");
+        find(b"ICRD\x19\0\0\x002024-01-01T12:00:00.000Z\0");
+        find(b"ISFT\x0b\0\0\0akasha 1.0\0");
+        find(b"DATE=2024-01-01T12:00:00.000Z\nENCODER=akasha 1.0\nNOTE=two\\nlines\0");
+
+        let mut reader = WavReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.frames(), 3);
+        assert_eq!(reader.read_frames(3).unwrap()[4], 2. / 32768.);
+    }
+
+    #[test]
+    fn corrupt_chunk_lengths_are_refused_before_allocating() {
+        let good = write(WavContainer::Rf64, 10).unwrap();
+        for at in [16, FMT_OFFSET as usize + 4] {
+            let mut bytes = good.clone();
+            bytes[at..at + 4].copy_from_slice(&0xffff_fff0u32.to_le_bytes());
+            let err = WavReader::new(Cursor::new(bytes)).err().unwrap();
+            assert!(err.to_string().contains("past the end of the file"), "{}", err);
+        }
+    }
+}
@@ -1,113 +1,672 @@
 use std::error::Error;
-use std::fs::File;
-use std::num::{NonZeroU32, NonZeroU8};
-use std::path::PathBuf;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
 use std::time::{Duration, Instant};
-use async_fn_stream::fn_stream;
 
-use futures_core::Stream;
-use futures_util::{pin_mut, StreamExt};
-use hound::{WavSpec, WavWriter};
-use vorbis_rs::VorbisEncoder;
+use clap::ValueEnum;
 use printrn::printrn;
-//use signal_hook::low_level::channel::Channel;
+use serde_json::json;
 
-use crate::Chunk;
+use crate::dither::{quantize, TpdfDither};
+use crate::encrypt::SegmentSink;
+use crate::flac_encoder::FlacEncoder;
+use crate::opus_encoder::OpusEncoder;
+use crate::segment_meta::SegmentMeta;
+use crate::vorbis_encoder::VorbisEncoder;
+use crate::wav::{SampleFormat, WavContainer, WavSpec, WavWriter};
+use crate::{Chunk, FlacOpts, FormatSelect, OggOpts, OpusOpts, OutputFormat, Rec, WavBits, WavDither, WavOpts};
 
-fn set_extension_if_none(p: &mut PathBuf, ext: &str) {
-    match p.extension() {
-        None => {
-            p.set_extension(ext);
-        }
-        _ => ()
+/// One output format of the recorder. The recorder decides when segments start and end;
+/// a `SegmentWriter` only has to turn the chunks it's handed into a file.
+pub trait SegmentWriter {
+    /// File extension for this format, without the dot
+    fn extension(&self) -> &'static str;
+    /// Start a new segment, written to `sink`. Any segment still open is finalized first.
+    fn open(&mut self, sink: SegmentSink, config: &cpal::StreamConfig, meta: &SegmentMeta) -> Result<(), Box<dyn Error>>;
+    /// Write one interleaved chunk to the open segment
+    fn write_chunk(&mut self, chunk: &[f32]) -> Result<(), Box<dyn Error>>;
+    /// Finish the open segment, and sync it to disk. Does nothing if no segment is open.
+    fn finalize(&mut self) -> Result<(), Box<dyn Error>>;
+    /// Size of the open segment file so far
+    fn bytes_written(&self) -> u64;
+    /// Encoder settings in effect for the open segment, for its sidecar
+    fn settings(&self) -> serde_json::Value;
+}
+
+/// The writers for every `--format` the user asked for, alongside where their files go.
+pub fn writers_for(rec: &Rec) -> Vec<(OutputFormat, Box<dyn SegmentWriter>)> {
+    let segment_dur = Duration::from(&rec.segment_dur);
+    rec.formats
+        .iter()
+        .map(|output| {
+            let writer = writer_for(output.format, &rec.wav, &rec.ogg, &rec.flac, &rec.opus, segment_dur);
+            (output.clone(), writer)
+        })
+        .collect()
+}
+
+/// A writer for one format. `expected_dur` is about how long each file will be.
+pub fn writer_for(
+    format: FormatSelect,
+    wav: &WavOpts,
+    ogg: &OggOpts,
+    flac: &FlacOpts,
+    opus: &OpusOpts,
+    expected_dur: Duration,
+) -> Box<dyn SegmentWriter> {
+    match format {
+        FormatSelect::Wav => Box::new(WavSegmentWriter::new(wav.clone(), expected_dur)),
+        FormatSelect::Ogg => Box::new(OggSegmentWriter::new(ogg.clone())),
+        FormatSelect::Flac => Box::new(FlacSegmentWriter::new(flac.clone())),
+        FormatSelect::Opus => Box::new(OpusSegmentWriter::new(ogg.clone(), opus.clone())),
     }
 }
 
+fn value_name<T: ValueEnum>(value: &T) -> Option<String> {
+    value.to_possible_value().map(|v| v.get_name().to_owned())
+}
+
 /// Splits a chunk into multiple, based on the number of channels
-pub fn un_interleave<S: Stream<Item = Chunk> + Unpin>(mut input: S, num_channels: usize) -> impl Stream<Item=Vec<Chunk>> {
-    fn_stream(|emitter| async move {
-        while let Some(chunk) = input.next().await {
-            let mut channel_chunks: Vec<Chunk> = Vec::new();
-            for _ in 0..num_channels {
-                channel_chunks.push(Chunk::new());
-            }
-            for (i, sample) in chunk.iter().enumerate() {
-                let channel_num = i % num_channels;
-                channel_chunks[channel_num].push(*sample);
-            }
-            emitter.emit(channel_chunks).await;
+pub fn un_interleave(chunk: &[f32], num_channels: usize) -> Vec<Chunk> {
+    let mut channel_chunks: Vec<Chunk> = Vec::new();
+    for _ in 0..num_channels {
+        channel_chunks.push(Chunk::with_capacity(chunk.len() / num_channels));
+    }
+    for (i, sample) in chunk.iter().enumerate() {
+        let channel_num = i % num_channels;
+        channel_chunks[channel_num].push(*sample);
+    }
+    channel_chunks
+}
+
+/// Keeps track of how large the file behind it has grown, seeks included.
+pub struct CountingWriter<W> {
+    inner: W,
+    position: u64,
+    len: u64,
+}
+
+impl<W> CountingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self { inner, position: 0, len: 0 }
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.len
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        self.len = self.len.max(self.position);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for CountingWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+type SegmentFile = CountingWriter<SegmentSink>;
+
+/// Forces buffered Ogg pages out to disk every `interval`, so a crash loses little audio.
+struct FlushTimer {
+    interval: Duration,
+    last_flush: Instant,
+}
+
+impl FlushTimer {
+    fn new(interval: Duration) -> Self {
+        Self { interval, last_flush: Instant::now() }
+    }
+
+    fn due(&mut self) -> bool {
+        if self.last_flush.elapsed() < self.interval {
+            return false;
+        }
+        self.last_flush = Instant::now();
+        true
+    }
+}
+
+pub struct OggSegmentWriter {
+    opts: OggOpts,
+    current: Option<(VorbisEncoder<SegmentFile>, FlushTimer)>,
+}
+
+impl OggSegmentWriter {
+    pub fn new(opts: OggOpts) -> Self {
+        Self { opts, current: None }
+    }
+}
+
+impl SegmentWriter for OggSegmentWriter {
+    fn extension(&self) -> &'static str {
+        "ogg"
+    }
+
+    fn open(&mut self, sink: SegmentSink, config: &cpal::StreamConfig, meta: &SegmentMeta) -> Result<(), Box<dyn Error>> {
+        self.finalize()?;
+        printrn!("Begin writing to OGG...");
+        let comments = meta.vorbis_comments();
+        let tags = comments.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        let vorbis_encoder = VorbisEncoder::new(
+            tags,
+            config.sample_rate.0,
+            config.channels,
+            self.opts.bitrate_strategy()?,
+            self.opts.ogg_minimum_page_data_size,
+            CountingWriter::new(sink))?;
+        let flush_timer = FlushTimer::new(Duration::from(&self.opts.ogg_flush_interval));
+        self.current = Some((vorbis_encoder, flush_timer));
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk: &[f32]) -> Result<(), Box<dyn Error>> {
+        let (vorbis_encoder, flush_timer) = self.current.as_mut().ok_or("No OGG segment open")?;
+        let channels = vorbis_encoder.channels();
+        vorbis_encoder.encode_audio_block(&un_interleave(chunk, channels))?;
+        // Don't let libogg sit on more than `ogg_flush_interval` worth of audio
+        if flush_timer.due() {
+            vorbis_encoder.flush()?;
+            vorbis_encoder.sink_mut().inner.sync_data()?;
         }
-    })
-}
-
-pub async fn write_to_ogg<S: Stream<Item = Chunk> + Unpin>(
-    path: &PathBuf,
-    mic_input_stream: S,
-    config: &cpal::StreamConfig,
-    segment_dur: &Duration)
-    -> Result<(), Box<dyn Error>> {
-    let mut p = path.clone();
-    set_extension_if_none(&mut p, "ogg");
-    printrn!("Begin writing to OGG...");
-    let tags: Vec<(String, String)> = Vec::new();
-    let brmgmt = vorbis_rs::VorbisBitrateManagementStrategy::Vbr {
-        target_bitrate:  NonZeroU32::new(128_000)
-            .ok_or("could not cast target_bitrate as NonZeroU32")?
-    };
-    let f = File::create(p).expect("Could not create file!");
-    let start_vorbis_encoder = VorbisEncoder::new(
-        0,
-        tags.into_iter(),
-        NonZeroU32::new(config.sample_rate.0)
-            .ok_or("could not cast sample_rate as NonZeroU32")?,
-        NonZeroU8::new(config.channels as u8)
-            .ok_or("could not cast channels as NonZeroU8")?,
-        brmgmt,
-        None,
-        f);
-    let mut vorbis_encoder = start_vorbis_encoder.unwrap();
-    let time_at_start = Instant::now();
-    let un_interleave =  un_interleave(mic_input_stream, config.channels as usize);
-    pin_mut!(un_interleave);
-    while let Some(chunks) = un_interleave.next().await {
-        vorbis_encoder.encode_audio_block(chunks)?;
-        if time_at_start.elapsed() >= *segment_dur {
-            break;
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some((vorbis_encoder, _)) = self.current.take() {
+            vorbis_encoder.finish()?.into_inner().finish()?.sync_all()?;
         }
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.current.as_ref().map(|(e, _)| e.sink().bytes_written()).unwrap_or(0)
+    }
+
+    fn settings(&self) -> serde_json::Value {
+        json!({
+            "bitrate_strategy": self.opts.bitrate_strategy().ok(),
+            "minimum_page_data_size": self.opts.ogg_minimum_page_data_size,
+            "flush_interval_secs": Duration::from(&self.opts.ogg_flush_interval).as_secs_f64(),
+        })
+    }
+}
+
+pub struct OpusSegmentWriter {
+    ogg_opts: OggOpts,
+    opts: OpusOpts,
+    current: Option<(OpusEncoder<SegmentFile>, FlushTimer)>,
+}
+
+impl OpusSegmentWriter {
+    pub fn new(ogg_opts: OggOpts, opts: OpusOpts) -> Self {
+        Self { ogg_opts, opts, current: None }
     }
-    vorbis_encoder.finish()?;
-    //Ok(mic_input_stream)
-    Ok(())
 }
 
-pub async fn write_to_wav<S: Stream<Item = Vec<f32>> + Unpin>(
-    path: &PathBuf,
-    mut mic_input_stream: S,
-    config: &cpal::StreamConfig,
-    segment_dur: &Duration
-) -> Result<(), Box<dyn Error>> {
-    let mut p = path.clone();
-    set_extension_if_none(&mut p, "wav");
+impl SegmentWriter for OpusSegmentWriter {
+    fn extension(&self) -> &'static str {
+        "opus"
+    }
 
-    let mut wav_writer = WavWriter::create(p, WavSpec{
-        channels: config.channels,
-        sample_rate: config.sample_rate.0, // Dynamically grab
-        bits_per_sample: 32, // Hound locks this at 32
-        sample_format: hound::SampleFormat::Float // I believe they all should be float?
-    })?;
+    fn open(&mut self, sink: SegmentSink, config: &cpal::StreamConfig, meta: &SegmentMeta) -> Result<(), Box<dyn Error>> {
+        self.finalize()?;
+        printrn!("Begin writing to Opus...");
+        let comments = meta.vorbis_comments();
+        let tags = comments.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        let opus_encoder = OpusEncoder::new(
+            tags,
+            config.sample_rate.0,
+            config.channels,
+            self.opts.opus_bitrate.saturating_mul(1000),
+            self.opts.opus_application,
+            // Frame sizes are given in ms; granules are always 48 kHz samples
+            (self.opts.opus_frame_size * 48.) as u32,
+            self.ogg_opts.ogg_minimum_page_data_size,
+            CountingWriter::new(sink))?;
+        let flush_timer = FlushTimer::new(Duration::from(&self.ogg_opts.ogg_flush_interval));
+        self.current = Some((opus_encoder, flush_timer));
+        Ok(())
+    }
 
+    fn write_chunk(&mut self, chunk: &[f32]) -> Result<(), Box<dyn Error>> {
+        let (opus_encoder, flush_timer) = self.current.as_mut().ok_or("No Opus segment open")?;
+        let channels = opus_encoder.channels();
+        opus_encoder.encode_audio_block(&un_interleave(chunk, channels))?;
+        if flush_timer.due() {
+            opus_encoder.flush()?;
+            opus_encoder.sink_mut().inner.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some((opus_encoder, _)) = self.current.take() {
+            opus_encoder.finish()?.into_inner().finish()?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.current.as_ref().map(|(e, _)| e.sink().bytes_written()).unwrap_or(0)
+    }
+
+    fn settings(&self) -> serde_json::Value {
+        json!({
+            "bitrate": self.opts.opus_bitrate.saturating_mul(1000),
+            "application": self.opts.opus_application,
+            "frame_size_ms": self.opts.opus_frame_size,
+            "encode_rate": self.current.as_ref().map(|(e, _)| e.encode_rate()),
+            "minimum_page_data_size": self.ogg_opts.ogg_minimum_page_data_size,
+            "flush_interval_secs": Duration::from(&self.ogg_opts.ogg_flush_interval).as_secs_f64(),
+        })
+    }
+}
+
+pub struct FlacSegmentWriter {
+    opts: FlacOpts,
+    current: Option<FlacEncoder<CountingWriter<BufWriter<SegmentSink>>>>,
+}
+
+impl FlacSegmentWriter {
+    pub fn new(opts: FlacOpts) -> Self {
+        Self { opts, current: None }
+    }
+}
+
+impl SegmentWriter for FlacSegmentWriter {
+    fn extension(&self) -> &'static str {
+        "flac"
+    }
+
+    fn open(&mut self, sink: SegmentSink, config: &cpal::StreamConfig, meta: &SegmentMeta) -> Result<(), Box<dyn Error>> {
+        self.finalize()?;
+        printrn!("Begin writing to FLAC...");
+        let comments = meta.vorbis_comments();
+        let tags = comments.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+        let seekable = sink.is_seekable();
+        let flac_encoder = FlacEncoder::new(
+            tags,
+            config.sample_rate.0,
+            config.channels,
+            self.opts.flac_bits,
+            self.opts.flac_compression_level,
+            CountingWriter::new(BufWriter::new(sink)))?;
+        self.current = Some(if seekable { flac_encoder } else { flac_encoder.without_seeking() });
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk: &[f32]) -> Result<(), Box<dyn Error>> {
+        self.current.as_mut().ok_or("No FLAC segment open")?.encode_interleaved(chunk)
+    }
 
-    let time_at_start = Instant::now();
-    while let Some(chunk) = mic_input_stream.next().await {
-        for sample in chunk.as_slice() {
-            wav_writer.write_sample(*sample)?;
+    fn finalize(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(flac_encoder) = self.current.take() {
+            flac_encoder.finish()?.into_inner().into_inner().map_err(io::IntoInnerError::into_error)?.finish()?.sync_all()?;
         }
-        if time_at_start.elapsed() >= *segment_dur  {
-            break;
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.current.as_ref().map(|e| e.sink().bytes_written()).unwrap_or(0)
+    }
+
+    fn settings(&self) -> serde_json::Value {
+        json!({
+            "compression_level": self.opts.flac_compression_level,
+            "bits": self.opts.flac_bits,
+        })
+    }
+}
+
+pub struct WavSegmentWriter {
+    opts: WavOpts,
+    segment_dur: Duration,
+    dither: Option<TpdfDither>,
+    current: Option<WavWriter<CountingWriter<BufWriter<SegmentSink>>>>,
+}
+
+impl WavSegmentWriter {
+    pub fn new(opts: WavOpts, segment_dur: Duration) -> Self {
+        let dither = match opts.wav_dither {
+            WavDither::Tpdf => Some(TpdfDither::new()),
+            WavDither::None => None,
+        };
+        Self { opts, segment_dur, dither, current: None }
+    }
+}
+
+impl SegmentWriter for WavSegmentWriter {
+    fn extension(&self) -> &'static str {
+        "wav"
+    }
+
+    fn open(&mut self, sink: SegmentSink, config: &cpal::StreamConfig, meta: &SegmentMeta) -> Result<(), Box<dyn Error>> {
+        self.finalize()?;
+        let comments = meta.vorbis_comments();
+        let (bits_per_sample, sample_format) = match self.opts.wav_bits {
+            WavBits::Int16 => (16, SampleFormat::Int),
+            WavBits::Int24 => (24, SampleFormat::Int),
+            WavBits::Float32 => (32, SampleFormat::Float),
+        };
+        let spec = WavSpec {
+            channels: config.channels,
+            sample_rate: config.sample_rate.0, // Dynamically grab
+            bits_per_sample,
+            sample_format
+        };
+        // Go straight to RF64 if we already know the segment won't fit in a plain WAV
+        let expected_size = spec.bytes_per_frame() as f64 * spec.sample_rate as f64 * self.segment_dur.as_secs_f64();
+        let container = match self.opts.wav_container {
+            WavContainer::Auto if expected_size >= u32::MAX as f64 => WavContainer::Rf64,
+            container => container,
+        };
+        self.current = Some(if sink.is_seekable() {
+            WavWriter::new(CountingWriter::new(BufWriter::new(sink)), spec, container, &comments)?
+        } else {
+            WavWriter::new_streaming(CountingWriter::new(BufWriter::new(sink)), spec, &comments)?
+        });
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, chunk: &[f32]) -> Result<(), Box<dyn Error>> {
+        let wav_writer = self.current.as_mut().ok_or("No WAV segment open")?;
+        let spec = *wav_writer.spec();
+        for sample in chunk {
+            if spec.sample_format == SampleFormat::Float {
+                wav_writer.write_sample_f32(*sample)?;
+            } else {
+                let bits = spec.bits_per_sample as u8;
+                let sample = match self.dither.as_mut() {
+                    Some(dither) => dither.quantize(*sample, bits),
+                    None => quantize(*sample, bits),
+                };
+                wav_writer.write_sample_int(sample)?;
+            }
         }
         wav_writer.flush()?; // Flush after each chunk, so we don't lose a single chunk
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(wav_writer) = self.current.take() {
+            wav_writer.finalize()?.into_inner().into_inner().map_err(io::IntoInnerError::into_error)?.finish()?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn bytes_written(&self) -> u64 {
+        self.current.as_ref().map(|w| w.sink().bytes_written()).unwrap_or(0)
+    }
+    fn settings(&self) -> serde_json::Value {
+        json!({
+            "bits": value_name(&self.opts.wav_bits),
+            "dither": value_name(&self.opts.wav_dither),
+            "container": match self.current.as_ref().map(|w| w.is_rf64()) {
+                Some(true) => Some("rf64"),
+                Some(false) => Some("riff"),
+                None => None,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+    use std::fs::File;
+    use std::path::{Path, PathBuf};
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::audio_info::{self, AudioInfo};
+    use crate::decode;
+    use crate::encrypt::Identities;
+    use crate::ogg_demux::OggPacketReader;
+    use crate::test_dir::TestDir;
+    use crate::test_opts::rec_opts;
+    use crate::wav::WavReader;
+
+    const FREQ: f32 = 440.;
+
+    /// `frames` of a 440 Hz sine at half scale, the same on every channel.
+    fn sine(frames: usize, channels: usize, sample_rate: u32) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| std::iter::repeat_n(0.5 * (TAU * FREQ * i as f32 / sample_rate as f32).sin(), channels))
+            .collect()
+    }
+
+    fn meta(channels: u16, sample_rate: u32) -> SegmentMeta {
+        SegmentMeta {
+            start: Utc::now(),
+            device: "test".to_owned(),
+            hostname: "host".to_owned(),
+            session_id: "session".to_owned(),
+            index: 1,
+            sample_rate,
+            channels,
+            user_tags: Vec::new(),
+        }
+    }
+
+    /// Writes `frames` of sine through `writer` the way the recorder would, and returns the finished file.
+    fn write_segment(
+        writer: &mut dyn SegmentWriter,
+        dir: &TestDir,
+        channels: u16,
+        sample_rate: u32,
+        frames: usize,
+    ) -> PathBuf {
+        let path = dir.join(format!("segment.{}", writer.extension()));
+        let config = cpal::StreamConfig {
+            channels,
+            sample_rate: cpal::SampleRate(sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        writer
+            .open(SegmentSink::create(&path, &[]).unwrap(), &config, &meta(channels, sample_rate))
+            .unwrap();
+        // Chunks that don't line up with any codec's frames
+        for chunk in sine(frames, channels as usize, sample_rate).chunks(1000 * channels as usize) {
+            writer.write_chunk(chunk).unwrap();
+        }
+        assert!(writer.bytes_written() > 0);
+        writer.finalize().unwrap();
+        path
+    }
+
+    fn decode_all(path: &Path) -> Vec<f32> {
+        let mut reader = decode::open(path, &Identities::default()).unwrap();
+        let mut samples = Vec::new();
+        while let Some(chunk) = reader.read_chunk().unwrap() {
+            samples.extend(chunk);
+        }
+        samples
+    }
+
+    fn info(path: &Path) -> AudioInfo {
+        audio_info::read(path, &Identities::default()).unwrap()
     }
-    wav_writer.finalize()?;
-    //Ok(mic_input_stream)
-    Ok(())
-}
\ No newline at end of file
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    fn assert_close(decoded: &[f32], expected: &[f32], tolerance: f32) {
+        assert_eq!(decoded.len(), expected.len());
+        for (i, (decoded, expected)) in decoded.iter().zip(expected).enumerate() {
+            assert!((decoded - expected).abs() <= tolerance, "sample {}: {} != {}", i, decoded, expected);
+        }
+    }
+
+    #[test]
+    fn wav_keeps_every_sample() {
+        let dir = TestDir::new("wav");
+        let rec = rec_opts(&["--wav-bits", "16", "--wav-dither", "none"]);
+        let mut writer = WavSegmentWriter::new(rec.wav, Duration::from_secs(60));
+        let path = write_segment(&mut writer, &dir, 2, 44_100, 66_150);
+
+        let spec = *WavReader::new(File::open(&path).unwrap()).unwrap().spec();
+        assert_eq!(spec.bits_per_sample, 16);
+        assert_eq!(spec.sample_format, SampleFormat::Int);
+        assert_eq!(info(&path), AudioInfo { sample_rate: 44_100, channels: 2, frames: 66_150 });
+        assert_close(&decode_all(&path), &sine(66_150, 2, 44_100), 1. / 16_384.);
+        assert_eq!(writer.settings()["container"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn dithered_24_bit_wav_is_within_a_step_or_two() {
+        let dir = TestDir::new("wav-24");
+        let rec = rec_opts(&["--wav-bits", "24"]);
+        let mut writer = WavSegmentWriter::new(rec.wav, Duration::from_secs(60));
+        let path = write_segment(&mut writer, &dir, 2, 48_000, 10_000);
+
+        let spec = *WavReader::new(File::open(&path).unwrap()).unwrap().spec();
+        assert_eq!((spec.bits_per_sample, spec.sample_format), (24, SampleFormat::Int));
+        assert_close(&decode_all(&path), &sine(10_000, 2, 48_000), 2.5 / 8_388_608.);
+        assert_eq!(writer.settings()["dither"], "tpdf");
+    }
+
+    #[test]
+    fn wav_float() {
+        let dir = TestDir::new("wav-float");
+        let mut writer = WavSegmentWriter::new(rec_opts(&[]).wav, Duration::from_secs(60));
+        let path = write_segment(&mut writer, &dir, 1, 48_000, 12_345);
+
+        let spec = *WavReader::new(File::open(&path).unwrap()).unwrap().spec();
+        assert_eq!(spec.sample_format, SampleFormat::Float);
+        assert_close(&decode_all(&path), &sine(12_345, 1, 48_000), 0.);
+    }
+
+    #[test]
+    fn flac_is_lossless() {
+        let dir = TestDir::new("flac");
+        let mut writer = FlacSegmentWriter::new(rec_opts(&[]).flac);
+        let path = write_segment(&mut writer, &dir, 2, 44_100, 50_000);
+
+        assert_eq!(&std::fs::read(&path).unwrap()[..4], b"fLaC");
+        assert_eq!(info(&path), AudioInfo { sample_rate: 44_100, channels: 2, frames: 50_000 });
+        assert_close(&decode_all(&path), &sine(50_000, 2, 44_100), 1. / 4_000_000.);
+    }
+
+    #[test]
+    fn ogg_vorbis_length_and_level() {
+        let dir = TestDir::new("ogg");
+        let mut writer = OggSegmentWriter::new(rec_opts(&[]).ogg);
+        let path = write_segment(&mut writer, &dir, 2, 44_100, 44_100);
+
+        assert_eq!(info(&path), AudioInfo { sample_rate: 44_100, channels: 2, frames: 44_100 });
+        let decoded = decode_all(&path);
+        assert_eq!(decoded.len(), 44_100 * 2);
+        // Half-scale sine: RMS of 0.354
+        assert!((rms(&decoded) - 0.354).abs() < 0.02, "RMS {}", rms(&decoded));
+    }
+
+    /// Fields of the comment header, the second packet of a Vorbis or Opus stream.
+    fn ogg_comments(path: &Path, magic: &[u8]) -> Vec<String> {
+        let mut packets = OggPacketReader::new(File::open(path).unwrap());
+        packets.next_packet().unwrap().unwrap();
+        let packet = packets.next_packet().unwrap().unwrap().data;
+        let mut body = packet.strip_prefix(magic).unwrap();
+        fn read<'a>(body: &mut &'a [u8]) -> &'a [u8] {
+            let len = u32::from_le_bytes(body[..4].try_into().unwrap()) as usize;
+            let (field, rest) = body[4..].split_at(len);
+            *body = rest;
+            field
+        }
+        let _vendor = read(&mut body);
+        let count = u32::from_le_bytes(body[..4].try_into().unwrap());
+        body = &body[4..];
+        (0..count).map(|_| String::from_utf8(read(&mut body).to_vec()).unwrap()).collect()
+    }
+
+    #[test]
+    fn ogg_segments_are_tagged() {
+        let dir = TestDir::new("ogg-tags");
+        let mut writer = OggSegmentWriter::new(rec_opts(&[]).ogg);
+        let vorbis = write_segment(&mut writer, &dir, 1, 44_100, 1_000);
+        let rec = rec_opts(&[]);
+        let mut writer = OpusSegmentWriter::new(rec.ogg, rec.opus);
+        let opus = write_segment(&mut writer, &dir, 1, 48_000, 1_000);
+
+        for comments in [ogg_comments(&vorbis, b"\x03vorbis"), ogg_comments(&opus, b"OpusTags")] {
+            for field in ["AKASHA_DEVICE=test", "AKASHA_HOSTNAME=host", "AKASHA_SESSION=session", "AKASHA_SEGMENT_INDEX=1"] {
+                assert!(comments.iter().any(|comment| comment == field), "no {} in {:?}", field, comments);
+            }
+            assert!(comments.iter().any(|comment| comment.starts_with("DATE=")));
+        }
+    }
+
+    #[test]
+    fn opus_length_and_level() {
+        let dir = TestDir::new("opus");
+        let rec = rec_opts(&["--opus-bitrate", "128", "--opus-application", "audio"]);
+        let mut writer = OpusSegmentWriter::new(rec.ogg, rec.opus);
+        let path = write_segment(&mut writer, &dir, 2, 48_000, 48_000);
+
+        assert_eq!(info(&path), AudioInfo { sample_rate: 48_000, channels: 2, frames: 48_000 });
+        let decoded = decode_all(&path);
+        assert_eq!(decoded.len(), 48_000 * 2);
+        assert!((rms(&decoded) - 0.354).abs() < 0.02, "RMS {}", rms(&decoded));
+    }
+
+    #[test]
+    fn resampled_opus_is_not_delayed_or_cut_short() {
+        let dir = TestDir::new("opus-resampled");
+        let rec = rec_opts(&["--opus-bitrate", "128", "--opus-application", "audio"]);
+        let mut writer = OpusSegmentWriter::new(rec.ogg, rec.opus);
+        let path = write_segment(&mut writer, &dir, 1, 44_100, 44_100);
+
+        // Opus runs at 48 kHz, so a second of 44.1 kHz input comes back as 48000 frames
+        assert_eq!(info(&path), AudioInfo { sample_rate: 48_000, channels: 1, frames: 48_000 });
+        let decoded = decode_all(&path);
+        assert_eq!(decoded.len(), 48_000);
+        // The sine reaches a quarter of full scale 1/12 of a cycle in, ~9 samples at 48 kHz,
+        // unless the resampler's delay was left in
+        let onset = decoded.iter().position(|s| s.abs() > 0.25).unwrap();
+        assert!((5..=15).contains(&onset), "sound starts at sample {}", onset);
+        // ...and the end wasn't trimmed off
+        assert!(rms(&decoded[47_000..]) > 0.3, "tail RMS {}", rms(&decoded[47_000..]));
+    }
+
+    #[test]
+    fn one_writer_per_format() {
+        let rec = rec_opts(&["-f", "wav,flac:archive,ogg,opus:listen"]);
+        let extensions: Vec<_> = writers_for(&rec).iter().map(|(_, writer)| writer.extension()).collect();
+        assert_eq!(extensions, ["wav", "flac", "ogg", "opus"]);
+        let dirs: Vec<_> = writers_for(&rec).iter().map(|(output, _)| output.dir_for(Path::new("rec"))).collect();
+        assert_eq!(dirs, [Path::new("rec"), Path::new("rec/archive"), Path::new("rec"), Path::new("rec/listen")]);
+    }
+
+    #[test]
+    fn counting_writer_tracks_seeks() {
+        let mut writer = CountingWriter::new(io::Cursor::new(Vec::new()));
+        writer.write_all(&[0; 100]).unwrap();
+        writer.seek(SeekFrom::Start(10)).unwrap();
+        writer.write_all(&[1; 20]).unwrap();
+        assert_eq!(writer.bytes_written(), 100);
+        writer.seek(SeekFrom::End(0)).unwrap();
+        writer.write_all(&[2; 5]).unwrap();
+        assert_eq!(writer.bytes_written(), 105);
+    }
+
+    #[test]
+    fn un_interleave_splits_channels() {
+        assert_eq!(un_interleave(&[1., 2., 3., 4., 5., 6.], 2), vec![vec![1., 3., 5.], vec![2., 4., 6.]]);
+    }
+}